@@ -0,0 +1,226 @@
+//! Discovery of the ACPI system description tables.
+//!
+//! The tables are located through the root system description pointer (RSDP) that
+//! the BIOS places in low memory. All tables lie in RAM and are read through the
+//! physical memory mapping. Tables with an invalid checksum are ignored.
+//!
+//! `init` parses the tables other subsystems need into `ACPI_TABLE`.
+
+use crate::memory::phys_to_virt;
+use core::{mem, ptr, slice};
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+
+use fadt::Fadt;
+use hpet::Hpet;
+use madt::Madt;
+
+/// The parsed tables, filled in by `init`. Tables the firmware doesn't provide are
+/// `None`.
+pub static ACPI_TABLE: Mutex<AcpiTables> = Mutex::new(AcpiTables {
+    fadt: None,
+    madt: None,
+    hpet: None,
+});
+
+#[derive(Debug)]
+pub struct AcpiTables {
+    pub fadt: Option<Fadt>,
+    pub madt: Option<Madt>,
+    pub hpet: Option<Hpet>,
+}
+
+/// Locates and parses the ACPI tables. Returns whether an RSDP was found.
+pub fn init() -> bool {
+    if find_rsdp().is_none() {
+        return false;
+    }
+    let tables = AcpiTables {
+        fadt: Fadt::get(),
+        madt: Madt::get(),
+        hpet: Hpet::get(),
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| *ACPI_TABLE.lock() = tables);
+    true
+}
+
+/// `GenericAddress::address_space` of a register in memory.
+pub const SYSTEM_MEMORY: u8 = 0;
+/// `GenericAddress::address_space` of a register in I/O space.
+pub const SYSTEM_IO: u8 = 1;
+
+/// The location of a register in memory, I/O or configuration space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Reads the 12 byte structure from `data` at `offset`.
+    fn read(data: &[u8], offset: usize) -> Option<Self> {
+        Some(GenericAddress {
+            address_space: read(data, offset)?,
+            bit_width: read(data, offset + 1)?,
+            bit_offset: read(data, offset + 2)?,
+            access_size: read(data, offset + 3)?,
+            address: read(data, offset + 4)?,
+        })
+    }
+}
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// Root system description pointer, as defined by ACPI 2.0.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the fields below only exist from revision 2 on
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Size of the RSDP of ACPI 1.0, which ends after `rsdt_address`.
+const RSDP_V1_SIZE: usize = 20;
+
+/// The header shared by all system description tables.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// A system description table with a valid checksum.
+#[derive(Debug, Clone, Copy)]
+pub struct Sdt {
+    addr: PhysAddr,
+    header: SdtHeader,
+}
+
+impl Sdt {
+    /// Reads the table at `addr` and validates its checksum.
+    ///
+    /// This function is unsafe because `addr` must point to a system description table.
+    pub unsafe fn new(addr: PhysAddr) -> Option<Self> {
+        let header: SdtHeader = ptr::read_unaligned(phys_to_virt(addr).as_ptr());
+        let length = header.length as usize;
+        if length < mem::size_of::<SdtHeader>() {
+            return None;
+        }
+        let bytes = slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), length);
+        if checksum(bytes) != 0 {
+            return None;
+        }
+        Some(Sdt { addr, header })
+    }
+
+    pub fn header(&self) -> SdtHeader {
+        self.header
+    }
+
+    pub fn signature(&self) -> [u8; 4] {
+        self.header.signature
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.addr
+    }
+
+    /// Returns the table contents following the header.
+    pub fn data(&self) -> &'static [u8] {
+        let header_size = mem::size_of::<SdtHeader>();
+        let start = phys_to_virt(self.addr + header_size).as_ptr::<u8>();
+        unsafe { slice::from_raw_parts(start, self.header.length as usize - header_size) }
+    }
+}
+
+/// Returns the table with the given signature, e.g. `b"APIC"` for the MADT.
+pub fn find_table(signature: &[u8; 4]) -> Option<Sdt> {
+    tables().find(|table| &table.signature() == signature)
+}
+
+/// Returns all valid tables referenced by the RSDT or XSDT.
+pub fn tables() -> impl Iterator<Item = Sdt> {
+    let (root, entry_size) = match find_rsdp() {
+        Some(rsdp) if rsdp.revision >= 2 && rsdp.xsdt_address != 0 => {
+            (unsafe { Sdt::new(PhysAddr::new(rsdp.xsdt_address)) }, 8)
+        }
+        Some(rsdp) => (
+            unsafe { Sdt::new(PhysAddr::new(u64::from(rsdp.rsdt_address))) },
+            4,
+        ),
+        None => (None, 4),
+    };
+    let entries = root.map_or(&[][..], |root| root.data());
+    entries.chunks_exact(entry_size).filter_map(move |entry| {
+        let mut addr = [0; 8];
+        addr[..entry_size].copy_from_slice(entry);
+        unsafe { Sdt::new(PhysAddr::new(u64::from_le_bytes(addr))) }
+    })
+}
+
+/// Searches the first KiB of the extended BIOS data area and the BIOS ROM for the RSDP.
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda_segment: u16 = unsafe { ptr::read(phys_to_virt(PhysAddr::new(0x40e)).as_ptr()) };
+    let ebda = u64::from(ebda_segment) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+
+    for &(start, end) in areas.iter().filter(|&&(start, _)| start != 0) {
+        for addr in (start..end).step_by(16) {
+            let virt = phys_to_virt(PhysAddr::new(addr));
+            let signature: [u8; 8] = unsafe { ptr::read(virt.as_ptr()) };
+            if &signature != RSDP_SIGNATURE {
+                continue;
+            }
+            let rsdp: Rsdp = unsafe { ptr::read_unaligned(virt.as_ptr()) };
+            let v1 = unsafe { slice::from_raw_parts(virt.as_ptr::<u8>(), RSDP_V1_SIZE) };
+            if checksum(v1) != 0 {
+                continue;
+            }
+            if rsdp.revision >= 2 {
+                let length = rsdp.length as usize;
+                let v2 = unsafe { slice::from_raw_parts(virt.as_ptr::<u8>(), length) };
+                if length < mem::size_of::<Rsdp>() || checksum(v2) != 0 {
+                    continue;
+                }
+            }
+            return Some(rsdp);
+        }
+    }
+    None
+}
+
+/// Sums up `bytes`, which gives zero for a valid ACPI structure.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Reads a `T` from `data` at `offset`, which does not need to be aligned.
+fn read<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    if offset + mem::size_of::<T>() > data.len() {
+        return None;
+    }
+    Some(unsafe { ptr::read_unaligned(data[offset..].as_ptr() as *const T) })
+}
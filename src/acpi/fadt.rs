@@ -0,0 +1,93 @@
+//! The fixed ACPI description table (FADT), which describes the power management
+//! hardware and points to the DSDT.
+
+use super::{find_table, read, GenericAddress};
+use x86_64::PhysAddr;
+
+/// Set in `Fadt::flags` if the reset register is supported.
+pub const RESET_REG_SUP: u32 = 1 << 10;
+
+// offsets into the table data, i.e. from the end of the header
+const DSDT: usize = 4;
+const SCI_INTERRUPT: usize = 10;
+const SMI_COMMAND: usize = 12;
+const ACPI_ENABLE: usize = 16;
+const ACPI_DISABLE: usize = 17;
+const PM1A_EVENT_BLOCK: usize = 20;
+const PM1B_EVENT_BLOCK: usize = 24;
+const PM1A_CONTROL_BLOCK: usize = 28;
+const PM1B_CONTROL_BLOCK: usize = 32;
+const PM_TIMER_BLOCK: usize = 40;
+const PM_TIMER_LENGTH: usize = 55;
+const CENTURY: usize = 72;
+const IAPC_BOOT_ARCH: usize = 73;
+const FLAGS: usize = 76;
+const RESET_REGISTER: usize = 80;
+const RESET_VALUE: usize = 92;
+const X_DSDT: usize = 104;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// The legacy IRQ of the system control interrupt.
+    pub sci_interrupt: u16,
+    /// I/O port to write `acpi_enable` or `acpi_disable` to, zero if ACPI mode is fixed.
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    /// I/O port of the PM1a event block.
+    pub pm1a_event_block: u32,
+    /// I/O port of the PM1b event block, zero if there is none.
+    pub pm1b_event_block: u32,
+    /// I/O port of the PM1a control block.
+    pub pm1a_control_block: u32,
+    /// I/O port of the PM1b control block, zero if there is none.
+    pub pm1b_control_block: u32,
+    /// I/O port of the power management timer, zero if there is none.
+    pub pm_timer_block: u32,
+    pub pm_timer_length: u8,
+    /// Index of the RTC CMOS register holding the century, zero if there is none.
+    pub century: u8,
+    pub iapc_boot_arch: u16,
+    pub flags: u32,
+    /// Register to write `reset_value` to for a reset, if supported (ACPI 2.0).
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+    /// Physical address of the differentiated system description table.
+    pub dsdt: PhysAddr,
+}
+
+impl Fadt {
+    /// Finds and parses the FADT.
+    pub fn get() -> Option<Self> {
+        let data = find_table(b"FACP")?.data();
+        let flags = read(data, FLAGS).unwrap_or(0);
+        let reset_register = if flags & RESET_REG_SUP != 0 {
+            GenericAddress::read(data, RESET_REGISTER)
+        } else {
+            None
+        };
+        let dsdt = match read::<u64>(data, X_DSDT) {
+            Some(x_dsdt) if x_dsdt != 0 => x_dsdt,
+            _ => u64::from(read::<u32>(data, DSDT)?),
+        };
+
+        Some(Fadt {
+            sci_interrupt: read(data, SCI_INTERRUPT)?,
+            smi_command: read(data, SMI_COMMAND)?,
+            acpi_enable: read(data, ACPI_ENABLE)?,
+            acpi_disable: read(data, ACPI_DISABLE)?,
+            pm1a_event_block: read(data, PM1A_EVENT_BLOCK)?,
+            pm1b_event_block: read(data, PM1B_EVENT_BLOCK)?,
+            pm1a_control_block: read(data, PM1A_CONTROL_BLOCK)?,
+            pm1b_control_block: read(data, PM1B_CONTROL_BLOCK)?,
+            pm_timer_block: read(data, PM_TIMER_BLOCK)?,
+            pm_timer_length: read(data, PM_TIMER_LENGTH)?,
+            century: read(data, CENTURY)?,
+            iapc_boot_arch: read(data, IAPC_BOOT_ARCH).unwrap_or(0),
+            flags,
+            reset_register,
+            reset_value: read(data, RESET_VALUE).unwrap_or(0),
+            dsdt: PhysAddr::new(dsdt),
+        })
+    }
+}
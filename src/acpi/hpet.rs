@@ -0,0 +1,42 @@
+//! The HPET description table, which locates the high precision event timer.
+
+use super::{find_table, read, GenericAddress};
+
+const EVENT_TIMER_BLOCK_ID: usize = 0;
+const BASE_ADDRESS: usize = 4;
+const NUMBER: usize = 16;
+const MINIMUM_TICK: usize = 17;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub hardware_rev_id: u8,
+    /// Number of comparators, i.e. the index of the last one plus one.
+    pub comparator_count: u8,
+    pub counter_64bit: bool,
+    /// Whether the HPET can replace the PIT and RTC interrupts.
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    /// The memory mapped registers.
+    pub base_address: GenericAddress,
+    pub number: u8,
+    /// Minimum number of counter ticks for a periodic timer without lost interrupts.
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    /// Finds and parses the HPET table.
+    pub fn get() -> Option<Self> {
+        let data = find_table(b"HPET")?.data();
+        let id: u32 = read(data, EVENT_TIMER_BLOCK_ID)?;
+        Some(Hpet {
+            hardware_rev_id: id as u8,
+            comparator_count: ((id >> 8) & 0x1f) as u8 + 1,
+            counter_64bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            pci_vendor_id: (id >> 16) as u16,
+            base_address: GenericAddress::read(data, BASE_ADDRESS)?,
+            number: read(data, NUMBER)?,
+            minimum_tick: read(data, MINIMUM_TICK)?,
+        })
+    }
+}
@@ -0,0 +1,162 @@
+//! The multiple APIC description table (MADT), which lists the processors and
+//! interrupt controllers of the system.
+
+use super::{find_table, read};
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+const PROCESSOR_LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const LOCAL_APIC_NMI: u8 = 4;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// Set in the MADT flags if the system also has 8259 PICs.
+const PCAT_COMPAT: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Whether the processor can be started. Disabled entries describe hot-pluggable
+    /// processors that are not present.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first global system interrupt (GSI) handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// Connects a legacy ISA IRQ to a GSI other than its own number or with other than
+/// the ISA default active high, edge triggered signaling.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+/// A local APIC interrupt pin connected to the NMI line.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// The ACPI processor id, or 0xff for all processors.
+    pub processor_id: u8,
+    /// The LINT pin, 0 or 1.
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Whether the system also has 8259 PICs, which must be masked when using the APIC.
+    pub pcat_compat: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    /// Finds and parses the MADT.
+    pub fn get() -> Option<Self> {
+        let data = find_table(b"APIC")?.data();
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(u64::from(read::<u32>(data, 0)?)),
+            pcat_compat: read::<u32>(data, 4)? & PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            local_apic_nmis: Vec::new(),
+        };
+
+        let mut offset = 8;
+        while let (Some(kind), Some(length)) =
+            (read::<u8>(data, offset), read::<u8>(data, offset + 1))
+        {
+            let length = usize::from(length);
+            if length < 2 || offset + length > data.len() {
+                break;
+            }
+            let entry = &data[offset..offset + length];
+            match kind {
+                PROCESSOR_LOCAL_APIC => madt.processors.push(Processor {
+                    processor_id: read(entry, 2)?,
+                    apic_id: read(entry, 3)?,
+                    enabled: read::<u32>(entry, 4)? & 1 != 0,
+                }),
+                IO_APIC => madt.io_apics.push(IoApic {
+                    id: read(entry, 2)?,
+                    address: PhysAddr::new(u64::from(read::<u32>(entry, 4)?)),
+                    gsi_base: read(entry, 8)?,
+                }),
+                INTERRUPT_SOURCE_OVERRIDE => {
+                    let flags: u16 = read(entry, 8)?;
+                    madt.overrides.push(InterruptOverride {
+                        irq: read(entry, 3)?,
+                        gsi: read(entry, 4)?,
+                        polarity: polarity(flags),
+                        trigger_mode: trigger_mode(flags),
+                    });
+                }
+                LOCAL_APIC_NMI => {
+                    let flags: u16 = read(entry, 3)?;
+                    madt.local_apic_nmis.push(LocalApicNmi {
+                        processor_id: read(entry, 2)?,
+                        lint: read(entry, 5)?,
+                        polarity: polarity(flags),
+                        trigger_mode: trigger_mode(flags),
+                    });
+                }
+                LOCAL_APIC_ADDRESS_OVERRIDE => {
+                    madt.local_apic_address = PhysAddr::new(read(entry, 4)?);
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        Some(madt)
+    }
+
+    /// Returns how the legacy ISA `irq` is connected to the I/O APICs.
+    pub fn isa_irq(&self, irq: u8) -> (u32, Polarity, TriggerMode) {
+        match self.overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => (o.gsi, o.polarity, o.trigger_mode),
+            None => (u32::from(irq), Polarity::ActiveHigh, TriggerMode::Edge),
+        }
+    }
+}
+
+/// Decodes the polarity bits of MPS INTI flags. Conforming means ISA signaling.
+fn polarity(flags: u16) -> Polarity {
+    match flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    }
+}
+
+/// Decodes the trigger mode bits of MPS INTI flags. Conforming means ISA signaling.
+fn trigger_mode(flags: u16) -> TriggerMode {
+    match (flags >> 2) & 0b11 {
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::Edge,
+    }
+}
@@ -1,14 +1,31 @@
+use crate::memory;
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+use core::cmp::{max, min};
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use fixed_size_block::SIZE_CLASSES;
+use linked_list_allocator::Heap;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB, UnusedPhysFrame,
     },
     VirtAddr,
 };
 
+pub mod fixed_size_block;
+pub mod tracking;
+
 pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// Size of the heap that is mapped by `init_heap`.
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Default upper bound for the heap size, see `set_max_size`.
+pub const DEFAULT_HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+/// The heap grows by at least this many bytes at a time.
+const HEAP_GROW_STEP: usize = 64 * 1024; // 64 KiB
+const PAGE_SIZE: usize = 4096;
+
+static HEAP_MAX_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_HEAP_MAX_SIZE);
 
 pub struct Dummy;
 
@@ -22,29 +39,162 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
 
-pub fn init_heap(
+/// A wrapper around spin::Mutex to permit trait implementations.
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+/// A bare `Heap` doesn't know whether it owns the memory above its top, so it never
+/// grows. The kernel heap is a `FixedSizeBlockAllocator`.
+unsafe impl GlobalAlloc for Locked<Heap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.lock().allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
+/// A snapshot of the kernel heap usage, see `stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes currently allocated, as requested by the callers.
+    pub bytes_allocated: usize,
+    /// The highest value `bytes_allocated` ever reached.
+    pub peak_bytes_allocated: usize,
+    /// Number of allocations ever made, per entry of `fixed_size_block::BLOCK_SIZES`.
+    /// The last entry counts allocations that were too large for any block size.
+    pub allocations: [u64; SIZE_CLASSES],
+    /// Number of live allocations, indexed like `allocations`.
+    pub live_allocations: [usize; SIZE_CLASSES],
+    /// Bytes currently mapped for the heap.
+    pub heap_size: usize,
+    /// Size of the largest allocation that can be served without growing the heap.
+    pub largest_free_block: usize,
+}
+
+/// Returns usage statistics of the kernel heap.
+pub fn stats() -> HeapStats {
+    x86_64::instructions::interrupts::without_interrupts(|| super::ALLOCATOR.lock().stats())
+}
+
+/// Sets the maximum size the kernel heap may grow to.
+///
+/// Memory that is already mapped is never returned, so lowering the limit below the
+/// current heap size only prevents further growth.
+pub fn set_max_size(size: usize) {
+    HEAP_MAX_SIZE.store(size, Ordering::SeqCst);
+}
+
+/// Returns the maximum size the kernel heap may grow to.
+pub fn max_size() -> usize {
+    HEAP_MAX_SIZE.load(Ordering::SeqCst)
+}
+
+pub fn init_heap() -> Result<(), MapToError> {
+    memory::with_mapper(|mapper, frame_allocator| {
+        map_heap_pages(mapper, frame_allocator, HEAP_START, HEAP_SIZE)
+    })?;
+
+    unsafe {
+        super::ALLOCATOR.lock().init_growable(HEAP_START, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
+/// Extends `heap` by mapping fresh pages directly above its top, so that an
+/// allocation of `layout` fits into it.
+///
+/// Returns `false` if `heap` reached its maximum size or if there are no physical
+/// frames left.
+///
+/// This function is unsafe because `heap` must own the virtual memory from its bottom
+/// up to `max_size`, and nothing may be mapped above its top.
+pub(crate) unsafe fn grow(heap: &mut Heap, layout: &Layout) -> bool {
+    // in the worst case the allocation needs `align` bytes of padding
+    let needed = layout.size() + layout.align();
+    let available = max_size().saturating_sub(heap.size());
+    let by = min(align_up(max(needed, HEAP_GROW_STEP), PAGE_SIZE), available);
+    if by < needed {
+        return false;
+    }
+
+    let top = heap.top();
+    let result = memory::with_mapper(|mapper, frame_allocator| {
+        map_heap_pages(mapper, frame_allocator, top, by)
+    });
+    if result.is_err() {
+        return false;
+    }
+
+    heap.extend(by);
+    true
+}
+
+/// Maps `size` bytes starting at `start` to fresh frames.
+///
+/// If mapping fails midway, the pages mapped so far are unmapped again, so that a
+/// later attempt can start from the same address.
+fn map_heap_pages<A>(
     mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError> {
+    frame_allocator: &mut A,
+    start: usize,
+    size: usize,
+) -> Result<(), MapToError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
+    let first_page = page_range.start;
 
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-    }
-
-    unsafe {
-        super::ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        let result = match frame_allocator.allocate_frame() {
+            Some(frame) => {
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                mapper.map_to(page, frame, flags, frame_allocator)
+            }
+            None => Err(MapToError::FrameAllocationFailed),
+        };
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                for mapped in Page::range(first_page, page) {
+                    if let Ok((frame, flush)) = mapper.unmap(mapped) {
+                        flush.flush();
+                        frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+                    }
+                }
+                return Err(err);
+            }
+        }
     }
 
     Ok(())
 }
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
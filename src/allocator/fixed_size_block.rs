@@ -0,0 +1,232 @@
+use super::{tracking, HeapStats, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{
+    mem,
+    ptr::{self, NonNull},
+};
+use linked_list_allocator::Heap;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// The block sizes to use.
+///
+/// The sizes must each be power of 2 because they are also used as
+/// the block alignment (alignments must be always powers of 2).
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Number of size classes: one per block size plus one for the fallback allocator.
+pub const SIZE_CLASSES: usize = BLOCK_SIZES.len() + 1;
+
+/// Size of the slab that is carved into blocks when a size class runs empty.
+const SLAB_SIZE: usize = 4096;
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// An allocator that serves small allocations from per-size-class free lists and
+/// falls back to a linked list heap for everything larger than the biggest block size.
+///
+/// Freed blocks are kept in their size class and are never returned to the fallback heap.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: Heap,
+    /// Whether the fallback heap owns the virtual memory above its top, see
+    /// `init_growable`.
+    growable: bool,
+    counters: Counters,
+}
+
+/// Bookkeeping for `allocator::stats`.
+struct Counters {
+    bytes_allocated: usize,
+    peak_bytes_allocated: usize,
+    allocations: [u64; SIZE_CLASSES],
+    live_allocations: [usize; SIZE_CLASSES],
+}
+
+impl FixedSizeBlockAllocator {
+    /// Creates an empty FixedSizeBlockAllocator.
+    pub const fn new() -> Self {
+        FixedSizeBlockAllocator {
+            list_heads: [None; BLOCK_SIZES.len()],
+            fallback_allocator: Heap::empty(),
+            growable: false,
+            counters: Counters {
+                bytes_allocated: 0,
+                peak_bytes_allocated: 0,
+                allocations: [0; SIZE_CLASSES],
+                live_allocations: [0; SIZE_CLASSES],
+            },
+        }
+    }
+
+    /// Returns a snapshot of the allocation counters.
+    ///
+    /// Finding the largest free block probes the fallback heap with test allocations,
+    /// so this takes time proportional to the number of holes in it.
+    pub fn stats(&mut self) -> HeapStats {
+        let largest_block = (0..BLOCK_SIZES.len())
+            .rev()
+            .find(|&index| self.list_heads[index].is_some())
+            .map_or(0, |index| BLOCK_SIZES[index]);
+
+        HeapStats {
+            bytes_allocated: self.counters.bytes_allocated,
+            peak_bytes_allocated: self.counters.peak_bytes_allocated,
+            allocations: self.counters.allocations,
+            live_allocations: self.counters.live_allocations,
+            heap_size: self.fallback_allocator.size(),
+            largest_free_block: largest_block.max(self.largest_fallback_hole()),
+        }
+    }
+
+    /// Binary searches for the largest allocation the fallback heap can serve without
+    /// growing. Every successful probe is freed again right away.
+    fn largest_fallback_hole(&mut self) -> usize {
+        let mut low = 0;
+        let mut high = self.fallback_allocator.size();
+        while low < high {
+            let mid = low + (high - low + 1) / 2;
+            let layout = Layout::from_size_align(mid, mem::align_of::<usize>()).unwrap();
+            match self.fallback_allocator.allocate_first_fit(layout) {
+                Ok(ptr) => {
+                    unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+                    low = mid;
+                }
+                Err(_) => high = mid - 1,
+            }
+        }
+        low
+    }
+
+    fn count_alloc(&mut self, class: usize, layout: &Layout) {
+        let counters = &mut self.counters;
+        counters.bytes_allocated += layout.size();
+        counters.peak_bytes_allocated = counters.peak_bytes_allocated.max(counters.bytes_allocated);
+        counters.allocations[class] += 1;
+        counters.live_allocations[class] += 1;
+    }
+
+    fn count_dealloc(&mut self, class: usize, layout: &Layout) {
+        self.counters.bytes_allocated -= layout.size();
+        self.counters.live_allocations[class] -= 1;
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// Like `init`, but the heap grows by mapping fresh pages above its top when it
+    /// runs out, up to `allocator::max_size`.
+    ///
+    /// This function is additionally unsafe because the virtual memory from
+    /// `heap_start` up to the maximum size must not be used for anything else.
+    pub unsafe fn init_growable(&mut self, heap_start: usize, heap_size: usize) {
+        self.init(heap_start, heap_size);
+        self.growable = true;
+    }
+
+    /// Allocates using the fallback allocator, growing the heap if it is growable.
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        loop {
+            match self.fallback_allocator.allocate_first_fit(layout) {
+                Ok(ptr) => return ptr.as_ptr(),
+                Err(_) => {
+                    let grown = self.growable
+                        && unsafe { super::grow(&mut self.fallback_allocator, &layout) };
+                    if !grown {
+                        return ptr::null_mut();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Carves a new slab from the fallback allocator into blocks of size class `index`.
+    ///
+    /// Returns `false` if the fallback allocator is out of memory.
+    fn refill(&mut self, index: usize) -> bool {
+        let block_size = BLOCK_SIZES[index];
+        let layout = Layout::from_size_align(SLAB_SIZE, block_size).unwrap();
+        let slab = self.fallback_alloc(layout);
+        if slab.is_null() {
+            return false;
+        }
+
+        for offset in (0..SLAB_SIZE).step_by(block_size) {
+            let node_ptr = unsafe { slab.add(offset) } as *mut ListNode;
+            unsafe { self.push(index, node_ptr) };
+        }
+        true
+    }
+
+    /// Adds the block at `node_ptr` to the free list of size class `index`.
+    ///
+    /// The block must be unused, at least as large and as aligned as the size class.
+    unsafe fn push(&mut self, index: usize, node_ptr: *mut ListNode) {
+        // verify that block has size and alignment required for storing node
+        assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+        let new_node = ListNode {
+            next: self.list_heads[index].take(),
+        };
+        node_ptr.write(new_node);
+        self.list_heads[index] = Some(&mut *node_ptr);
+    }
+}
+
+/// Choose an appropriate block size for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // the lock is never held with interrupts enabled, so a thread holding it
+        // can't be preempted and interrupt handlers can allocate
+        without_interrupts(|| {
+            let mut allocator = self.lock();
+            let class = list_index(&layout).unwrap_or(BLOCK_SIZES.len());
+            let ptr = match list_index(&layout) {
+                Some(index) => {
+                    if allocator.list_heads[index].is_none() && !allocator.refill(index) {
+                        return ptr::null_mut();
+                    }
+                    let node = allocator.list_heads[index].take().unwrap();
+                    allocator.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                None => allocator.fallback_alloc(layout),
+            };
+            if !ptr.is_null() {
+                allocator.count_alloc(class, &layout);
+                tracking::record_alloc(ptr, &layout);
+            }
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| {
+            let mut allocator = self.lock();
+            let class = list_index(&layout).unwrap_or(BLOCK_SIZES.len());
+            allocator.count_dealloc(class, &layout);
+            tracking::record_dealloc(ptr);
+            match list_index(&layout) {
+                Some(index) => allocator.push(index, ptr as *mut ListNode),
+                None => {
+                    let ptr = NonNull::new(ptr).unwrap();
+                    allocator.fallback_allocator.deallocate(ptr, layout);
+                }
+            }
+        })
+    }
+}
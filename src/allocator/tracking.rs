@@ -0,0 +1,182 @@
+//! Optional leak tracking for the kernel heap.
+//!
+//! While tracking is enabled, every allocation is recorded together with a short
+//! backtrace of its call site and removed again when it is freed. Tracking is off by
+//! default and costs a single atomic load per allocation in that case.
+//!
+//! Only the allocations of the thread that started tracking are recorded, so that
+//! threads running on other processors meanwhile don't show up as leaks. Frees are
+//! matched regardless of the thread, since an allocation may be handed to another
+//! thread that frees it. Allocations of interrupt handlers can't be told apart from
+//! the thread they interrupted and are recorded if they interrupt the tracking
+//! thread.
+//!
+//! The backtraces are taken by walking frame pointers, so they are only meaningful
+//! because the target specification keeps them.
+
+use crate::thread;
+use alloc::alloc::Layout;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Maximum number of live allocations that can be recorded at once.
+const MAX_TRACKED: usize = 256;
+/// Number of return addresses recorded per allocation.
+pub const BACKTRACE_DEPTH: usize = 8;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The id of the thread whose allocations are recorded.
+static THREAD: AtomicU64 = AtomicU64::new(0);
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker::new());
+
+/// A live allocation recorded while tracking was enabled.
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    pub ptr: usize,
+    pub size: usize,
+    /// Return addresses of the call site, innermost first. Unused entries are zero.
+    pub backtrace: [usize; BACKTRACE_DEPTH],
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes at {:#x}, allocated from", self.size, self.ptr)?;
+        for addr in self.backtrace.iter().take_while(|&&addr| addr != 0) {
+            write!(f, " {:#x}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+/// The allocations that were still live when tracking was stopped.
+#[derive(Debug)]
+pub struct LeakReport {
+    pub leaks: Vec<Allocation>,
+    /// Allocations that could not be recorded because the table was full.
+    pub dropped: usize,
+}
+
+impl LeakReport {
+    /// Panics with the recorded call sites if anything leaked.
+    pub fn assert_no_leaks(&self) {
+        if self.leaks.is_empty() && self.dropped == 0 {
+            return;
+        }
+        for leak in &self.leaks {
+            serial_println!("leaked {}", leak);
+        }
+        panic!(
+            "{} allocations leaked ({} not recorded)",
+            self.leaks.len() + self.dropped,
+            self.dropped
+        );
+    }
+}
+
+struct Tracker {
+    allocations: [Option<Allocation>; MAX_TRACKED],
+    dropped: usize,
+}
+
+impl Tracker {
+    const fn new() -> Self {
+        Tracker {
+            allocations: [None; MAX_TRACKED],
+            dropped: 0,
+        }
+    }
+}
+
+/// Starts recording the allocations of the running thread, discarding anything
+/// recorded before.
+pub fn start() {
+    let mut tracker = TRACKER.lock();
+    for slot in tracker.allocations.iter_mut() {
+        *slot = None;
+    }
+    tracker.dropped = 0;
+    THREAD.store(thread::current().as_u64(), Ordering::SeqCst);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stops recording and returns the allocations that are still live.
+pub fn stop() -> LeakReport {
+    ENABLED.store(false, Ordering::SeqCst);
+
+    // reserve the space up front, so that nothing is allocated with the lock held
+    let mut leaks = Vec::with_capacity(MAX_TRACKED);
+    let tracker = TRACKER.lock();
+    leaks.extend(tracker.allocations.iter().filter_map(|a| *a));
+    LeakReport {
+        leaks,
+        dropped: tracker.dropped,
+    }
+}
+
+/// Runs `f` with tracking enabled and reports what it allocated without freeing.
+///
+/// Allocations of other threads meanwhile are not reported.
+pub fn check_leaks<F: FnOnce()>(f: F) -> LeakReport {
+    start();
+    f();
+    stop()
+}
+
+/// Called by the global allocator for every successful allocation.
+pub(super) fn record_alloc(ptr: *mut u8, layout: &Layout) {
+    if !ENABLED.load(Ordering::Relaxed)
+        || thread::current().as_u64() != THREAD.load(Ordering::Relaxed)
+    {
+        return;
+    }
+    let allocation = Allocation {
+        ptr: ptr as usize,
+        size: layout.size(),
+        backtrace: backtrace(),
+    };
+    let mut tracker = TRACKER.lock();
+    match tracker.allocations.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(allocation),
+        None => tracker.dropped += 1,
+    }
+}
+
+/// Called by the global allocator for every deallocation.
+pub(super) fn record_dealloc(ptr: *mut u8) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut tracker = TRACKER.lock();
+    let slot = tracker
+        .allocations
+        .iter_mut()
+        .find(|slot| slot.map_or(false, |a| a.ptr == ptr as usize));
+    // allocations made before tracking started are not in the table
+    if let Some(slot) = slot {
+        *slot = None;
+    }
+}
+
+/// Collects return addresses by following the saved frame pointers.
+#[inline(always)]
+fn backtrace() -> [usize; BACKTRACE_DEPTH] {
+    let mut addresses = [0; BACKTRACE_DEPTH];
+    let mut rbp: usize;
+    unsafe { asm!("mov %rbp, $0" : "=r"(rbp) ::: "volatile") };
+
+    for address in addresses.iter_mut() {
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+        let (next, ret) = unsafe { (*(rbp as *const usize), *((rbp + 8) as *const usize)) };
+        *address = ret;
+        // the stack grows down, so callers always have higher frame pointers
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    addresses
+}
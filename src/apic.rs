@@ -0,0 +1,355 @@
+//! Local APIC and I/O APIC driver.
+//!
+//! When the MADT describes an APIC, the 8259 PICs are masked and the legacy IRQs are
+//! routed through the I/O APICs to the same vectors the PICs used, so handlers
+//! registered with `interrupts::register_irq` keep working. GSIs 16 to 23 are
+//! delivered as IRQ `gsi` and start masked, higher GSIs stay masked. Without an APIC
+//! the kernel keeps using the PICs.
+//!
+//! The local APIC also sends the interrupts processors raise on each other, including
+//! the INIT and startup IPIs that start the application processors.
+
+use crate::acpi::{
+    madt::{Madt, Polarity, TriggerMode},
+    ACPI_TABLE,
+};
+use crate::interrupts::irq;
+use crate::memory::mmio;
+use crate::pit;
+use crate::smp::percpu;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{spin_loop_hint, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+use x86_64::VirtAddr;
+
+/// The vector of spurious interrupts, which must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// local APIC registers
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xb0;
+const SPURIOUS: usize = 0xf0;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+const LVT_ERROR: usize = 0x370;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL: u32 = 1 << 15;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_LEVEL: u32 = 1 << 15;
+/// Divides the bus clock by 16 for the timer.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// I/O APIC registers
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+
+/// Virtual address of the local APIC registers, zero if the APIC is not used.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+/// Local APIC timer ticks per millisecond, measured by `init`.
+static TIMER_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+/// An I/O APIC, which routes the GSIs `gsi_base..gsi_base + entries` to local APICs.
+struct IoApic {
+    registers: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        let select = self.registers.as_mut_ptr::<u32>();
+        unsafe {
+            ptr::write_volatile(select, register);
+            ptr::read_volatile(select.add(4))
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        let select = self.registers.as_mut_ptr::<u32>();
+        unsafe {
+            ptr::write_volatile(select, register);
+            ptr::write_volatile(select.add(4), value);
+        }
+    }
+
+    fn redirection(&self, gsi: u32) -> u64 {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        u64::from(self.read(register)) | u64::from(self.read(register + 1)) << 32
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        // mask the entry while the halves don't match
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    fn contains(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.entries
+    }
+}
+
+/// Switches interrupt delivery from the PICs to the APICs if the MADT describes them.
+///
+/// Must be called after `acpi::init` and the PICs were initialized and before
+/// interrupts are enabled.
+/// Returns whether the APICs are used.
+pub fn init() -> bool {
+    let madt = ACPI_TABLE.lock().madt.clone();
+    let madt = match madt {
+        Some(madt) if has_apic() && !madt.io_apics.is_empty() => madt,
+        _ => return false,
+    };
+
+    if madt.pcat_compat {
+        unsafe {
+            Port::<u8>::new(0x21).write(0xff);
+            Port::<u8>::new(0xa1).write(0xff);
+        }
+    }
+
+    let local_apic =
+        unsafe { mmio::map(madt.local_apic_address, 4096) }.expect("failed to map local APIC");
+    unsafe {
+        let mut base = Msr::new(IA32_APIC_BASE);
+        let value = base.read();
+        base.write(value | APIC_BASE_ENABLE);
+    }
+    LOCAL_APIC.store(local_apic.as_u64(), Ordering::SeqCst);
+    init_local_apic(&madt);
+    percpu::set_bsp_apic_id(local_apic_id());
+
+    let mut io_apics = IO_APICS.lock();
+    for info in &madt.io_apics {
+        let registers = unsafe { mmio::map(info.address, 0x20) }.expect("failed to map I/O APIC");
+        let mut io_apic = IoApic {
+            registers,
+            gsi_base: info.gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+            let vector = gsi_vector(gsi);
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED | u64::from(vector));
+        }
+        io_apics.push(io_apic);
+    }
+
+    let bsp = u64::from(local_apic_id());
+    for isa_irq in 0..irq::LEGACY_IRQS {
+        // IRQ 2 is the cascade of the PICs and is never raised
+        if isa_irq == 2 {
+            continue;
+        }
+        let (gsi, polarity, trigger_mode) = madt.isa_irq(isa_irq);
+        let mut entry = bsp << 56 | u64::from(irq::vector(isa_irq));
+        if polarity == Polarity::ActiveLow {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if trigger_mode == TriggerMode::Level {
+            entry |= REDIRECTION_LEVEL;
+        }
+        if let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.contains(gsi)) {
+            io_apic.set_redirection(gsi, entry);
+        }
+    }
+    drop(io_apics);
+
+    calibrate_timer();
+    true
+}
+
+/// Enables the local APIC of an application processor and starts its timer at the
+/// default frequency of the timer IRQ, which only reaches the bootstrap processor.
+///
+/// Must be called by the application processor after `init` returned true.
+pub(crate) fn init_ap() {
+    let madt = ACPI_TABLE
+        .lock()
+        .madt
+        .clone()
+        .expect("APIC used without MADT");
+    unsafe {
+        let mut base = Msr::new(IA32_APIC_BASE);
+        let value = base.read();
+        base.write(value | APIC_BASE_ENABLE);
+    }
+    init_local_apic(&madt);
+    start_timer(u64::from(pit::DEFAULT_FREQUENCY));
+}
+
+/// Returns whether interrupts are delivered through the APICs.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Relaxed) != 0
+}
+
+/// Signals the end of the current interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+/// Returns the id of the local APIC of the executing processor.
+pub fn local_apic_id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+/// Sends the interrupt `vector` to the processor with the local APIC `apic_id`.
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send_command(apic_id, u32::from(vector));
+}
+
+/// Sends an INIT IPI, which resets the processor into a state waiting for a startup
+/// IPI.
+pub fn send_init(apic_id: u8) {
+    send_command(apic_id, ICR_INIT | ICR_LEVEL | ICR_ASSERT);
+    send_command(apic_id, ICR_INIT | ICR_LEVEL);
+}
+
+/// Sends a startup IPI, which starts the processor in real mode at `page * 4096`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_command(apic_id, ICR_STARTUP | u32::from(page));
+}
+
+/// Writes the interrupt command register and waits until the IPI was sent.
+fn send_command(apic_id: u8, command: u32) {
+    assert!(is_enabled(), "the local APIC is not used");
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write(ICR_HIGH, u32::from(apic_id) << 24);
+        write(ICR_LOW, command);
+        while read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            spin_loop_hint();
+        }
+    });
+}
+
+/// Masks or unmasks `gsi` at its I/O APIC. Returns false if no I/O APIC handles it.
+pub fn set_gsi_masked(gsi: u32, masked: bool) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        match io_apics.iter().find(|io_apic| io_apic.contains(gsi)) {
+            Some(io_apic) => {
+                let entry = io_apic.redirection(gsi);
+                if masked {
+                    io_apic.set_redirection(gsi, entry | REDIRECTION_MASKED);
+                } else {
+                    io_apic.set_redirection(gsi, entry & !REDIRECTION_MASKED);
+                }
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Starts the local APIC timer, raising `irq::LAPIC_TIMER` `hz` times per second.
+pub fn start_timer(hz: u64) {
+    assert!(is_enabled(), "the local APIC is not used");
+    let count = (TIMER_TICKS_PER_MS.load(Ordering::SeqCst) * 1000 / hz).max(1);
+    write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(
+        LVT_TIMER,
+        LVT_TIMER_PERIODIC | u32::from(irq::vector(irq::LAPIC_TIMER)),
+    );
+    write(
+        TIMER_INITIAL_COUNT,
+        count.min(u64::from(u32::max_value())) as u32,
+    );
+}
+
+/// Stops the local APIC timer.
+pub fn stop_timer() {
+    write(TIMER_INITIAL_COUNT, 0);
+    write(LVT_TIMER, LVT_MASKED);
+}
+
+/// Returns the vector GSIs without a legacy IRQ are delivered on. The IRQs from
+/// `irq::LAPIC_TIMER` on are raised by local APICs.
+fn gsi_vector(gsi: u32) -> u8 {
+    if gsi < u32::from(irq::LAPIC_TIMER) {
+        irq::vector(gsi as u8)
+    } else {
+        SPURIOUS_VECTOR
+    }
+}
+
+fn init_local_apic(madt: &Madt) {
+    write(TASK_PRIORITY, 0);
+    write(SPURIOUS, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+    write(LVT_TIMER, LVT_MASKED);
+    write(LVT_ERROR, LVT_MASKED);
+    // external interrupts arrive through the I/O APIC instead of LINT0
+    write(LVT_LINT0, LVT_MASKED);
+    write(LVT_LINT1, LVT_MASKED);
+
+    for nmi in &madt.local_apic_nmis {
+        let mut lvt = LVT_NMI;
+        if nmi.polarity == Polarity::ActiveLow {
+            lvt |= LVT_ACTIVE_LOW;
+        }
+        if nmi.trigger_mode == TriggerMode::Level {
+            lvt |= LVT_LEVEL;
+        }
+        match nmi.lint {
+            0 => write(LVT_LINT0, lvt),
+            1 => write(LVT_LINT1, lvt),
+            _ => {}
+        }
+    }
+}
+
+/// Measures the frequency of the local APIC timer against the PIT.
+fn calibrate_timer() {
+    const CALIBRATION_MS: u64 = 10;
+
+    write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(LVT_TIMER, LVT_MASKED);
+    write(TIMER_INITIAL_COUNT, u32::max_value());
+    pit::wait_micros(CALIBRATION_MS * 1000);
+    let elapsed = u32::max_value() - read(TIMER_CURRENT_COUNT);
+    write(TIMER_INITIAL_COUNT, 0);
+
+    TIMER_TICKS_PER_MS.store(u64::from(elapsed) / CALIBRATION_MS, Ordering::SeqCst);
+}
+
+/// Checks CPUID for an on-chip APIC.
+fn has_apic() -> bool {
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    features.edx & (1 << 9) != 0
+}
+
+fn read(register: usize) -> u32 {
+    let base = LOCAL_APIC.load(Ordering::Relaxed) as usize;
+    unsafe { ptr::read_volatile((base + register) as *const u32) }
+}
+
+fn write(register: usize, value: u32) {
+    let base = LOCAL_APIC.load(Ordering::Relaxed) as usize;
+    unsafe { ptr::write_volatile((base + register) as *mut u32, value) }
+}
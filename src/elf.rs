@@ -0,0 +1,382 @@
+//! Loader of statically linked ELF64 executables.
+//!
+//! `load` checks the headers, maps every `PT_LOAD` segment with the access rights of
+//! its flags into a fresh address space and copies its file contents, the rest of
+//! the segment stays zeroed. The stack is mapped below `USER_STACK_TOP` and set up as
+//! the System V ABI describes it: `argc` at the stack pointer, followed by the
+//! `argv` and `envp` pointer arrays, each ending with a null pointer, and the
+//! auxiliary vector. The strings they point to are at the top of the stack.
+//!
+//! Only the user part of an address space can be loaded to, so programs must be
+//! linked above `USER_START`, e.g. with `-Ttext-segment`.
+
+use crate::memory::address_space::{
+    AddressSpace, AddressSpaceError, Protection, USER_END, USER_START,
+};
+use crate::memory::stack::StackError;
+use crate::thread::{self, ThreadId};
+use crate::time::Instant;
+use alloc::vec::Vec;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::VirtAddr;
+
+/// The stack pointer of a program starts below this address.
+pub const USER_STACK_TOP: u64 = USER_END;
+/// Size of the stack of a program, including the arguments and environment.
+pub const USER_STACK_SIZE: u64 = 64 * 4096;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+/// More program headers than any sane program has.
+const MAX_PROGRAM_HEADERS: u16 = 64;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const EV_CURRENT: u32 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+#[derive(Debug)]
+pub enum ElfError {
+    /// The file ends inside a header or segment.
+    Truncated,
+    /// The file doesn't start with the ELF magic.
+    BadMagic,
+    /// Not a statically linked little endian x86_64 executable.
+    Unsupported,
+    /// A program header is inconsistent or a segment lies outside the user part.
+    BadSegment,
+    /// The entry point is not in an executable segment.
+    BadEntry,
+    /// The arguments and environment don't fit onto the stack.
+    ArgumentsTooLarge,
+    Map(AddressSpaceError),
+    /// The kernel stack of the thread could not be allocated.
+    Stack(StackError),
+}
+
+impl From<AddressSpaceError> for ElfError {
+    fn from(err: AddressSpaceError) -> Self {
+        ElfError::Map(err)
+    }
+}
+
+impl From<MapToError> for ElfError {
+    fn from(err: MapToError) -> Self {
+        ElfError::Map(AddressSpaceError::Map(err))
+    }
+}
+
+impl From<StackError> for ElfError {
+    fn from(err: StackError) -> Self {
+        ElfError::Stack(err)
+    }
+}
+
+/// An entry of the program header table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+impl ProgramHeader {
+    /// Returns the access rights ring 3 gets for the segment.
+    pub fn protection(&self) -> Protection {
+        Protection::new(self.flags & PF_W != 0, self.flags & PF_X != 0, true)
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.vaddr && addr - self.vaddr < self.mem_size
+    }
+}
+
+/// An executable whose headers were checked.
+#[derive(Debug, Clone, Copy)]
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_header_offset: usize,
+    program_header_count: u16,
+}
+
+impl<'a> ElfFile<'a> {
+    /// Checks the ELF header and the program headers of `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELF_CLASS_64
+            || data[5] != ELF_DATA_LITTLE_ENDIAN
+            || u32::from(data[6]) != EV_CURRENT
+            || read_u16(data, 16) != ET_EXEC
+            || read_u16(data, 18) != EM_X86_64
+            || read_u32(data, 20) != EV_CURRENT
+        {
+            return Err(ElfError::Unsupported);
+        }
+
+        let count = read_u16(data, 56);
+        if usize::from(read_u16(data, 54)) != PROGRAM_HEADER_SIZE || count > MAX_PROGRAM_HEADERS {
+            return Err(ElfError::Unsupported);
+        }
+        let offset = read_u64(data, 32);
+        let end = offset.checked_add(u64::from(count) * PROGRAM_HEADER_SIZE as u64);
+        match end {
+            Some(end) if end <= data.len() as u64 => {}
+            _ => return Err(ElfError::Truncated),
+        }
+
+        let file = ElfFile {
+            data,
+            entry: read_u64(data, 24),
+            program_header_offset: offset as usize,
+            program_header_count: count,
+        };
+        for header in file.program_headers() {
+            match header.kind {
+                PT_LOAD => check_segment(&header, data.len())?,
+                // needs a dynamic linker
+                PT_INTERP => return Err(ElfError::Unsupported),
+                _ => {}
+            }
+        }
+        let entry_segment = file
+            .load_segments()
+            .find(|segment| segment.contains(file.entry));
+        match entry_segment {
+            Some(segment) if segment.flags & PF_X != 0 => Ok(file),
+            _ => Err(ElfError::BadEntry),
+        }
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let offset = self.program_header_offset;
+        (0..usize::from(self.program_header_count)).map(move |index| {
+            let header = offset + index * PROGRAM_HEADER_SIZE;
+            ProgramHeader {
+                kind: read_u32(data, header),
+                flags: read_u32(data, header + 4),
+                offset: read_u64(data, header + 8),
+                vaddr: read_u64(data, header + 16),
+                file_size: read_u64(data, header + 32),
+                mem_size: read_u64(data, header + 40),
+            }
+        })
+    }
+
+    /// Returns the `PT_LOAD` program headers.
+    pub fn load_segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers()
+            .filter(|header| header.kind == PT_LOAD)
+    }
+
+    /// Returns the file contents of `segment`.
+    fn contents(&self, segment: &ProgramHeader) -> &'a [u8] {
+        let start = segment.offset as usize;
+        &self.data[start..start + segment.file_size as usize]
+    }
+
+    /// Returns the address the program header table is loaded to, if it is.
+    fn program_header_addr(&self) -> Option<u64> {
+        if let Some(phdr) = self.program_headers().find(|h| h.kind == PT_PHDR) {
+            return Some(phdr.vaddr);
+        }
+        let offset = self.program_header_offset as u64;
+        let size = u64::from(self.program_header_count) * PROGRAM_HEADER_SIZE as u64;
+        self.load_segments()
+            .find(|s| offset >= s.offset && offset + size <= s.offset + s.file_size)
+            .map(|s| s.vaddr + (offset - s.offset))
+    }
+}
+
+/// A loaded program, ready to run in ring 3.
+#[derive(Debug)]
+pub struct Program {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    /// The initial stack pointer, pointing to `argc`.
+    pub stack_pointer: VirtAddr,
+}
+
+/// Loads the executable `data` into a fresh address space and sets up its stack with
+/// the arguments `args` and the environment `env`.
+pub fn load(data: &[u8], args: &[&str], env: &[&str]) -> Result<Program, ElfError> {
+    let file = ElfFile::parse(data)?;
+    let mut space = AddressSpace::new()?;
+    for segment in file.load_segments().filter(|s| s.mem_size > 0) {
+        map_segment(&mut space, &segment)?;
+        let start = VirtAddr::new(segment.vaddr);
+        space.write(start, file.contents(&segment))?;
+    }
+
+    let stack_bottom = VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE);
+    space.map(stack_bottom, USER_STACK_SIZE, Protection::USER_READ_WRITE)?;
+    let mut auxv = Vec::new();
+    if let Some(addr) = file.program_header_addr() {
+        auxv.push((AT_PHDR, addr));
+    }
+    auxv.push((AT_PHENT, PROGRAM_HEADER_SIZE as u64));
+    auxv.push((AT_PHNUM, u64::from(file.program_header_count)));
+    auxv.push((AT_PAGESZ, 4096));
+    auxv.push((AT_ENTRY, file.entry));
+    let stack_pointer = push_arguments(&mut space, args, env, &auxv)?;
+
+    Ok(Program {
+        address_space: space,
+        entry: VirtAddr::new(file.entry),
+        stack_pointer,
+    })
+}
+
+/// Loads the executable `data` and runs it in a new user thread.
+pub fn spawn(data: &[u8], args: &[&str], env: &[&str]) -> Result<ThreadId, ElfError> {
+    let program = load(data, args, env)?;
+    let id = thread::spawn_user(program.address_space, program.entry, program.stack_pointer)?;
+    Ok(id)
+}
+
+fn check_segment(segment: &ProgramHeader, file_size: usize) -> Result<(), ElfError> {
+    let file_end = segment
+        .offset
+        .checked_add(segment.file_size)
+        .ok_or(ElfError::Truncated)?;
+    if file_end > file_size as u64 {
+        return Err(ElfError::Truncated);
+    }
+    if segment.file_size > segment.mem_size {
+        return Err(ElfError::BadSegment);
+    }
+    let mem_end = segment
+        .vaddr
+        .checked_add(segment.mem_size)
+        .ok_or(ElfError::BadSegment)?;
+    if segment.vaddr < USER_START || mem_end > USER_END {
+        return Err(ElfError::BadSegment);
+    }
+    Ok(())
+}
+
+/// Maps the pages of `segment`. Pages shared with a segment mapped before get the
+/// access rights of both.
+fn map_segment(space: &mut AddressSpace, segment: &ProgramHeader) -> Result<(), ElfError> {
+    let protection = segment.protection();
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.vaddr));
+    let last = Page::containing_address(VirtAddr::new(segment.vaddr + segment.mem_size - 1));
+    for page in Page::range_inclusive(first, last) {
+        let start = page.start_address();
+        match space.protection(start) {
+            Some(mapped) => {
+                let combined = Protection::new(
+                    mapped.writable || protection.writable,
+                    mapped.executable || protection.executable,
+                    true,
+                );
+                space.protect(start, 4096, combined)?;
+            }
+            None => space.map(start, 4096, protection)?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes the strings, `argc`, `argv`, `envp` and the auxiliary vector `auxv` to the
+/// top of the stack and returns the stack pointer, which points to `argc`.
+fn push_arguments(
+    space: &mut AddressSpace,
+    args: &[&str],
+    env: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, ElfError> {
+    let strings_size = args.iter().chain(env).map(|s| s.len() + 1).sum::<usize>() + 16;
+    if strings_size as u64 > USER_STACK_SIZE / 2 {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+    let strings_start = (USER_STACK_TOP - strings_size as u64) & !15;
+
+    let mut strings = Vec::with_capacity(strings_size);
+    let mut words = Vec::new();
+    words.push(args.len() as u64);
+    for arg in args {
+        words.push(strings_start + strings.len() as u64);
+        strings.extend_from_slice(arg.as_bytes());
+        strings.push(0);
+    }
+    words.push(0);
+    for var in env {
+        words.push(strings_start + strings.len() as u64);
+        strings.extend_from_slice(var.as_bytes());
+        strings.push(0);
+    }
+    words.push(0);
+
+    // not random, but differs between programs, for libcs seeding stack protectors
+    let random_addr = strings_start + strings.len() as u64;
+    let seed = Instant::now().since_boot().as_nanos() as u64;
+    strings.extend_from_slice(&seed.to_le_bytes());
+    strings.extend_from_slice(&(seed ^ random_addr).rotate_left(29).to_le_bytes());
+
+    for &(kind, value) in auxv {
+        words.push(kind);
+        words.push(value);
+    }
+    words.extend_from_slice(&[AT_RANDOM, random_addr, AT_NULL, 0]);
+
+    let stack_pointer = (strings_start - 8 * words.len() as u64) & !15;
+    if USER_STACK_TOP - stack_pointer > USER_STACK_SIZE {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+    let mut bytes = Vec::with_capacity(8 * words.len());
+    for word in words {
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+    space.write(VirtAddr::new(strings_start), &strings)?;
+    space.write(VirtAddr::new(stack_pointer), &bytes)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
@@ -1,53 +1,110 @@
-use lazy_static::lazy_static;
+use crate::memory::stack;
+use crate::smp::percpu;
+use alloc::boxed::Box;
+use core::sync::atomic::Ordering;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::VirtAddr;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// NMIs, machine checks and debug exceptions can arrive right after `syscall`, while
+// the stack of ring 3 is still loaded, so they always switch stacks.
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const DEBUG_IST_INDEX: u16 = 3;
+/// Size of the double fault stack in pages.
+const DOUBLE_FAULT_STACK_PAGES: u64 = 4;
+/// Size of the other interrupt stacks in pages.
+const IST_STACK_PAGES: u64 = 4;
 
+// The order of the segments is fixed by `syscall` and `sysret`, which derive the
+// selectors from the STAR MSR: the kernel data segment follows the kernel code
+// segment, the user code segment follows the user data segment.
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+// Flat segments. The data segments are writable, which `iretq` checks when it loads
+// the stack segment of ring 3.
+const KERNEL_CODE_SEGMENT: u64 = 0x00af_9a00_0000_ffff;
+const KERNEL_DATA_SEGMENT: u64 = 0x00cf_9200_0000_ffff;
+const USER_DATA_SEGMENT: u64 = 0x00cf_f200_0000_ffff;
+const USER_CODE_SEGMENT: u64 = 0x00af_fa00_0000_ffff;
+
+/// Loads a GDT and a TSS for the executing processor.
+///
+/// Every processor needs its own TSS, since a TSS is marked busy while it is loaded,
+/// and with it gets its own interrupt stacks. The tables live forever, and so do the
+/// stacks. The stacks are allocated with a guard page, so memory and the heap must be
+/// initialized before, and so must the data of the processor.
 pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::segmentation::{load_ds, load_es, load_ss, set_cs};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
-    unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+    let tss: &'static mut TaskStateSegment = Box::leak(Box::new(new_tss()));
+    let tss_addr = tss as *mut TaskStateSegment as u64;
+    let mut gdt = GlobalDescriptorTable::new();
+    let selectors = [
+        gdt.add_entry(Descriptor::UserSegment(KERNEL_CODE_SEGMENT)),
+        gdt.add_entry(Descriptor::UserSegment(KERNEL_DATA_SEGMENT)),
+        gdt.add_entry(Descriptor::UserSegment(USER_DATA_SEGMENT)),
+        gdt.add_entry(Descriptor::UserSegment(USER_CODE_SEGMENT)),
+        gdt.add_entry(Descriptor::tss_segment(tss)),
+    ];
+    let expected = [
+        KERNEL_CODE_SELECTOR,
+        KERNEL_DATA_SELECTOR,
+        USER_DATA_SELECTOR,
+        USER_CODE_SELECTOR,
+        TSS_SELECTOR,
+    ];
+    for (selector, expected) in selectors.iter().zip(expected.iter()) {
+        debug_assert_eq!(selector.index(), expected.index());
     }
-}
+    let gdt: &'static GlobalDescriptorTable = Box::leak(Box::new(gdt));
+    percpu::current().tss.store(tss_addr, Ordering::SeqCst);
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
+    gdt.load();
+    unsafe {
+        set_cs(KERNEL_CODE_SELECTOR);
+        // the selectors loaded before may point to other entries of the new table,
+        // e.g. an application processor comes from the GDT of its trampoline
+        load_ss(KERNEL_DATA_SELECTOR);
+        load_ds(KERNEL_DATA_SELECTOR);
+        load_es(KERNEL_DATA_SELECTOR);
+        load_tss(TSS_SELECTOR);
+    }
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// Sets the stack the executing processor switches to when an interrupt or a system
+/// call arrives in ring 3.
+///
+/// Must be called with interrupts disabled, whenever a thread is switched in.
+pub fn set_kernel_stack(top: VirtAddr) {
+    let cpu = percpu::current();
+    cpu.kernel_stack.store(top.as_u64(), Ordering::Relaxed);
+    let tss = cpu.tss.load(Ordering::Relaxed) as *mut TaskStateSegment;
+    if !tss.is_null() {
+        // only read by the processor, when it enters ring 0 from ring 3
+        unsafe { (*tss).privilege_stack_table[0] = top };
+    }
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+fn new_tss() -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    let stacks = [
+        (DOUBLE_FAULT_IST_INDEX, DOUBLE_FAULT_STACK_PAGES),
+        (NMI_IST_INDEX, IST_STACK_PAGES),
+        (MACHINE_CHECK_IST_INDEX, IST_STACK_PAGES),
+        (DEBUG_IST_INDEX, IST_STACK_PAGES),
+    ];
+    for &(index, pages) in &stacks {
+        let stack = stack::allocate(pages).expect("failed to allocate interrupt stack");
+        tss.interrupt_stack_table[index as usize] = stack.top();
+        core::mem::forget(stack);
+    }
+    tss
 }
@@ -0,0 +1,91 @@
+//! High precision event timer (HPET) driver.
+//!
+//! Only the main counter is used, as a clock source. HPETs with a 32 bit counter are
+//! ignored, because their counter wraps around within minutes.
+
+use crate::acpi::{ACPI_TABLE, SYSTEM_MEMORY};
+use crate::memory::mmio;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+// registers
+const CAPABILITIES: usize = 0x00;
+const CONFIGURATION: usize = 0x10;
+const MAIN_COUNTER: usize = 0xf0;
+
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+const ENABLE_CNF: u64 = 1 << 0;
+const LEG_RT_CNF: u64 = 1 << 1;
+
+/// Femtoseconds per nanosecond.
+const FS_PER_NS: u128 = 1_000_000;
+
+/// Virtual address of the registers, zero if there is no usable HPET.
+static REGISTERS: AtomicU64 = AtomicU64::new(0);
+/// Length of a counter tick in femtoseconds.
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+/// Maps the HPET described by the ACPI tables and starts its main counter.
+///
+/// Must be called after `acpi::init`. Returns whether a usable HPET was found.
+pub fn init() -> bool {
+    let table = ACPI_TABLE.lock().hpet;
+    let base = match table {
+        Some(hpet) if hpet.base_address.address_space == SYSTEM_MEMORY => {
+            PhysAddr::new(hpet.base_address.address)
+        }
+        _ => return false,
+    };
+
+    let registers = unsafe { mmio::map(base, 0x400) }.expect("failed to map HPET");
+    let capabilities = read(registers.as_u64(), CAPABILITIES);
+    let period = capabilities >> 32;
+    if capabilities & COUNT_SIZE_CAP == 0 || period == 0 {
+        return false;
+    }
+
+    let config = read(registers.as_u64(), CONFIGURATION);
+    // the PIT and RTC keep raising their own interrupts
+    write(
+        registers.as_u64(),
+        CONFIGURATION,
+        (config & !LEG_RT_CNF) | ENABLE_CNF,
+    );
+
+    PERIOD_FS.store(period, Ordering::SeqCst);
+    REGISTERS.store(registers.as_u64(), Ordering::SeqCst);
+    true
+}
+
+/// Returns whether the HPET is used.
+pub fn is_enabled() -> bool {
+    REGISTERS.load(Ordering::Relaxed) != 0
+}
+
+/// Returns the value of the main counter.
+pub fn counter() -> u64 {
+    let registers = REGISTERS.load(Ordering::Relaxed);
+    assert!(registers != 0, "the HPET is not used");
+    read(registers, MAIN_COUNTER)
+}
+
+/// Returns the frequency of the main counter in Hz.
+pub fn frequency() -> u64 {
+    assert!(is_enabled(), "the HPET is not used");
+    (1_000_000_000_000_000 / u128::from(PERIOD_FS.load(Ordering::Relaxed))) as u64
+}
+
+/// Returns the value of the main counter in nanoseconds.
+pub fn nanos() -> u64 {
+    let period = u128::from(PERIOD_FS.load(Ordering::Relaxed));
+    (u128::from(counter()) * period / FS_PER_NS) as u64
+}
+
+fn read(registers: u64, register: usize) -> u64 {
+    unsafe { ptr::read_volatile((registers as usize + register) as *const u64) }
+}
+
+fn write(registers: u64, register: usize, value: u64) {
+    unsafe { ptr::write_volatile((registers as usize + register) as *mut u64, value) }
+}
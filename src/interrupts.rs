@@ -1,14 +1,37 @@
-use super::mouse::MOUSE;
-use crate::hlt_loop;
-use crate::print;
-use crate::println;
-use crate::{gdt, time};
+use crate::smp::percpu;
+use crate::syscall;
+use core::mem;
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
 use spin;
-use spin::Mutex;
-use x86_64::instructions::port::Port;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::PrivilegeLevel;
+
+const IA32_GS_BASE: u32 = 0xC000_0101;
+
+/// Prints to both the VGA buffer and the serial port, so that diagnostics of fatal
+/// errors are visible on screen and in the test output.
+macro_rules! oops_println {
+    ($($arg:tt)*) => {{
+        $crate::println!($($arg)*);
+        $crate::serial_println!($($arg)*);
+    }};
+}
+
+/// Like `oops_println`, but drops the output for a writer that is locked, since the
+/// interrupted code may hold it. For exceptions that can arrive anywhere.
+macro_rules! try_oops_println {
+    ($($arg:tt)*) => {{
+        $crate::vga_buffer::_try_print(format_args!("{}\n", format_args!($($arg)*)));
+        $crate::serial::_try_print(format_args!("{}\n", format_args!($($arg)*)));
+    }};
+}
+
+pub mod exceptions;
+pub mod irq;
+
+pub use irq::{register_irq, unregister_irq};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -19,136 +42,60 @@ pub static PICS: spin::Mutex<ChainedPics> =
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt[SecondaryInterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        exceptions::install(&mut idt);
+        irq::install(&mut idt);
+        // the entry saves the registers itself, it is no `x86-interrupt` function
+        let syscall_entry: HandlerFunc = unsafe { mem::transmute(syscall::interrupt_entry()) };
+        idt[usize::from(syscall::INTERRUPT_VECTOR)]
+            .set_handler_fn(syscall_entry)
+            .set_privilege_level(PrivilegeLevel::Ring3);
         idt
     };
 }
 
-extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    println!("123");
-    MOUSE.lock().handler();
-    println!("234");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(SecondaryInterruptIndex::Mouse.as_u8());
-    }
-}
-
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
-
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
-    }
-
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+pub fn init_idt() {
+    IDT.load();
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum SecondaryInterruptIndex {
-    RTClock = PIC_2_OFFSET,
-    ACPI,
-    Available1,
-    Available2,
-    Mouse,
+/// Loads the GS base of the kernel while an interrupt that arrived in ring 3 is
+/// handled and restores the one of ring 3 when dropped, see `syscall`.
+///
+/// Handlers create it before anything accesses the data of the processor.
+pub(crate) struct KernelGs {
+    from_user: bool,
 }
 
-impl SecondaryInterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
-
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+impl KernelGs {
+    pub(crate) fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        KernelGs::swap_if(stack_frame.code_segment & 3 == 3)
     }
-}
-
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-}
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
+    /// Like `enter`, but for NMIs, machine checks and debug exceptions, which can also
+    /// arrive in ring 0 while the GS base of ring 3 is loaded: right after `syscall`
+    /// and right before `sysretq` or `iretq`. Decides by the GS base itself.
+    pub(crate) fn enter_any() -> Self {
+        // ring 3 can't set its GS base, which `syscall::init` leaves at zero, while
+        // the one of the kernel points to the data of the processor
+        let gs_base = unsafe { Msr::new(IA32_GS_BASE).read() };
+        KernelGs::swap_if(gs_base == 0 && percpu::is_ready())
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    fn swap_if(from_user: bool) -> Self {
+        if from_user {
+            unsafe { asm!("swapgs" :::: "volatile") };
+        }
+        KernelGs { from_user }
     }
 }
 
-pub fn init_idt() {
-    IDT.load();
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    const PIT_RATE: u64 = 2_250_286;
-    let mut offset = time::OFFSET.lock();
-    let sum = offset.1 + PIT_RATE;
-    offset.1 = sum % 1_000_000_000;
-    offset.0 += sum / 1_000_000_000;
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.from_user {
+            unsafe { asm!("swapgs" :::: "volatile") };
+        }
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-}
-
 #[cfg(test)]
 use crate::{serial_print, serial_println};
 
@@ -0,0 +1,550 @@
+//! Handlers for the architectural CPU exceptions (vectors 0 to 31).
+//!
+//! Every fatal exception prints its name, the decoded error code, the interrupt stack
+//! frame and the control registers to both the VGA buffer and the serial port before
+//! panicking. Breakpoints, debug exceptions and NMIs are reported and resumed.
+//!
+//! The exceptions that can be fatal enter through assembly stubs, which save the
+//! general purpose registers of the interrupted code for the register dump. The
+//! other handlers use the `x86-interrupt` calling convention, which doesn't expose
+//! them, so their dump is limited to the registers that can be read from inside the
+//! handler.
+//!
+//! A fatal exception in ring 3 only ends the process of the thread that raised it
+//! with `process::EXCEPTION_STATUS`, or the thread alone if it has no process.
+//!
+//! NMIs and debug exceptions can interrupt code holding the VGA or serial lock, so
+//! their reports skip locked writers instead of waiting for them.
+//!
+//! Page faults are fatal unless they hit a lazily backed kernel area or are writes
+//! of ring 3 to a copy-on-write page, see `AddressSpace::handle_write_fault`.
+
+use super::KernelGs;
+use crate::memory::vma::{self, FaultError};
+use crate::{gdt, process, thread};
+use core::{fmt, mem};
+use x86_64::registers::control::{Cr0, Cr2, Cr3};
+use x86_64::registers::model_specific::{Efer, Msr};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+const IA32_FS_BASE: u32 = 0xC000_0100;
+const IA32_GS_BASE: u32 = 0xC000_0101;
+
+/// Returns the entry stub `$stub` as a handler function of the IDT.
+macro_rules! stub {
+    ($stub:ident) => {
+        // the stubs save the registers themselves, they are no `x86-interrupt`
+        // functions
+        unsafe { mem::transmute::<unsafe extern "C" fn(), _>($stub) }
+    };
+}
+
+/// Installs the handlers of all exceptions into `idt`.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.debug
+            .set_handler_fn(debug_handler)
+            .set_stack_index(gdt::DEBUG_IST_INDEX);
+        idt.non_maskable_interrupt
+            .set_handler_fn(nmi_handler)
+            .set_stack_index(gdt::NMI_IST_INDEX);
+    }
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+
+    idt.divide_error.set_handler_fn(stub!(divide_error_entry));
+    idt.overflow.set_handler_fn(stub!(overflow_entry));
+    idt.bound_range_exceeded
+        .set_handler_fn(stub!(bound_range_exceeded_entry));
+    idt.invalid_opcode
+        .set_handler_fn(stub!(invalid_opcode_entry));
+    idt.device_not_available
+        .set_handler_fn(stub!(device_not_available_entry));
+    let options = idt.double_fault.set_handler_fn(stub!(double_fault_entry));
+    unsafe { options.set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX) };
+    idt.invalid_tss.set_handler_fn(stub!(invalid_tss_entry));
+    idt.segment_not_present
+        .set_handler_fn(stub!(segment_not_present_entry));
+    idt.stack_segment_fault
+        .set_handler_fn(stub!(stack_segment_fault_entry));
+    idt.general_protection_fault
+        .set_handler_fn(stub!(general_protection_fault_entry));
+    idt.page_fault.set_handler_fn(stub!(page_fault_entry));
+    idt.x87_floating_point
+        .set_handler_fn(stub!(x87_floating_point_entry));
+    idt.alignment_check
+        .set_handler_fn(stub!(alignment_check_entry));
+    let options = idt.machine_check.set_handler_fn(stub!(machine_check_entry));
+    unsafe { options.set_stack_index(gdt::MACHINE_CHECK_IST_INDEX) };
+    idt.simd_floating_point
+        .set_handler_fn(stub!(simd_floating_point_entry));
+    idt.virtualization
+        .set_handler_fn(stub!(virtualization_entry));
+    idt.security_exception
+        .set_handler_fn(stub!(security_exception_entry));
+}
+
+/// The error code pushed by exceptions that refer to a segment selector or an IDT
+/// entry (#TS, #NP, #SS and #GP).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+/// The descriptor table a `SelectorErrorCode` refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+impl SelectorErrorCode {
+    /// Whether the exception was caused by an event external to the program, e.g. a
+    /// hardware interrupt.
+    pub fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// Index of the descriptor in `table`, i.e. the selector shifted right by three
+    /// or the interrupt vector for the IDT.
+    pub fn index(self) -> u64 {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "{:#x} (not selector related)", self.0);
+        }
+        write!(
+            f,
+            "{:#x} ({:?} index {}",
+            self.0,
+            self.table(),
+            self.index()
+        )?;
+        if self.external() {
+            write!(f, ", external")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The registers that can be read inside an exception handler.
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterDump {
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+}
+
+impl RegisterDump {
+    pub fn read() -> Self {
+        let cr4: u64;
+        unsafe { asm!("mov %cr4, $0" : "=r"(cr4) ::: "volatile") };
+        let (cr3, cr3_flags) = Cr3::read();
+        RegisterDump {
+            cr0: Cr0::read().bits(),
+            cr2: Cr2::read().as_u64(),
+            cr3: cr3.start_address().as_u64() | cr3_flags.bits(),
+            cr4,
+            efer: Efer::read().bits(),
+            fs_base: unsafe { Msr::new(IA32_FS_BASE).read() },
+            gs_base: unsafe { Msr::new(IA32_GS_BASE).read() },
+        }
+    }
+}
+
+impl fmt::Display for RegisterDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "CR0={:#018x} CR2={:#018x} CR3={:#018x} CR4={:#018x}",
+            self.cr0, self.cr2, self.cr3, self.cr4
+        )?;
+        write!(
+            f,
+            "EFER={:#x} FS_BASE={:#x} GS_BASE={:#x}",
+            self.efer, self.fs_base, self.gs_base
+        )
+    }
+}
+
+/// The general purpose registers of the interrupted code, in the reverse order the
+/// entry stubs push them.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GeneralRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+impl fmt::Display for GeneralRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "RAX={:#018x} RBX={:#018x} RCX={:#018x} RDX={:#018x}",
+            self.rax, self.rbx, self.rcx, self.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI={:#018x} RDI={:#018x} RBP={:#018x} R8 ={:#018x}",
+            self.rsi, self.rdi, self.rbp, self.r8
+        )?;
+        writeln!(
+            f,
+            "R9 ={:#018x} R10={:#018x} R11={:#018x} R12={:#018x}",
+            self.r9, self.r10, self.r11, self.r12
+        )?;
+        write!(
+            f,
+            "R13={:#018x} R14={:#018x} R15={:#018x}",
+            self.r13, self.r14, self.r15
+        )
+    }
+}
+
+/// What the entry stubs leave on the stack.
+#[repr(C)]
+struct ExceptionFrame {
+    registers: GeneralRegisters,
+    vector: u64,
+    /// Zero for the exceptions without an error code.
+    error_code: u64,
+    stack_frame: InterruptStackFrame,
+}
+
+global_asm!(
+    r#"
+    // Pushes a zero error code unless the processor pushed one, so that every
+    // exception leaves an `ExceptionFrame` on the stack.
+    .macro exception_entry name, vector
+    .global \name
+\name:
+    pushq $0
+    pushq $\vector
+    jmp exception_common
+    .endm
+
+    .macro exception_entry_error_code name, vector
+    .global \name
+\name:
+    pushq $\vector
+    jmp exception_common
+    .endm
+
+    exception_entry divide_error_entry, 0
+    exception_entry overflow_entry, 4
+    exception_entry bound_range_exceeded_entry, 5
+    exception_entry invalid_opcode_entry, 6
+    exception_entry device_not_available_entry, 7
+    exception_entry_error_code double_fault_entry, 8
+    exception_entry_error_code invalid_tss_entry, 10
+    exception_entry_error_code segment_not_present_entry, 11
+    exception_entry_error_code stack_segment_fault_entry, 12
+    exception_entry_error_code general_protection_fault_entry, 13
+    exception_entry_error_code page_fault_entry, 14
+    exception_entry x87_floating_point_entry, 16
+    exception_entry_error_code alignment_check_entry, 17
+    exception_entry machine_check_entry, 18
+    exception_entry simd_floating_point_entry, 19
+    exception_entry virtualization_entry, 20
+    exception_entry_error_code security_exception_entry, 30
+
+    // The stack is 16 byte aligned again after the registers were pushed.
+exception_common:
+    push %rax
+    push %rbx
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rbp
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    mov %rsp, %rdi
+    cld
+    call exception_handler
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rbp
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rbx
+    pop %rax
+    // the vector and the error code
+    add $16, %rsp
+    iretq
+    "#
+);
+
+extern "C" {
+    fn divide_error_entry();
+    fn overflow_entry();
+    fn bound_range_exceeded_entry();
+    fn invalid_opcode_entry();
+    fn device_not_available_entry();
+    fn double_fault_entry();
+    fn invalid_tss_entry();
+    fn segment_not_present_entry();
+    fn stack_segment_fault_entry();
+    fn general_protection_fault_entry();
+    fn page_fault_entry();
+    fn x87_floating_point_entry();
+    fn alignment_check_entry();
+    fn machine_check_entry();
+    fn simd_floating_point_entry();
+    fn virtualization_entry();
+    fn security_exception_entry();
+}
+
+/// Prints a line with `oops_println`.
+fn oops_line(args: fmt::Arguments) {
+    oops_println!("{}", args);
+}
+
+/// Prints a line with `try_oops_println`.
+fn try_oops_line(args: fmt::Arguments) {
+    try_oops_println!("{}", args);
+}
+
+/// Prints everything known about an exception line by line with `print`.
+///
+/// Exceptions that can interrupt code holding the VGA or serial lock, like NMIs,
+/// must pass `try_oops_line`.
+fn report(
+    print: fn(fmt::Arguments),
+    name: &str,
+    vector: u8,
+    error: Option<&dyn fmt::Display>,
+    frame: &InterruptStackFrame,
+    registers: Option<&GeneralRegisters>,
+) {
+    print(format_args!("EXCEPTION: {} (vector {})", name, vector));
+    if let Some(error) = error {
+        print(format_args!("Error Code: {}", error));
+    }
+    print(format_args!(
+        "RFLAGS: {:?}",
+        RFlags::from_bits_truncate(frame.cpu_flags)
+    ));
+    if let Some(registers) = registers {
+        print(format_args!("{}", registers));
+    }
+    print(format_args!("{}", RegisterDump::read()));
+    print(format_args!("{:#?}", frame));
+}
+
+fn fatal(name: &str, vector: u8, error: Option<&dyn fmt::Display>, frame: &ExceptionFrame) -> ! {
+    report(
+        oops_line,
+        name,
+        vector,
+        error,
+        &frame.stack_frame,
+        Some(&frame.registers),
+    );
+    if frame.stack_frame.code_segment & 3 == 3 {
+        let thread = thread::current().as_u64();
+        match process::current() {
+            Some(pid) => oops_println!("ending process {} of user thread {}", pid.as_u64(), thread),
+            None => oops_println!("ending user thread {}", thread),
+        }
+        process::exit(process::EXCEPTION_STATUS);
+    }
+    panic!("EXCEPTION: {}", name);
+}
+
+/// Called by the entry stubs, returning resumes the interrupted code.
+#[no_mangle]
+extern "C" fn exception_handler(frame: &mut ExceptionFrame) {
+    // machine checks can arrive anywhere, like NMIs
+    let _gs = if frame.vector == 18 {
+        KernelGs::enter_any()
+    } else {
+        KernelGs::enter(&frame.stack_frame)
+    };
+    match frame.vector {
+        8 => double_fault(frame),
+        14 => page_fault(frame),
+        _ => fatal_exception(frame),
+    }
+}
+
+fn fatal_exception(frame: &ExceptionFrame) -> ! {
+    let name = match frame.vector {
+        0 => "DIVIDE ERROR",
+        4 => "OVERFLOW",
+        5 => "BOUND RANGE EXCEEDED",
+        6 => "INVALID OPCODE",
+        7 => "DEVICE NOT AVAILABLE",
+        10 => "INVALID TSS",
+        11 => "SEGMENT NOT PRESENT",
+        12 => "STACK SEGMENT FAULT",
+        13 => "GENERAL PROTECTION FAULT",
+        16 => "X87 FLOATING POINT",
+        17 => "ALIGNMENT CHECK",
+        18 => "MACHINE CHECK",
+        19 => "SIMD FLOATING POINT",
+        20 => "VIRTUALIZATION",
+        30 => "SECURITY EXCEPTION",
+        _ => "UNKNOWN",
+    };
+    let selector = SelectorErrorCode(frame.error_code);
+    let error: Option<&dyn fmt::Display> = match frame.vector {
+        10..=13 => Some(&selector),
+        17 | 30 => Some(&frame.error_code),
+        _ => None,
+    };
+    fatal(name, frame.vector as u8, error, frame);
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter_any();
+    report(try_oops_line, "DEBUG", 1, None, stack_frame, None);
+}
+
+extern "x86-interrupt" fn nmi_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter_any();
+    report(
+        try_oops_line,
+        "NON-MASKABLE INTERRUPT",
+        2,
+        None,
+        stack_frame,
+        None,
+    );
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    report(oops_line, "BREAKPOINT", 3, None, stack_frame, None);
+}
+
+fn double_fault(frame: &ExceptionFrame) -> ! {
+    // a page fault in a guard page can't be handled on the overflowed stack
+    let addr = Cr2::read();
+    if let Some(vmas) = vma::KERNEL_VMAS.try_lock() {
+        if let Some(guard) = vmas.find(addr).filter(|v| v.kind == vma::VmaKind::Guard) {
+            oops_println!(
+                "kernel stack overflow: accessed {:?} in `{}` ({:?}..{:?})",
+                addr,
+                guard.name,
+                guard.start,
+                guard.end
+            );
+        }
+    }
+    // the error code of a double fault is always zero
+    fatal("DOUBLE FAULT", 8, Some(&frame.error_code), frame);
+}
+
+fn page_fault(frame: &ExceptionFrame) {
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let addr = Cr2::read();
+    let reason = match resolve_page_fault(addr, error_code) {
+        Ok(()) => return,
+        Err(reason) => reason,
+    };
+
+    oops_println!("Accessed Address: {:?}", addr);
+    oops_println!("Reason: {}", reason);
+    fatal("PAGE FAULT", 14, Some(&DebugDisplay(error_code)), frame);
+}
+
+/// Resolves a write of ring 3 to a copy-on-write page of the running thread, or else
+/// a fault inside a lazily backed kernel area.
+fn resolve_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    let write = PageFaultErrorCode::PROTECTION_VIOLATION
+        | PageFaultErrorCode::CAUSED_BY_WRITE
+        | PageFaultErrorCode::USER_MODE;
+    if error_code.contains(write) {
+        if let Some(address_space) = thread::address_space() {
+            // a fault of ring 3 runs in the context of its thread, which holds no
+            // locks, so the address space is locked as in a system call, like
+            // `fatal` ends the process
+            match address_space.lock().handle_write_fault(addr) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(_) => return Err(FaultError::CopyOnWrite),
+            }
+        }
+    }
+    vma::handle_page_fault(addr, error_code)
+}
+
+/// Displays a value through its `Debug` implementation.
+struct DebugDisplay<T>(T);
+
+impl<T: fmt::Debug> fmt::Display for DebugDisplay<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_selector_error_code() {
+    serial_print!("test_selector_error_code... ");
+    // GDT index 5 (selector 0x28)
+    let code = SelectorErrorCode(0x28);
+    assert_eq!(code.table(), DescriptorTable::Gdt);
+    assert_eq!(code.index(), 5);
+    assert!(!code.external());
+    // IDT vector 0x21, raised by an external event
+    let code = SelectorErrorCode((0x21 << 3) | 0b011);
+    assert_eq!(code.table(), DescriptorTable::Idt);
+    assert_eq!(code.index(), 0x21);
+    assert!(code.external());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_exception_frame_layout() {
+    serial_print!("test_exception_frame_layout... ");
+    // 15 registers, the vector, the error code and the frame of the processor
+    assert_eq!(mem::size_of::<ExceptionFrame>(), (15 + 2 + 5) * 8);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,215 @@
+//! Registration of hardware interrupt handlers.
+//!
+//! Every IRQ line has a generic stub in the IDT that counts the interrupt, calls the
+//! handlers registered for the line in registration order and then signals the end
+//! of the interrupt to the interrupt controller. Several drivers can share a line.
+//! A thread whose time slice ran out is preempted only after that.
+//!
+//! The handlers are copied out of the registry before they are called, so they can
+//! register and unregister handlers themselves. A handler can still be called by an
+//! interrupt that started before it was unregistered.
+//!
+//! Without the APIC, the 8259 PICs raise IRQ 7 or 15 for an interrupt request that
+//! went away before it was acknowledged. These spurious interrupts are neither
+//! counted nor passed to the handlers.
+//!
+//! IRQ `n` is delivered on vector `PIC_1_OFFSET + n`. With the APIC, IRQs 16 to 23
+//! are the GSIs of the same number, the IRQs from 24 on are raised by the local APIC
+//! timer and by other processors.
+
+use super::{KernelGs, PICS, PIC_1_OFFSET};
+use crate::sync::lock_order;
+use crate::{apic, thread};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+pub const TIMER: u8 = 0;
+pub const KEYBOARD: u8 = 1;
+pub const RTC: u8 = 8;
+pub const ACPI: u8 = 9;
+pub const MOUSE: u8 = 12;
+/// The local APIC timer, only raised when the APIC is used.
+pub const LAPIC_TIMER: u8 = 24;
+/// Sent by another processor to flush TLB entries, see `smp::ipi`.
+pub const TLB_SHOOTDOWN: u8 = 30;
+/// Sent by another processor when a thread became ready.
+pub const RESCHEDULE: u8 = 31;
+
+/// Number of legacy IRQs handled by the 8259 PICs.
+pub const LEGACY_IRQS: u8 = 16;
+/// Number of IRQ lines with a stub, including the ones only reachable through an
+/// APIC.
+pub const MAX_IRQS: u8 = 32;
+/// Maximum number of handlers sharing an IRQ line.
+pub const MAX_SHARED_HANDLERS: usize = 8;
+
+/// The commands and ports of the 8259 PICs to read their in-service registers.
+const PIC_READ_ISR: u8 = 0x0b;
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+/// The IRQ the slave PIC is cascaded on.
+const CASCADE: u8 = 2;
+
+/// A handler for an IRQ line. It is called with the IRQ number and interrupts
+/// disabled, so it must not block.
+pub type IrqHandler = fn(u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The IRQ number is not below `MAX_IRQS`.
+    InvalidIrq,
+    /// The handler is already registered for the IRQ.
+    AlreadyRegistered,
+    /// The IRQ already has `MAX_SHARED_HANDLERS` handlers.
+    TooManyHandlers,
+}
+
+/// The handlers of an IRQ line in registration order, followed by `None`s.
+type Chain = [Option<IrqHandler>; MAX_SHARED_HANDLERS];
+
+const NO_HANDLERS: Chain = [None; MAX_SHARED_HANDLERS];
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+static HANDLERS: Mutex<[Chain; MAX_IRQS as usize]> = Mutex::new([NO_HANDLERS; MAX_IRQS as usize]);
+static COUNTS: [AtomicU64; MAX_IRQS as usize] = [ZERO; MAX_IRQS as usize];
+
+/// Adds `handler` to the handlers of `irq`.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if irq >= MAX_IRQS {
+        return Err(IrqError::InvalidIrq);
+    }
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let chain = &mut handlers[usize::from(irq)];
+        if chain
+            .iter()
+            .flatten()
+            .any(|&h| h as usize == handler as usize)
+        {
+            return Err(IrqError::AlreadyRegistered);
+        }
+        let free = chain
+            .iter_mut()
+            .find(|h| h.is_none())
+            .ok_or(IrqError::TooManyHandlers)?;
+        *free = Some(handler);
+        Ok(())
+    })
+}
+
+/// Removes `handler` from the handlers of `irq`. Returns whether it was registered.
+pub fn unregister_irq(irq: u8, handler: IrqHandler) -> bool {
+    if irq >= MAX_IRQS {
+        return false;
+    }
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let chain = &mut handlers[usize::from(irq)];
+        match chain
+            .iter()
+            .position(|&h| h.map(|h| h as usize) == Some(handler as usize))
+        {
+            Some(index) => {
+                // keep the order of the remaining handlers
+                chain[index..].rotate_left(1);
+                chain[MAX_SHARED_HANDLERS - 1] = None;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Returns how often `irq` was raised since boot.
+pub fn irq_count(irq: u8) -> u64 {
+    COUNTS
+        .get(usize::from(irq))
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// Returns the vector `irq` is delivered on.
+pub fn vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+fn dispatch(irq: u8) {
+    if is_spurious(irq) {
+        // the master PIC raised the cascade IRQ for the slave and expects its EOI
+        if irq == 15 {
+            unsafe { PICS.lock().notify_end_of_interrupt(vector(CASCADE)) };
+        }
+        return;
+    }
+    COUNTS[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+    let chain = HANDLERS.lock()[usize::from(irq)];
+    // the handlers don't wait for the locks of the interrupted thread
+    let held_locks = lock_order::held();
+    lock_order::set_held(0);
+    for handler in chain.iter().flatten() {
+        handler(irq);
+    }
+    lock_order::set_held(held_locks);
+    end_of_interrupt(irq);
+    thread::preempt_if_requested();
+}
+
+/// Returns whether `irq` is a spurious interrupt of the 8259 PICs, which is the case
+/// if the PIC raised IRQ 7 or 15 without marking it in service.
+fn is_spurious(irq: u8) -> bool {
+    let command = match irq {
+        7 => PIC_1_COMMAND,
+        15 => PIC_2_COMMAND,
+        _ => return false,
+    };
+    if apic::is_enabled() {
+        return false;
+    }
+    let _pics = PICS.lock();
+    let mut port = Port::<u8>::new(command);
+    unsafe {
+        port.write(PIC_READ_ISR);
+        port.read() & (1 << (irq % 8)) == 0
+    }
+}
+
+fn end_of_interrupt(irq: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else if irq < LEGACY_IRQS {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector(irq)) };
+    }
+}
+
+/// Spurious APIC interrupts are not counted and must not be acknowledged.
+extern "x86-interrupt" fn spurious_handler(_stack_frame: &mut InterruptStackFrame) {}
+
+macro_rules! irq_stubs {
+    ($($irq:expr => $stub:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $stub(stack_frame: &mut InterruptStackFrame) {
+                let _gs = KernelGs::enter(stack_frame);
+                dispatch($irq);
+            }
+        )*
+
+        /// Installs the stubs of all IRQ lines into `idt`.
+        pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+            $(idt[usize::from(vector($irq))].set_handler_fn($stub);)*
+            idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_handler);
+        }
+    };
+}
+
+irq_stubs! {
+    0 => irq0, 1 => irq1, 2 => irq2, 3 => irq3,
+    4 => irq4, 5 => irq5, 6 => irq6, 7 => irq7,
+    8 => irq8, 9 => irq9, 10 => irq10, 11 => irq11,
+    12 => irq12, 13 => irq13, 14 => irq14, 15 => irq15,
+    16 => irq16, 17 => irq17, 18 => irq18, 19 => irq19,
+    20 => irq20, 21 => irq21, 22 => irq22, 23 => irq23,
+    24 => irq24, 25 => irq25, 26 => irq26, 27 => irq27,
+    28 => irq28, 29 => irq29, 30 => irq30, 31 => irq31,
+}
@@ -0,0 +1,14 @@
+use crate::interrupts::irq;
+use crate::task;
+use x86_64::instructions::port::Port;
+
+pub fn init() {
+    irq::register_irq(irq::KEYBOARD, interrupt_handler).expect("keyboard IRQ is taken");
+}
+
+/// Hands the scancode to `task::keyboard`, where it is decoded.
+fn interrupt_handler(_irq: u8) {
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    task::keyboard::add_scancode(scancode);
+}
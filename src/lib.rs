@@ -5,16 +5,31 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(alloc_error_handler)]
+#![feature(const_in_array_repeat_expressions)]
+#![feature(asm)]
+#![feature(global_asm)]
 
 #[macro_use]
 pub mod serial;
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod elf;
 pub mod gdt;
+pub mod hpet;
 pub mod interrupts;
+pub mod keyboard;
 pub mod memory;
 pub mod mouse;
-mod pit;
+pub mod pit;
+pub mod power;
+pub mod process;
 pub mod rtc;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod thread;
 pub mod time;
 pub mod vga_buffer;
 
@@ -22,20 +37,27 @@ extern crate alloc;
 
 use core::panic::PanicInfo;
 
-use linked_list_allocator::LockedHeap;
+use allocator::{fixed_size_block::FixedSizeBlockAllocator, Locked};
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
 pub fn init() {
+    smp::percpu::init_bsp();
     gdt::init();
+    syscall::init();
     interrupts::init_idt();
+    acpi::init();
     pit::init();
+    hpet::init();
+    time::init();
+    thread::init();
     rtc::init();
     unsafe { interrupts::PICS.lock().initialize() };
-    // have to initialize the mouse before
-    // enabling the interrupts or we will have a deadlock
+    apic::init();
     mouse::MOUSE.lock().init();
+    keyboard::init();
+    smp::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -59,13 +81,11 @@ entry_point!(test_kernel_main);
 /// Entry point for `cargo xtest`
 #[cfg(test)]
 fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
-    use crate::memory::BootInfoFrameAllocator;
     use x86_64::VirtAddr;
 
     let phys_mem_offset = VirtAddr::new(_boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&_boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    unsafe { memory::init(phys_mem_offset, &_boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
 
     init();
     test_main();
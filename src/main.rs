@@ -10,6 +10,7 @@ use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 #[allow(unused_imports)]
 use metal_os::println;
+use metal_os::task::{executor::Executor, keyboard, mouse, Task};
 use x86_64::VirtAddr;
 
 entry_point!(kernel_main);
@@ -17,19 +18,22 @@ entry_point!(kernel_main);
 // this function is the entry point, since the linker looks for a function
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use metal_os::allocator;
-    use metal_os::memory::{self, BootInfoFrameAllocator};
+    use metal_os::memory;
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    allocator::init_heap().expect("heap initialization failed");
 
     metal_os::init();
 
     #[cfg(test)]
     test_main();
-    metal_os::hlt_loop();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(mouse::process_packets()));
+    executor.run();
 }
 
 /// This function is called on panic.
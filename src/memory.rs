@@ -1,63 +1,372 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB, UnusedPhysFrame,
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB, UnusedPhysFrame,
     },
     PhysAddr, VirtAddr,
 };
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+pub mod address_space;
+pub mod mmio;
+pub mod stack;
+pub mod vma;
+
+/// Number of frames tracked by a single bitmap word.
+const FRAMES_PER_WORD: usize = 64;
+
+/// A physical frame allocator backed by a bitmap with one bit per 4 KiB frame.
+///
+/// The bitmap itself lives in physical memory taken from the first usable region of
+/// the bootloader's memory map that is large enough to hold it, and is accessed through
+/// the complete physical memory mapping at `physical_memory_offset`. A set bit means
+/// that the frame is in use (or is not usable RAM at all).
+///
+/// A used frame can be shared, e.g. by copy-on-write mappings. It is only freed when
+/// every reference to it was deallocated.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// The references to each used frame beyond the first one, stored behind the
+    /// bitmap.
+    shares: &'static mut [u16],
+    total_frames: usize,
+    free_frames: usize,
+    /// Index of the first word that might contain a free bit.
+    next_word: usize,
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        for word_index in self.next_word..self.bitmap.len() {
+            let word = self.bitmap[word_index];
+            if word == !0 {
+                continue;
+            }
+            let bit = (!word).trailing_zeros() as usize;
+            self.bitmap[word_index] |= 1 << bit;
+            self.free_frames -= 1;
+            self.next_word = word_index;
+            let frame = frame_from_index(word_index * FRAMES_PER_WORD + bit);
+            // the bit was clear, so nobody else owns this frame
+            return Some(unsafe { UnusedPhysFrame::new(frame) });
+        }
+        self.next_word = self.bitmap.len();
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    /// Drops a reference to `frame`, which is freed if it was the last one.
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame) {
+        let index = frame_index(*frame);
+        if self.shares[index] > 0 {
+            self.shares[index] -= 1;
+        } else {
+            self.free(index);
+        }
     }
 }
 
-impl BootInfoFrameAllocator {
+impl BitmapFrameAllocator {
     /// Create a FrameAllocator from the passed memory map.
     ///
     /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+    /// memory map is valid and that the complete physical memory is mapped at
+    /// `physical_memory_offset`. The main requirement is that all frames that are marked
+    /// as `USABLE` in the memory map are really unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let total_frames = usable_regions()
+            .map(|r| r.range.end_frame_number as usize)
+            .max()
+            .unwrap_or(0);
+        let words = (total_frames + FRAMES_PER_WORD - 1) / FRAMES_PER_WORD;
+        let bitmap_frames = (words * 8 + 4095) / 4096;
+        let shares_frames = (words * FRAMES_PER_WORD * 2 + 4095) / 4096;
+        let storage_frames = bitmap_frames + shares_frames;
+
+        // place the bitmap and the share counts at the start of the first region that
+        // is large enough
+        let storage = usable_regions()
+            .find(|r| {
+                (r.range.end_frame_number - r.range.start_frame_number) as usize >= storage_frames
+            })
+            .expect("no usable region large enough for the frame bitmap");
+        let storage_start = storage.range.start_frame_number as usize;
+
+        let bitmap_ptr: *mut u64 =
+            (physical_memory_offset + storage.range.start_addr()).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+        let shares_ptr: *mut u16 = bitmap_ptr.add(bitmap_frames * 512) as *mut u16;
+        let shares = core::slice::from_raw_parts_mut(shares_ptr, words * FRAMES_PER_WORD);
+
+        // everything is used until proven usable
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        for count in shares.iter_mut() {
+            *count = 0;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            shares,
+            total_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        };
+        for region in usable_regions() {
+            let start = region.range.start_frame_number as usize;
+            let end = region.range.end_frame_number as usize;
+            for index in start..end {
+                allocator.total_frames += 1;
+                if index < storage_start || index >= storage_start + storage_frames {
+                    allocator.free(index);
+                }
+            }
+        }
+        allocator.next_word = 0;
+        allocator
+    }
+
+    /// Allocates `count` physically contiguous frames, e.g. for DMA buffers.
+    ///
+    /// The first frame of the returned range is aligned to `align` frames, which must be
+    /// a power of two.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+
+        let limit = self.bitmap.len() * FRAMES_PER_WORD;
+        let mut start = self.next_word * FRAMES_PER_WORD;
+        start = (start + align - 1) & !(align - 1);
+        while start + count <= limit {
+            match (start..start + count).find(|&index| self.is_used(index)) {
+                // continue searching behind the used frame
+                Some(used) => start = (used + 1 + align - 1) & !(align - 1),
+                None => {
+                    for index in start..start + count {
+                        self.set_used(index);
+                    }
+                    self.free_frames -= count;
+                    return Some(PhysFrame::range(
+                        frame_from_index(start),
+                        frame_from_index(start + count),
+                    ));
+                }
+            }
+        }
+        None
+    }
+
+    /// Allocates a frame that lies below `limit`, e.g. for code that runs in real mode.
+    pub fn allocate_frame_below(&mut self, limit: PhysAddr) -> Option<UnusedPhysFrame> {
+        let end = ((limit.as_u64() / 4096) as usize).min(self.bitmap.len() * FRAMES_PER_WORD);
+        let index = (0..end).find(|&index| !self.is_used(index))?;
+        self.set_used(index);
+        self.free_frames -= 1;
+        // the bit was clear, so nobody else owns this frame
+        Some(unsafe { UnusedPhysFrame::new(frame_from_index(index)) })
+    }
+
+    /// Returns a range of frames obtained from `allocate_contiguous`.
+    pub fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.free(frame_index(frame));
+        }
+    }
+
+    /// Number of usable frames managed by this allocator.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Number of frames that are currently available.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Adds a reference to the used `frame`, which must then be deallocated once more
+    /// before it is freed.
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame);
+        assert!(self.is_used(index), "sharing free frame {}", index);
+        self.shares[index] = self.shares[index]
+            .checked_add(1)
+            .expect("too many references to a frame");
+    }
+
+    /// Number of references to `frame`, zero if it is free.
+    pub fn reference_count(&self, frame: PhysFrame) -> usize {
+        let index = frame_index(frame);
+        if self.is_used(index) {
+            self.shares[index] as usize + 1
+        } else {
+            0
         }
     }
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = UnusedPhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        let frames = frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)));
-        // we know that the frames are really unused
-        frames.map(|f| unsafe { UnusedPhysFrame::new(f) })
+    /// Number of frames that are currently allocated, including the bitmap and the share
+    /// counts themselves.
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / FRAMES_PER_WORD] & (1 << (index % FRAMES_PER_WORD)) != 0
+    }
+
+    fn set_used(&mut self, index: usize) {
+        self.bitmap[index / FRAMES_PER_WORD] |= 1 << (index % FRAMES_PER_WORD);
+    }
+
+    fn free(&mut self, index: usize) {
+        assert!(self.is_used(index), "double free of frame {}", index);
+        self.bitmap[index / FRAMES_PER_WORD] &= !(1 << (index % FRAMES_PER_WORD));
+        self.free_frames += 1;
+        if index / FRAMES_PER_WORD < self.next_word {
+            self.next_word = index / FRAMES_PER_WORD;
+        }
     }
 }
 
-/// Initialize a new OffsetPageTable.
+fn frame_index(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / 4096) as usize
+}
+
+fn frame_from_index(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * 4096))
+}
+
+/// The page table of the kernel, available after `init`.
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+/// The physical frame allocator, available after `init`.
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static KERNEL_LEVEL_4_FRAME: AtomicU64 = AtomicU64::new(0);
+/// A frame below 1 MiB reserved by `init`, zero if there was none.
+static REAL_MODE_FRAME: AtomicU64 = AtomicU64::new(0);
+
+/// Start of the memory that can't be reached in real mode.
+const REAL_MODE_LIMIT: u64 = 0x10_0000;
+
+/// Virtual regions the kernel maps into after boot.
+///
+/// Their level 4 entries are created by `init`, so that every address space created
+/// later shares the underlying tables and sees all future kernel mappings.
+const KERNEL_REGIONS: &[u64] = &[
+    crate::allocator::HEAP_START as u64,
+    stack::STACK_REGION_START,
+    mmio::MMIO_REGION_START,
+];
+
+/// Initialize the kernel page table and the physical frame allocator.
 ///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
-/// `physical_memory_offset`. Also, this function must be only called once
-/// to avoid aliasing `&mut` references (which is undefined behavior).
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+/// `physical_memory_offset` and that the memory map is valid. Also, this function
+/// must be only called once to avoid aliasing `&mut` references (which is undefined behavior).
+pub unsafe fn init(physical_memory_offset: VirtAddr, memory_map: &'static MemoryMap) {
+    use x86_64::registers::control::Cr3;
+
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
+    let (level_4_table_frame, _) = Cr3::read();
+    KERNEL_LEVEL_4_FRAME.store(
+        level_4_table_frame.start_address().as_u64(),
+        Ordering::SeqCst,
+    );
+
     let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+    let mut frame_allocator = BitmapFrameAllocator::init(memory_map, physical_memory_offset);
+    // reserved before the low frames are handed out for anything else
+    if let Some(frame) = frame_allocator.allocate_frame_below(PhysAddr::new(REAL_MODE_LIMIT)) {
+        REAL_MODE_FRAME.store(frame.start_address().as_u64(), Ordering::SeqCst);
+    }
+
+    for index in address_space::USER_LEVEL_4_ENTRIES {
+        assert!(
+            level_4_table[index].is_unused(),
+            "level 4 entry {} is reserved for user space",
+            index
+        );
+    }
+    for &region in KERNEL_REGIONS {
+        let entry = &mut level_4_table[usize::from(VirtAddr::new(region).p4_index())];
+        if entry.is_unused() {
+            let frame = frame_allocator
+                .allocate_frame()
+                .expect("no frame for kernel level 3 table");
+            let table: *mut PageTable = phys_to_virt(frame.start_address()).as_mut_ptr();
+            (*table).zero();
+            entry.set_frame(*frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+
+    *MAPPER.lock() = Some(OffsetPageTable::new(level_4_table, physical_memory_offset));
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Runs `f` with the kernel page table and the frame allocator locked.
+///
+/// Interrupts are disabled while the locks are held. The closure must not allocate
+/// from the kernel heap, because growing the heap needs these locks as well.
+pub fn with_mapper<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        f(
+            mapper.as_mut().expect("memory not initialized"),
+            frame_allocator.as_mut().expect("memory not initialized"),
+        )
+    })
+}
+
+/// Runs `f` with the frame allocator locked and interrupts disabled.
+pub fn with_frame_allocator<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BitmapFrameAllocator) -> R,
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        f(frame_allocator.as_mut().expect("memory not initialized"))
+    })
+}
+
+/// Returns the frame of the level 4 table the bootloader set up for the kernel.
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_FRAME.load(Ordering::SeqCst)))
+}
+
+/// Returns the frame below 1 MiB reserved for the code processors start with in real
+/// mode, see `smp`.
+pub fn real_mode_frame() -> Option<PhysFrame> {
+    match REAL_MODE_FRAME.load(Ordering::SeqCst) {
+        0 => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+    }
+}
+
+/// Returns the virtual address at which the complete physical memory is mapped.
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst))
+}
+
+/// Translates a physical address to its virtual address in the physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
 }
 
 /// Returns a mutable reference to the active level 4 table.
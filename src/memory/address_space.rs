@@ -0,0 +1,596 @@
+use super::{kernel_level_4_frame, phys_to_virt, physical_memory_offset, with_frame_allocator};
+use crate::smp::ipi;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::ptr;
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, MapperFlush, UnmapError},
+        page::PageRange,
+        FrameAllocator, FrameDeallocator, Mapper, MapperAllSizes, OffsetPageTable, Page, PageTable,
+        PageTableEntry, PageTableFlags, PhysFrame, Size4KiB, UnusedPhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Level 4 entries that make up the user part of every address space.
+///
+/// All other entries belong to the kernel and are shared by all address spaces.
+pub const USER_LEVEL_4_ENTRIES: Range<usize> = 32..128;
+/// First address of the user part of an address space.
+pub const USER_START: u64 = 0x0000_1000_0000_0000;
+/// End of the user part of an address space (exclusive).
+pub const USER_END: u64 = 0x0000_4000_0000_0000;
+/// Marks a writable page that shares its frame with other address spaces. It is mapped
+/// read-only, the first write to it gives it a copy of the frame.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Access rights of a mapping. Every mapping is readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection {
+    pub writable: bool,
+    pub executable: bool,
+    /// Whether the mapping is accessible from ring 3.
+    pub user: bool,
+}
+
+impl Protection {
+    pub const KERNEL_READ: Protection = Protection::new(false, false, false);
+    pub const KERNEL_READ_WRITE: Protection = Protection::new(true, false, false);
+    pub const KERNEL_READ_EXECUTE: Protection = Protection::new(false, true, false);
+    pub const USER_READ: Protection = Protection::new(false, false, true);
+    pub const USER_READ_WRITE: Protection = Protection::new(true, false, true);
+    pub const USER_READ_EXECUTE: Protection = Protection::new(false, true, true);
+
+    pub const fn new(writable: bool, executable: bool, user: bool) -> Self {
+        Protection {
+            writable,
+            executable,
+            user,
+        }
+    }
+
+    /// Returns the page table flags of a present page with these access rights.
+    pub fn flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        if self.user {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        flags
+    }
+
+    /// Returns the access rights encoded in the given page table flags, in which
+    /// copy-on-write pages are writable.
+    pub fn from_flags(flags: PageTableFlags) -> Self {
+        Protection {
+            writable: flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE),
+            executable: !flags.contains(PageTableFlags::NO_EXECUTE),
+            user: flags.contains(PageTableFlags::USER_ACCESSIBLE),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    /// The range is empty or not completely inside the user part.
+    OutOfRange,
+    /// A page of the range is not mapped.
+    NotMapped,
+    Map(MapToError),
+    Unmap(UnmapError),
+    FlagUpdate(FlagUpdateError),
+}
+
+impl From<MapToError> for AddressSpaceError {
+    fn from(err: MapToError) -> Self {
+        AddressSpaceError::Map(err)
+    }
+}
+
+impl From<UnmapError> for AddressSpaceError {
+    fn from(err: UnmapError) -> Self {
+        AddressSpaceError::Unmap(err)
+    }
+}
+
+impl From<FlagUpdateError> for AddressSpaceError {
+    fn from(err: FlagUpdateError) -> Self {
+        AddressSpaceError::FlagUpdate(err)
+    }
+}
+
+/// A level 4 page table with its own user part and the kernel part shared with all
+/// other address spaces.
+///
+/// All frames mapped into the user part, and the page tables needed for them, are
+/// owned by the address space and returned to the frame allocator when it is dropped.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user part.
+    pub fn new() -> Result<Self, MapToError> {
+        let level_4_frame =
+            with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame())
+                .ok_or(MapToError::FrameAllocationFailed)?;
+        let level_4_frame = *level_4_frame;
+
+        let table = unsafe { table_mut(level_4_frame) };
+        let kernel_table: &PageTable =
+            unsafe { &*phys_to_virt(kernel_level_4_frame().start_address()).as_ptr() };
+        table.zero();
+        for (index, entry) in table.iter_mut().enumerate() {
+            if !USER_LEVEL_4_ENTRIES.contains(&index) {
+                let kernel_entry = &kernel_table[index];
+                entry.set_addr(kernel_entry.addr(), kernel_entry.flags());
+            }
+        }
+
+        Ok(AddressSpace { level_4_frame })
+    }
+
+    /// Returns the frame of the level 4 table, i.e. the value loaded into CR3.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns whether this address space is loaded in CR3.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Loads this address space into CR3.
+    ///
+    /// This function is unsafe because the caller must make sure that nothing
+    /// references memory in the user part of the previous address space anymore.
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    /// Maps `size` bytes starting at `start` to freshly allocated, zeroed frames.
+    ///
+    /// On failure the pages mapped so far stay mapped.
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        protection: Protection,
+    ) -> Result<(), AddressSpaceError> {
+        for page in user_pages(start, size)? {
+            let frame = with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame())
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe { zero_frame(*frame) };
+            self.map_to(page, frame, protection)?;
+        }
+        Ok(())
+    }
+
+    /// Maps `page` to `frame`, which becomes owned by this address space.
+    pub fn map_to(
+        &mut self,
+        page: Page,
+        frame: UnusedPhysFrame,
+        protection: Protection,
+    ) -> Result<(), AddressSpaceError> {
+        self.map_to_with_flags(page, frame, protection.flags())
+    }
+
+    /// Unmaps `size` bytes starting at `start` and frees the frames behind them.
+    ///
+    /// Pages in the range that are not mapped are skipped.
+    pub fn unmap(&mut self, start: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+        let pages = user_pages(start, size)?;
+        let mut mapper = self.mapper();
+        let mut unmapped = Vec::new();
+        let mut result = Ok(());
+        for page in pages {
+            match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.ignore();
+                    unmapped.push(frame);
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(err) => {
+                    result = Err(err.into());
+                    break;
+                }
+            }
+        }
+        // the frames are only reused once no processor can reach them anymore
+        flush_tlb(pages);
+        with_frame_allocator(|frame_allocator| {
+            for frame in unmapped {
+                frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+            }
+        });
+        result
+    }
+
+    /// Changes the access rights of `size` bytes starting at `start`.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        protection: Protection,
+    ) -> Result<(), AddressSpaceError> {
+        let pages = user_pages(start, size)?;
+        let mut result = Ok(());
+        for page in pages {
+            let mut flags = protection.flags();
+            if protection.writable && self.is_shared(page) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            }
+            match self.mapper().update_flags(page, flags) {
+                Ok(flush) => flush.ignore(),
+                Err(err) => {
+                    result = Err(err.into());
+                    break;
+                }
+            }
+            if protection.user {
+                self.allow_user_access(page);
+            }
+        }
+        flush_tlb(pages);
+        result
+    }
+
+    /// Copies `bytes` to `start` through the physical memory mapping, so the address
+    /// space doesn't need to be loaded. All pages of the range must be mapped.
+    ///
+    /// Pages that share their frame with other address spaces get a copy first.
+    pub fn write(&mut self, start: VirtAddr, bytes: &[u8]) -> Result<(), AddressSpaceError> {
+        let mut written = 0;
+        while written < bytes.len() {
+            let addr = start + written as u64;
+            let page = user_pages(addr, 1)?;
+            if self.unshare(page.start)? {
+                flush_tlb(page);
+            }
+            let phys = self.translate(addr).ok_or(AddressSpaceError::NotMapped)?;
+            let len = (4096 - addr.as_u64() % 4096).min((bytes.len() - written) as u64) as usize;
+            let ptr: *mut u8 = phys_to_virt(phys).as_mut_ptr();
+            unsafe { ptr::copy_nonoverlapping(bytes[written..].as_ptr(), ptr, len) };
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// Creates an address space that shares every page in the user part of this one,
+    /// mapped with the same access rights.
+    ///
+    /// Writable pages become copy-on-write in both address spaces, so neither sees
+    /// what the other writes to them.
+    pub fn duplicate(&mut self) -> Result<AddressSpace, AddressSpaceError> {
+        let mut copy = AddressSpace::new()?;
+        let pages = self.mapped_pages();
+        let mut result = Ok(());
+        for &(page, frame, mut flags) in &pages {
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                let entry = self.entry_mut(page).expect("page is mapped");
+                entry.set_flags(flags);
+            }
+            with_frame_allocator(|frame_allocator| frame_allocator.share_frame(frame));
+            let shared = unsafe { UnusedPhysFrame::new(frame) };
+            if let Err(err) = copy.map_to_with_flags(page, shared, flags) {
+                with_frame_allocator(|frame_allocator| {
+                    frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) })
+                });
+                result = Err(err);
+                break;
+            }
+        }
+        // the pages of this address space are no longer writable
+        if let (Some(first), Some(last)) = (pages.first(), pages.last()) {
+            flush_tlb(Page::range(first.0, last.0 + 1));
+        }
+        result.map(|()| copy)
+    }
+
+    /// Resolves a write to the copy-on-write page containing `addr` by giving it a
+    /// copy of its frame, unless no other address space uses the frame anymore, and
+    /// making it writable. Returns `false` if the page is not copy-on-write.
+    ///
+    /// Only the TLB of the executing processor is flushed, so the address space must
+    /// not be loaded on any other, as the one of a process with its single thread.
+    pub fn handle_write_fault(&mut self, addr: VirtAddr) -> Result<bool, AddressSpaceError> {
+        if user_pages(addr, 1).is_err() {
+            return Ok(false);
+        }
+        let page = Page::containing_address(addr);
+        match self.entry_mut(page) {
+            Some(entry) if entry.flags().contains(COPY_ON_WRITE) => {}
+            _ => return Ok(false),
+        }
+        self.unshare(page)?;
+        let entry = self.entry_mut(page).expect("page is mapped");
+        entry.set_flags((entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE);
+        // no shootdown, this is called by the page fault handler
+        if self.is_active() {
+            tlb::flush(page.start_address());
+        }
+        Ok(true)
+    }
+
+    /// Translates `addr` to the physical address it is mapped to.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        let table = unsafe { table_mut(self.level_4_frame) };
+        let mapper = unsafe { OffsetPageTable::new(table, physical_memory_offset()) };
+        mapper.translate_addr(addr)
+    }
+
+    /// Returns the access rights of the page containing `addr`, which are limited by
+    /// every level of the page table, or `None` if it is not mapped.
+    pub fn protection(&self, addr: VirtAddr) -> Option<Protection> {
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let indices = [
+            page.p4_index(),
+            page.p3_index(),
+            page.p2_index(),
+            page.p1_index(),
+        ];
+        let mut table = unsafe { table_mut(self.level_4_frame) };
+        let mut flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        for (level, &index) in indices.iter().enumerate() {
+            let entry = &table[usize::from(index)];
+            let mut entry_flags = entry.flags();
+            if entry_flags.contains(COPY_ON_WRITE) {
+                entry_flags |= PageTableFlags::WRITABLE;
+            }
+            // no execute on any level applies to the page
+            let no_execute = (flags | entry_flags) & PageTableFlags::NO_EXECUTE;
+            flags = (flags & entry_flags & !PageTableFlags::NO_EXECUTE) | no_execute;
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            if level < indices.len() - 1 {
+                // huge pages are never mapped into the user part
+                table = unsafe { table_mut(entry.frame().ok()?) };
+            }
+        }
+        Some(Protection::from_flags(flags))
+    }
+
+    /// Returns the mapped pages of the user part with their frames and flags.
+    fn mapped_pages(&self) -> Vec<(Page, PhysFrame, PageTableFlags)> {
+        let mut pages = Vec::new();
+        let level_4 = unsafe { table_mut(self.level_4_frame) };
+        for p4 in USER_LEVEL_4_ENTRIES {
+            let level_3 = match next_table(&level_4[p4]) {
+                Some(table) => table,
+                None => continue,
+            };
+            for (p3, entry) in level_3.iter().enumerate() {
+                let level_2 = match next_table(entry) {
+                    Some(table) => table,
+                    None => continue,
+                };
+                for (p2, entry) in level_2.iter().enumerate() {
+                    let level_1 = match next_table(entry) {
+                        Some(table) => table,
+                        None => continue,
+                    };
+                    for (p1, entry) in level_1.iter().enumerate() {
+                        if entry.is_unused() {
+                            continue;
+                        }
+                        let addr = (p4 << 39) | (p3 << 30) | (p2 << 21) | (p1 << 12);
+                        let page = Page::containing_address(VirtAddr::new(addr as u64));
+                        let frame = entry.frame().expect("huge page in user part");
+                        pages.push((page, frame, entry.flags()));
+                    }
+                }
+            }
+        }
+        pages
+    }
+
+    /// Maps `page` to `frame` with the page table flags `flags`.
+    fn map_to_with_flags(
+        &mut self,
+        page: Page,
+        frame: UnusedPhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        user_pages(page.start_address(), page.size())?;
+        let active = self.is_active();
+        let mut mapper = self.mapper();
+        let flush = with_frame_allocator(|frame_allocator| {
+            mapper.map_to(page, frame, flags, frame_allocator)
+        })?;
+        finish(flush, active);
+        if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            self.allow_user_access(page);
+        }
+        Ok(())
+    }
+
+    /// Gives `page` a copy of its frame if the frame is shared with other address
+    /// spaces. Returns whether it did, the caller flushes the TLB entries then.
+    fn unshare(&mut self, page: Page) -> Result<bool, AddressSpaceError> {
+        let entry = self.entry_mut(page).ok_or(AddressSpaceError::NotMapped)?;
+        let frame = entry.frame().expect("huge page in user part");
+        let copy = with_frame_allocator(|frame_allocator| {
+            if frame_allocator.reference_count(frame) > 1 {
+                frame_allocator.allocate_frame().map(Some)
+            } else {
+                Some(None)
+            }
+        })
+        .ok_or(MapToError::FrameAllocationFailed)?;
+        let copy = match copy {
+            Some(copy) => *copy,
+            None => return Ok(false),
+        };
+        unsafe { copy_frame(frame, copy) };
+        entry.set_frame(copy, entry.flags());
+        // frees the frame if the other address spaces dropped it in the meantime
+        with_frame_allocator(|frame_allocator| {
+            frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) })
+        });
+        Ok(true)
+    }
+
+    /// Returns whether the frame `page` is mapped to is shared with other address
+    /// spaces.
+    fn is_shared(&mut self, page: Page) -> bool {
+        let frame = match self.entry_mut(page).map(|entry| entry.frame()) {
+            Some(Ok(frame)) => frame,
+            _ => return false,
+        };
+        with_frame_allocator(|frame_allocator| frame_allocator.reference_count(frame) > 1)
+    }
+
+    /// Returns the last level entry of `page`, `None` if it is unused.
+    fn entry_mut(&mut self, page: Page) -> Option<&mut PageTableEntry> {
+        let mut table = unsafe { table_mut(self.level_4_frame) };
+        for &index in &[page.p4_index(), page.p3_index(), page.p2_index()] {
+            let frame = next_table_frame(&table[usize::from(index)])?;
+            table = unsafe { table_mut(frame) };
+        }
+        let entry = &mut table[usize::from(page.p1_index())];
+        if entry.is_unused() {
+            None
+        } else {
+            Some(entry)
+        }
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let table = unsafe { table_mut(self.level_4_frame) };
+        unsafe { OffsetPageTable::new(table, physical_memory_offset()) }
+    }
+
+    /// Sets the user accessible flag on the higher level entries leading to `page`,
+    /// which the mapper only sets on the last level.
+    fn allow_user_access(&mut self, page: Page) {
+        let mut table = unsafe { table_mut(self.level_4_frame) };
+        for &index in &[page.p4_index(), page.p3_index(), page.p2_index()] {
+            let entry = &mut table[usize::from(index)];
+            entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+            table = unsafe { table_mut(entry.frame().expect("page is mapped")) };
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+
+        let table = unsafe { table_mut(self.level_4_frame) };
+        with_frame_allocator(|frame_allocator| {
+            for index in USER_LEVEL_4_ENTRIES {
+                free_entry(&mut table[index], 3, frame_allocator);
+            }
+            let frame = unsafe { UnusedPhysFrame::new(self.level_4_frame) };
+            frame_allocator.deallocate_frame(frame);
+        });
+    }
+}
+
+/// Loads the kernel's own level 4 table into CR3.
+///
+/// This function is unsafe for the same reasons as `AddressSpace::activate`.
+pub unsafe fn activate_kernel() {
+    Cr3::write(kernel_level_4_frame(), Cr3Flags::empty());
+}
+
+/// Frees the frame `entry` points to, including all tables below it.
+///
+/// `level` is the level of the table the entry points to, zero for a mapped page.
+fn free_entry<A>(entry: &mut PageTableEntry, level: u8, frame_allocator: &mut A)
+where
+    A: FrameDeallocator<Size4KiB>,
+{
+    if entry.is_unused() {
+        return;
+    }
+    // huge pages are never mapped into the user part
+    let frame = entry.frame().expect("huge page in user part");
+    if level > 0 {
+        let table = unsafe { table_mut(frame) };
+        for child in table.iter_mut() {
+            free_entry(child, level - 1, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+    entry.set_unused();
+}
+
+/// Returns the pages of `size` bytes starting at `start` if they lie in the user part.
+fn user_pages(start: VirtAddr, size: u64) -> Result<PageRange, AddressSpaceError> {
+    let end = start.as_u64().checked_add(size);
+    match end {
+        Some(end) if size > 0 && start.as_u64() >= USER_START && end <= USER_END => {
+            let first = Page::containing_address(start);
+            let last = Page::containing_address(VirtAddr::new(end - 1));
+            Ok(Page::range(first, last + 1))
+        }
+        _ => Err(AddressSpaceError::OutOfRange),
+    }
+}
+
+/// Flushes the TLB entry of a changed mapping if its address space is loaded.
+fn finish(flush: MapperFlush<Size4KiB>, active: bool) {
+    if active {
+        flush.flush();
+    } else {
+        flush.ignore();
+    }
+}
+
+/// Flushes the translations of `pages` on all processors, which may have the address
+/// space loaded.
+fn flush_tlb(pages: PageRange) {
+    let count = pages.end - pages.start;
+    if count > 0 {
+        ipi::flush_tlb(pages.start.start_address(), count);
+    }
+}
+
+/// Returns the page table stored in `frame` through the physical memory mapping.
+///
+/// This function is unsafe because the frame must contain a page table and the
+/// caller must not create aliasing references to it.
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Returns the table `entry` points to, `None` if it is unused.
+fn next_table(entry: &PageTableEntry) -> Option<&'static PageTable> {
+    Some(unsafe { table_mut(next_table_frame(entry)?) })
+}
+
+/// Returns the frame of the table `entry` points to, `None` if it is unused.
+fn next_table_frame(entry: &PageTableEntry) -> Option<PhysFrame> {
+    if entry.is_unused() {
+        return None;
+    }
+    // huge pages are never mapped into the user part
+    Some(entry.frame().expect("huge page in user part"))
+}
+
+/// Copies the contents of `from` to `to` through the physical memory mapping.
+unsafe fn copy_frame(from: PhysFrame, to: PhysFrame) {
+    let from: *const u8 = phys_to_virt(from.start_address()).as_ptr();
+    let to: *mut u8 = phys_to_virt(to.start_address()).as_mut_ptr();
+    ptr::copy_nonoverlapping(from, to, 4096);
+}
+
+/// Fills `frame` with zeros through the physical memory mapping.
+unsafe fn zero_frame(frame: PhysFrame) {
+    let ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+    ptr::write_bytes(ptr, 0, frame.size() as usize);
+}
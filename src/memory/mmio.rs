@@ -0,0 +1,63 @@
+//! Uncached mappings of device memory.
+//!
+//! Device registers usually lie outside of usable RAM and are therefore not part of
+//! the physical memory mapping set up by the bootloader. They are mapped into a
+//! dedicated region instead and stay mapped for the lifetime of the kernel.
+
+use super::with_mapper;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, UnusedPhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Start of the virtual region device memory is mapped into.
+pub const MMIO_REGION_START: u64 = 0x_7777_0000_0000;
+/// Size of the virtual region device memory is mapped into.
+pub const MMIO_REGION_SIZE: u64 = 0x_4000_0000; // 1 GiB
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Start of the part of the region that is not used yet.
+static NEXT: Mutex<u64> = Mutex::new(MMIO_REGION_START);
+
+/// Maps `size` bytes of device memory starting at `phys` and returns the virtual
+/// address of `phys`.
+///
+/// This function is unsafe because the range must not contain RAM managed by the
+/// frame allocator, since its frames are mapped without being allocated.
+pub unsafe fn map(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+    let pages = (last.start_address() - first.start_address()) / PAGE_SIZE + 1;
+
+    let start = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut next = NEXT.lock();
+        let start = *next;
+        if start + pages * PAGE_SIZE > MMIO_REGION_START + MMIO_REGION_SIZE {
+            return None;
+        }
+        *next += pages * PAGE_SIZE;
+        Some(start)
+    })
+    .ok_or(MapToError::FrameAllocationFailed)?;
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    with_mapper(|mapper, frame_allocator| {
+        for (i, frame) in PhysFrame::range_inclusive(first, last).enumerate() {
+            let page = Page::containing_address(VirtAddr::new(start + i as u64 * PAGE_SIZE));
+            mapper
+                .map_to(page, UnusedPhysFrame::new(frame), flags, frame_allocator)?
+                .flush();
+        }
+        Ok(())
+    })?;
+
+    Ok(VirtAddr::new(start + phys.as_u64() % PAGE_SIZE))
+}
@@ -0,0 +1,166 @@
+//! Kernel stacks with an unmapped guard page below each of them.
+//!
+//! Stacks are allocated from a dedicated virtual region. Every stack is preceded by a
+//! guard page that is registered as a guard area, so overflowing a stack causes a page
+//! fault instead of silently overwriting whatever lies below it.
+
+use super::vma::{self, Vma, VmaKind};
+use super::{address_space::Protection, with_frame_allocator, with_mapper};
+use crate::smp::ipi;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, UnusedPhysFrame,
+    },
+    VirtAddr,
+};
+
+/// Start of the virtual region kernel stacks are allocated from.
+pub const STACK_REGION_START: u64 = 0x_5555_0000_0000;
+/// Size of the virtual region kernel stacks are allocated from.
+pub const STACK_REGION_SIZE: u64 = 0x_1_0000_0000; // 4 GiB
+/// Size of a kernel stack if the caller does not need a specific one.
+pub const DEFAULT_STACK_PAGES: u64 = 4; // 16 KiB
+
+const PAGE_SIZE: u64 = 4096;
+
+lazy_static! {
+    static ref STACK_REGION: Mutex<StackRegion> = Mutex::new(StackRegion {
+        next: STACK_REGION_START,
+        free: Vec::new(),
+    });
+}
+
+/// Hands out virtual ranges for stacks, including their guard page.
+struct StackRegion {
+    /// Start of the part of the region that was never used.
+    next: u64,
+    /// Previously used ranges as (start, pages) pairs, ready for reuse.
+    free: Vec<(u64, u64)>,
+}
+
+impl StackRegion {
+    fn reserve(&mut self, pages: u64) -> Option<u64> {
+        if let Some(index) = self.free.iter().position(|&(_, p)| p == pages) {
+            return Some(self.free.swap_remove(index).0);
+        }
+        let start = self.next;
+        let end = start.checked_add(pages * PAGE_SIZE)?;
+        if end > STACK_REGION_START + STACK_REGION_SIZE {
+            return None;
+        }
+        self.next = end;
+        Some(start)
+    }
+}
+
+#[derive(Debug)]
+pub enum StackError {
+    /// The virtual region kernel stacks are allocated from is used up.
+    RegionExhausted,
+    Map(MapToError),
+}
+
+impl From<MapToError> for StackError {
+    fn from(err: MapToError) -> Self {
+        StackError::Map(err)
+    }
+}
+
+/// A mapped kernel stack. The stack is unmapped and its frames are freed on drop.
+#[derive(Debug)]
+pub struct KernelStack {
+    guard: VirtAddr,
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    /// Returns the initial stack pointer, i.e. the end of the stack.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// Returns the lowest address of the stack, directly above the guard page.
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    /// Returns the usable size in bytes.
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let first = Page::containing_address(self.bottom);
+        let last = Page::containing_address(self.top - 1u64);
+        let pages = (self.top - self.bottom) / PAGE_SIZE;
+        // allocated up front, the heap can't grow while the mapper is locked
+        let mut unmapped = Vec::with_capacity(pages as usize);
+        with_mapper(|mapper, _| {
+            for page in Page::range_inclusive(first, last) {
+                // pages are missing if `allocate` failed midway
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.ignore();
+                    unmapped.push(frame);
+                }
+            }
+        });
+        // the frames are only reused once no processor can reach them anymore
+        ipi::flush_tlb(self.bottom, pages);
+        with_frame_allocator(|frame_allocator| {
+            for frame in unmapped {
+                frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+            }
+        });
+        vma::unregister(self.guard);
+
+        let pages = (self.top - self.guard) / PAGE_SIZE;
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            STACK_REGION.lock().free.push((self.guard.as_u64(), pages));
+        });
+    }
+}
+
+/// Allocates a kernel stack of `pages` pages with a guard page below it.
+pub fn allocate(pages: u64) -> Result<KernelStack, StackError> {
+    assert!(pages > 0, "kernel stacks need at least one page");
+
+    let guard = x86_64::instructions::interrupts::without_interrupts(|| {
+        STACK_REGION.lock().reserve(pages + 1)
+    })
+    .ok_or(StackError::RegionExhausted)?;
+    let guard = VirtAddr::new(guard);
+    let bottom = guard + PAGE_SIZE;
+    let top = bottom + pages * PAGE_SIZE;
+
+    vma::register(Vma {
+        start: guard,
+        end: bottom,
+        protection: Protection::KERNEL_READ,
+        kind: VmaKind::Guard,
+        name: "kernel stack guard",
+    })
+    .expect("kernel stack region is corrupted");
+
+    // dropping the stack on failure frees the part that was mapped
+    let stack = KernelStack { guard, bottom, top };
+    let first = Page::containing_address(bottom);
+    let last = Page::containing_address(top - 1u64);
+    with_mapper(|mapper, frame_allocator| {
+        for page in Page::range_inclusive(first, last) {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let flags = Protection::KERNEL_READ_WRITE.flags();
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        }
+        Ok::<(), MapToError>(())
+    })?;
+
+    Ok(stack)
+}
@@ -0,0 +1,237 @@
+//! Registry of virtual memory areas (VMAs) in the kernel part of the address space.
+//!
+//! A page fault inside a lazily backed area is resolved by mapping a zeroed frame,
+//! every other page fault is fatal.
+
+use super::address_space::{Protection, USER_END, USER_START};
+use super::{phys_to_virt, with_frame_allocator, with_mapper};
+use crate::smp::ipi;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, Page, UnusedPhysFrame,
+    },
+    VirtAddr,
+};
+
+lazy_static! {
+    /// The areas registered in the kernel part of the address space.
+    pub static ref KERNEL_VMAS: Mutex<VmaSet> = Mutex::new(VmaSet::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// Backed by zeroed frames that are mapped on first access.
+    Lazy,
+    /// Must never be accessed, e.g. the guard page below a stack.
+    Guard,
+}
+
+/// A page aligned range of virtual memory with uniform access rights.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtAddr,
+    /// End of the area (exclusive).
+    pub end: VirtAddr,
+    pub protection: Protection,
+    pub kind: VmaKind,
+    /// Shown in kernel oops messages.
+    pub name: &'static str,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// The area is empty or its bounds are not page aligned.
+    Unaligned,
+    /// The area is not in the part of the address space the set is responsible for.
+    OutOfRange,
+    /// The area overlaps an area that is already registered.
+    Overlap,
+}
+
+/// A set of non-overlapping areas, ordered by start address.
+#[derive(Debug, Default)]
+pub struct VmaSet {
+    areas: BTreeMap<u64, Vma>,
+}
+
+impl VmaSet {
+    pub fn new() -> Self {
+        VmaSet {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        let aligned = |addr: VirtAddr| addr.as_u64() % 4096 == 0;
+        if vma.start >= vma.end || !aligned(vma.start) || !aligned(vma.end) {
+            return Err(VmaError::Unaligned);
+        }
+        let overlaps = self
+            .areas
+            .range(..vma.end.as_u64())
+            .next_back()
+            .map_or(false, |(_, other)| other.end > vma.start);
+        if overlaps {
+            return Err(VmaError::Overlap);
+        }
+        self.areas.insert(vma.start.as_u64(), vma);
+        Ok(())
+    }
+
+    /// Removes the area starting at `start`.
+    pub fn remove(&mut self, start: VirtAddr) -> Option<Vma> {
+        self.areas.remove(&start.as_u64())
+    }
+
+    /// Returns the area containing `addr`.
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.areas
+            .range(..=addr.as_u64())
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+}
+
+/// Why a page fault could not be resolved.
+#[derive(Debug, Clone, Copy)]
+pub enum FaultError {
+    /// The address is not inside any registered area.
+    Unmapped,
+    /// The address is inside a guard area.
+    Guard(Vma),
+    /// The access is not allowed by the area's protection.
+    AccessViolation(Vma),
+    /// No frame was available to back the page.
+    OutOfMemory(Vma),
+    /// No frame was available for the copy of a copy-on-write page.
+    CopyOnWrite,
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FaultError::Unmapped => write!(f, "address is not in any memory area"),
+            FaultError::Guard(vma) => write!(
+                f,
+                "access to guard area `{}` ({:?}..{:?})",
+                vma.name, vma.start, vma.end
+            ),
+            FaultError::AccessViolation(vma) => write!(
+                f,
+                "access violates protection {:?} of area `{}`",
+                vma.protection, vma.name
+            ),
+            FaultError::OutOfMemory(vma) => {
+                write!(f, "out of frames while backing area `{}`", vma.name)
+            }
+            FaultError::CopyOnWrite => write!(f, "out of frames for a copy-on-write page"),
+        }
+    }
+}
+
+/// Registers an area in the kernel part of the address space.
+///
+/// The pages of the area must not be mapped yet.
+pub fn register(vma: Vma) -> Result<(), VmaError> {
+    if vma.start.as_u64() < USER_END && vma.end.as_u64() > USER_START {
+        return Err(VmaError::OutOfRange);
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| KERNEL_VMAS.lock().insert(vma))
+}
+
+/// Unregisters the kernel area starting at `start` and frees the frames that were
+/// mapped for it.
+pub fn unregister(start: VirtAddr) -> Option<Vma> {
+    let vma =
+        x86_64::instructions::interrupts::without_interrupts(|| KERNEL_VMAS.lock().remove(start))?;
+
+    let first = Page::containing_address(vma.start);
+    let last = Page::containing_address(vma.end - 1u64);
+    let pages = (vma.end - vma.start + 4095) / 4096;
+    // allocated up front, the heap can't grow while the mapper is locked
+    let mut unmapped = Vec::with_capacity(pages as usize);
+    with_mapper(|mapper, _| {
+        for page in Page::range_inclusive(first, last) {
+            match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.ignore();
+                    unmapped.push(frame);
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(err) => panic!("failed to unmap {:?}: {:?}", page, err),
+            }
+        }
+    });
+    // the frames are only reused once no processor can reach them anymore
+    ipi::flush_tlb(vma.start, pages);
+    with_frame_allocator(|frame_allocator| {
+        for frame in unmapped {
+            frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+        }
+    });
+    Some(vma)
+}
+
+/// Tries to resolve a page fault at `addr` by backing a lazy area.
+///
+/// Called by the page fault handler, so it must not allocate from the kernel heap.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    let vma = match KERNEL_VMAS.lock().find(addr) {
+        Some(vma) => *vma,
+        None => return Err(FaultError::Unmapped),
+    };
+    if vma.kind == VmaKind::Guard {
+        return Err(FaultError::Guard(vma));
+    }
+
+    let allowed = !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && (vma.protection.writable || !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE))
+        && (vma.protection.executable
+            || !error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH))
+        && (vma.protection.user || !error_code.contains(PageFaultErrorCode::USER_MODE));
+    if !allowed {
+        return Err(FaultError::AccessViolation(vma));
+    }
+
+    let page = Page::containing_address(addr);
+    with_mapper(|mapper, frame_allocator| {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(FaultError::OutOfMemory(vma))?;
+        let phys = *frame;
+        let ptr: *mut u8 = phys_to_virt(phys.start_address()).as_mut_ptr();
+        unsafe { core::ptr::write_bytes(ptr, 0, 4096) };
+        match mapper.map_to(page, frame, vma.protection.flags(), frame_allocator) {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(err) => {
+                frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(phys) });
+                match err {
+                    // another processor faulted on the page first
+                    MapToError::PageAlreadyMapped => Ok(()),
+                    _ => Err(FaultError::OutOfMemory(vma)),
+                }
+            }
+        }
+    })
+}
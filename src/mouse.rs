@@ -1,4 +1,7 @@
+use crate::interrupts::irq;
 use crate::println;
+use crate::sync::{self, lock_order};
+use crate::task;
 use crate::time::duration_now;
 use alloc::{vec, vec::Vec};
 use core::ops::Sub;
@@ -9,7 +12,59 @@ use spin::Mutex;
 use x86_64::instructions::port::Port;
 
 lazy_static! {
-    pub static ref MOUSE: Mutex<MouseInternal> = { Mutex::new(MouseInternal::new()) };
+    pub static ref MOUSE: sync::Mutex<MouseInternal> =
+        { sync::Mutex::with_level(MouseInternal::new(), lock_order::MOUSE) };
+}
+
+/// Assembles the bytes of the mouse into packets and hands them to `task::mouse`,
+/// where they are processed. Doesn't lock `MOUSE`.
+fn interrupt_handler(_irq: u8) {
+    lazy_static! {
+        static ref INPUT: Mutex<[u8; 3]> = Mutex::new([0u8; 3]);
+        static ref MOUSE_CYCLE: AtomicU8 = AtomicU8::new(0);
+    }
+
+    let mut cmd_port = Port::<u8>::new(0x64);
+    let mut data_port = Port::<u8>::new(0x60);
+    loop {
+        let status = unsafe { cmd_port.read() };
+        if status & MOUSE_BIT == 0 {
+            break;
+        }
+
+        // the keyboard is on the same port
+        // don't do anything if it's not a mouse packet
+        if status & GET_COMPAQ_STATUS_BYTE == 0 {
+            continue;
+        }
+
+        let data = unsafe { data_port.read() };
+        let mut i = INPUT.lock();
+        let ms = MOUSE_CYCLE.load(Ordering::Relaxed);
+
+        match ms {
+            0..=1 => {
+                i[ms as usize] = data;
+                MOUSE_CYCLE.fetch_add(1, Ordering::Relaxed);
+            }
+            2 => {
+                i[ms as usize] = data;
+                MOUSE_CYCLE.store(0, Ordering::Relaxed);
+                /* The top two bits of the first byte (values 0x80 and 0x40)
+                supposedly show Y and X overflows, respectively.
+                They are not useful.
+                If they are set, you should probably just discard the entire packet. */
+                if i[0] & 0x80 == 1 || i[0] & 0x40 == 1 {
+                    println!("bad mouse packet {:?}", i);
+                    continue;
+                }
+
+                let packet = Packet::new(i[0] as i8, i[1] as i8, i[2], duration_now());
+                task::mouse::add_packet(packet);
+            }
+            _ => println!("unknown mouse cycle {}", ms),
+        }
+    }
 }
 
 //Get Compaq Status Byte command
@@ -108,8 +163,9 @@ impl Button {
     }
 }
 
+/// A movement and the state of the buttons, reported by the mouse.
 #[derive(Debug, Clone)]
-struct Packet {
+pub struct Packet {
     x_difference: i8,
     y_difference: i8,
     left_button_state: State,
@@ -161,7 +217,7 @@ impl MouseInternal {
             middle_button: Button::new(0, Code::Middle),
         }
     }
-    fn process_packet(&mut self, p: &Packet) {
+    pub(crate) fn process_packet(&mut self, p: &Packet) {
         self.x = (self.x as i32 + p.x_difference as i32) as u32;
         self.y = (self.y as i32 + p.y_difference as i32) as u32;
 
@@ -172,56 +228,6 @@ impl MouseInternal {
             .trigger_events(p.middle_button_state, p.time);
     }
 
-    pub fn handler(&mut self) {
-        lazy_static! {
-            static ref INPUT: Mutex<[u8; 3]> = Mutex::new([0u8; 3]);
-            static ref MOUSE_CYCLE: AtomicU8 = AtomicU8::new(0);
-        }
-
-        loop {
-            let status = unsafe { self.cmd_port.read() };
-            if !self.has_data(status) {
-                break;
-            }
-
-            // the keyboard is on the same port
-            // don't do anything if it's not a mouse packet
-            if status & GET_COMPAQ_STATUS_BYTE == 0 {
-                continue;
-            }
-
-            let data = unsafe { self.data_port.read() };
-            let mut i = INPUT.lock();
-            let ms = MOUSE_CYCLE.load(Ordering::Relaxed);
-
-            match ms {
-                0..=1 => {
-                    i[ms as usize] = data;
-                    MOUSE_CYCLE.fetch_add(1, Ordering::Relaxed);
-                }
-                2 => {
-                    i[ms as usize] = data;
-                    MOUSE_CYCLE.store(0, Ordering::Relaxed);
-                    /* The top two bits of the first byte (values 0x80 and 0x40)
-                    supposedly show Y and X overflows, respectively.
-                    They are not useful.
-                    If they are set, you should probably just discard the entire packet. */
-                    if i[0] & 0x80 == 1 || i[0] & 0x40 == 1 {
-                        println!("bad mouse packet {:?}", i);
-                        continue;
-                    }
-
-                    let packet = Packet::new(i[0] as i8, i[1] as i8, i[2], duration_now());
-
-                    self.process_packet(&packet);
-                    let (x, y) = self.coordinates();
-                    println!("x {} y {}", x, y);
-                }
-                _ => println!("unknown mouse cycle {}", ms),
-            }
-        }
-    }
-
     pub fn init(&mut self) {
         unsafe {
             self.wait(true);
@@ -248,6 +254,7 @@ impl MouseInternal {
 
         self.write(0xF6);
         self.write(0xF4);
+        irq::register_irq(irq::MOUSE, interrupt_handler).expect("mouse IRQ is taken");
     }
 
     fn write(&mut self, b: u8) {
@@ -1,21 +1,137 @@
+//! Programmable interval timer (PIT) driver.
+//!
+//! Channel 0 raises the timer IRQ, either periodically or once for tickless
+//! operation. Channel 2 is only used for busy waiting during timer calibration.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
 
 pub static mut CHAN0: Port<u8> = Port::new(0x40);
 #[allow(dead_code)]
 pub static mut CHAN1: Port<u8> = Port::new(0x41);
-#[allow(dead_code)]
 pub static mut CHAN2: Port<u8> = Port::new(0x42);
 pub static mut COMMAND: Port<u8> = Port::new(0x43);
 
+/// Controls the gate of channel 2 (bit 0) and shows its output (bit 5).
+static mut CHAN2_GATE: Port<u8> = Port::new(0x61);
+
+/// Frequency of the PIT input clock in Hz, rounded.
+pub const FREQUENCY: u64 = 1_193_182;
+/// Frequency of the PIT input clock is exactly 3.579545 MHz / 3.
+const CLOCK_NUMERATOR: u64 = 3_579_545;
+const CLOCK_DENOMINATOR: u64 = 3;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Frequency of the timer IRQ set by `init`. Gives a tick of about 2.25 ms.
+pub const DEFAULT_FREQUENCY: u32 = 444;
+/// Largest value of the 16 bit counter, which is programmed as 0.
+const MAX_COUNT: u64 = 0x1_0000;
+
 static SELECT_CHAN0: u8 = 0;
+static SELECT_CHAN2: u8 = 0x80;
 static LOHI: u8 = 0x30;
+/// Mode 0, interrupt on terminal count.
+static ONE_SHOT: u8 = 0;
+/// Mode 2, rate generator.
+static RATE_GENERATOR: u8 = 0b010 << 1;
+
+/// Denominator of `TickLength::remainder`.
+pub const TICK_LENGTH_DENOMINATOR: u64 = CLOCK_NUMERATOR;
 
-static CHAN0_DIVISOR: u16 = 2685;
+static TICK_NANOS: AtomicU64 = AtomicU64::new(0);
+static TICK_REMAINDER: AtomicU64 = AtomicU64::new(0);
+
+/// The exact time between two timer IRQs, which is
+/// `nanos + remainder / TICK_LENGTH_DENOMINATOR` nanoseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TickLength {
+    pub nanos: u64,
+    pub remainder: u64,
+}
+
+impl TickLength {
+    fn from_count(count: u64) -> Self {
+        let total = count * CLOCK_DENOMINATOR * NANOS_PER_SEC;
+        TickLength {
+            nanos: total / TICK_LENGTH_DENOMINATOR,
+            remainder: total % TICK_LENGTH_DENOMINATOR,
+        }
+    }
+
+    /// Returns the tick length rounded down to whole nanoseconds.
+    pub fn duration(self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+}
 
 pub fn init() {
+    set_frequency(DEFAULT_FREQUENCY);
+}
+
+/// Raises the timer IRQ periodically at about `hz` times per second.
+///
+/// Returns the exact tick length, which is also used by the time keeping.
+pub fn set_frequency(hz: u32) -> TickLength {
+    assert!(hz > 0, "PIT frequency must not be zero");
+    let hz = u64::from(hz) * CLOCK_DENOMINATOR;
+    let count = ((CLOCK_NUMERATOR + hz / 2) / hz).max(1).min(MAX_COUNT);
+    program_channel0(RATE_GENERATOR, count)
+}
+
+/// Raises the timer IRQ once after `duration` and stops raising it periodically.
+///
+/// The duration is rounded up to the next counter tick and limited to about 55 ms.
+/// Returns the duration that was actually programmed.
+pub fn start_one_shot(duration: Duration) -> TickLength {
+    let nanos = duration.as_nanos() as u64;
+    let total = CLOCK_DENOMINATOR * NANOS_PER_SEC;
+    let count = (u128::from(nanos) * u128::from(CLOCK_NUMERATOR) + u128::from(total) - 1)
+        / u128::from(total);
+    program_channel0(ONE_SHOT, (count as u64).max(1).min(MAX_COUNT))
+}
+
+/// Returns the time between two timer IRQs, or until the one-shot IRQ.
+pub fn tick_length() -> TickLength {
+    without_interrupts(|| TickLength {
+        nanos: TICK_NANOS.load(Ordering::Relaxed),
+        remainder: TICK_REMAINDER.load(Ordering::Relaxed),
+    })
+}
+
+fn program_channel0(mode: u8, count: u64) -> TickLength {
+    let length = TickLength::from_count(count);
+    // a count of 0 stands for MAX_COUNT
+    let count = (count % MAX_COUNT) as u16;
+    without_interrupts(|| {
+        TICK_NANOS.store(length.nanos, Ordering::Relaxed);
+        TICK_REMAINDER.store(length.remainder, Ordering::Relaxed);
+        unsafe {
+            COMMAND.write(SELECT_CHAN0 | LOHI | mode);
+            CHAN0.write((count & 0xFF) as u8);
+            CHAN0.write((count >> 8) as u8);
+        }
+    });
+    length
+}
+
+/// Busy waits for `micros` microseconds using channel 2, which raises no interrupt.
+///
+/// Used to calibrate other timers, so the wait must not exceed 50 ms.
+pub fn wait_micros(micros: u64) {
+    let count = FREQUENCY * micros / 1_000_000;
+    assert!(count <= 0xFFFF, "PIT channel 2 can't wait {} us", micros);
+
     unsafe {
-        COMMAND.write(SELECT_CHAN0 | LOHI | 5);
-        CHAN0.write((CHAN0_DIVISOR & 0xFF) as u8);
-        CHAN0.write((CHAN0_DIVISOR >> 8) as u8);
+        // gate low and speaker off while programming
+        let gate = CHAN2_GATE.read() & !0b11;
+        CHAN2_GATE.write(gate);
+        COMMAND.write(SELECT_CHAN2 | LOHI | ONE_SHOT);
+        CHAN2.write((count & 0xFF) as u8);
+        CHAN2.write((count >> 8) as u8);
+        CHAN2_GATE.write(gate | 1);
+        while CHAN2_GATE.read() & 0x20 == 0 {}
+        CHAN2_GATE.write(gate);
     }
 }
@@ -0,0 +1,185 @@
+//! Powering off and rebooting the machine.
+//!
+//! Both functions only use try-locks and port I/O, so they can be called while other
+//! parts of the kernel are locked, e.g. from a panic handler.
+
+use crate::acpi::{Sdt, ACPI_TABLE, SYSTEM_IO};
+use crate::pit;
+use x86_64::instructions::port::Port;
+
+/// Set in PM1 control registers while ACPI mode is enabled.
+const SCI_EN: u16 = 1;
+/// Written to PM1 control registers together with a sleep type to enter it.
+const SLP_EN: u16 = 1 << 13;
+/// The sleep type field of PM1 control registers.
+const SLP_TYP: u16 = 0b111 << 10;
+
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+
+/// Powers off the machine through the ACPI S5 sleep state.
+///
+/// Interrupts are disabled first, so no handler runs between switching to ACPI mode
+/// and entering the sleep state. If ACPI is not available or the machine is still
+/// running afterwards, the CPU is halted.
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    let fadt = ACPI_TABLE.try_lock().and_then(|tables| tables.fadt);
+    if let Some(fadt) = fadt {
+        let s5 = unsafe { Sdt::new(fadt.dsdt) }.and_then(|dsdt| s5_sleep_types(dsdt.data()));
+        if let Some((sleep_type_a, sleep_type_b)) = s5 {
+            enable_acpi(fadt.smi_command, fadt.acpi_enable, fadt.pm1a_control_block);
+            unsafe {
+                enter_sleep_state(fadt.pm1a_control_block, sleep_type_a);
+                if fadt.pm1b_control_block != 0 {
+                    enter_sleep_state(fadt.pm1b_control_block, sleep_type_b);
+                }
+            }
+            pit::wait_micros(50_000);
+        }
+    }
+
+    crate::hlt_loop();
+}
+
+/// Reboots the machine.
+///
+/// Tries the ACPI reset register, a reset pulse from the 8042 keyboard controller and
+/// finally a triple fault.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    let fadt = ACPI_TABLE.try_lock().and_then(|tables| tables.fadt);
+    if let Some(reset_register) = fadt.and_then(|fadt| fadt.reset_register) {
+        // only I/O space can be accessed without taking the memory locks
+        if reset_register.address_space == SYSTEM_IO {
+            let value = fadt.map_or(0, |fadt| fadt.reset_value);
+            unsafe { Port::<u8>::new(reset_register.address as u16).write(value) };
+            pit::wait_micros(50_000);
+        }
+    }
+
+    unsafe {
+        let mut status = Port::<u8>::new(0x64);
+        // wait until the input buffer is empty
+        for _ in 0..100_000 {
+            if status.read() & 0b10 == 0 {
+                break;
+            }
+        }
+        status.write(0xfe);
+    }
+    pit::wait_micros(50_000);
+
+    unsafe {
+        use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+
+        lidt(&DescriptorTablePointer { limit: 0, base: 0 });
+    }
+    x86_64::instructions::interrupts::int3();
+    crate::hlt_loop();
+}
+
+/// Switches the chipset to ACPI mode if the firmware started it in legacy mode.
+fn enable_acpi(smi_command: u32, acpi_enable: u8, pm1a_control_block: u32) {
+    let mut control = Port::<u16>::new(pm1a_control_block as u16);
+    if smi_command == 0 || acpi_enable == 0 || unsafe { control.read() } & SCI_EN != 0 {
+        return;
+    }
+    unsafe { Port::<u8>::new(smi_command as u16).write(acpi_enable) };
+    for _ in 0..300 {
+        if unsafe { control.read() } & SCI_EN != 0 {
+            break;
+        }
+        pit::wait_micros(10_000);
+    }
+}
+
+/// Writes `sleep_type` and `SLP_EN` to a PM1 control register, keeping the bits
+/// that don't belong to the sleep type.
+///
+/// Unsafe because the machine may power off.
+unsafe fn enter_sleep_state(control_block: u32, sleep_type: u8) {
+    let mut control = Port::<u16>::new(control_block as u16);
+    let value = control.read() & !SLP_TYP;
+    control.write(value | (u16::from(sleep_type) << 10 & SLP_TYP) | SLP_EN);
+}
+
+/// Finds the `\_S5_` package in the AML code of the DSDT and returns the sleep types
+/// to write to the PM1a and PM1b control registers.
+fn s5_sleep_types(aml: &[u8]) -> Option<(u8, u8)> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|&(_, name)| name == b"_S5_")
+        .find_map(|(start, _)| parse_s5(aml, start))
+}
+
+/// Parses the package of the `_S5_` name at `start`, if the name is defined there.
+fn parse_s5(aml: &[u8], start: usize) -> Option<(u8, u8)> {
+    // the name must be defined by `Name(_S5_, ...)` or `Name(\_S5_, ...)`
+    let defined = match start {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => {
+            let prefix = &aml[start - 2..start];
+            prefix[1] == NAME_OP || prefix == [NAME_OP, b'\\']
+        }
+    };
+    if !defined {
+        return None;
+    }
+
+    let mut bytes = aml[start + 4..].iter().copied();
+    if bytes.next()? != PACKAGE_OP {
+        return None;
+    }
+    // the two high bits of the first PkgLength byte count the bytes following it
+    let pkg_length = bytes.next()?;
+    for _ in 0..pkg_length >> 6 {
+        bytes.next()?;
+    }
+    let _num_elements = bytes.next()?;
+
+    let mut integer = || match bytes.next()? {
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        BYTE_PREFIX => bytes.next(),
+        _ => None,
+    };
+    let sleep_type_a = integer()?;
+    let sleep_type_b = integer()?;
+    Some((sleep_type_a, sleep_type_b))
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_s5_sleep_types() {
+    serial_print!("test_s5_sleep_types... ");
+    // Name (\_S5, Package (0x04) { Zero, Zero, Zero, Zero })
+    let aml = [
+        0x10, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(s5_sleep_types(&aml), Some((0, 0)));
+    // Name (_S5, Package (0x02) { 0x07, One })
+    let aml = [
+        0x08, b'_', b'S', b'5', b'_', 0x12, 0x05, 0x02, 0x0a, 0x07, 0x01,
+    ];
+    assert_eq!(s5_sleep_types(&aml), Some((7, 1)));
+    // a reference to _S5_ is not its definition
+    let aml = [
+        0x70, b'_', b'S', b'5', b'_', 0x12, 0x05, 0x02, 0x0a, 0x07, 0x01,
+    ];
+    assert_eq!(s5_sleep_types(&aml), None);
+    // the definition after a reference is found
+    let aml = [
+        0x70, b'_', b'S', b'5', b'_', 0x08, b'_', b'S', b'5', b'_', 0x12, 0x04, 0x02, 0x01, 0x00,
+    ];
+    assert_eq!(s5_sleep_types(&aml), Some((1, 0)));
+    serial_println!("[ok]");
+}
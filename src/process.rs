@@ -0,0 +1,343 @@
+//! User processes.
+//!
+//! A process is a user thread with its own address space, the files it has open and
+//! an exit status. Processes are started by the kernel with `spawn` or by another
+//! process with the `FORK` system call, which shares the address space of the running
+//! process copy-on-write and copies its file descriptors, and run registered programs
+//! with `EXEC`.
+//!
+//! A process that exited stays a zombie with its exit status until its parent reaps
+//! it with `WAIT`, or the kernel with `wait` if it spawned it. The children of an
+//! exiting process become orphans, the zombies among them are reaped at once and the
+//! others when they exit. The thread of a process is detached, so it is freed with
+//! its address space by a later spawn.
+
+use crate::elf::{self, ElfError};
+use crate::memory::address_space::AddressSpaceError;
+use crate::memory::stack::StackError;
+use crate::sync::{self, lock_order, Condvar};
+use crate::syscall::{self, SyscallFrame};
+use crate::thread::{self, ThreadId};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use file::FileTable;
+use lazy_static::lazy_static;
+use x86_64::structures::paging::mapper::MapToError;
+
+pub mod file;
+
+/// The exit status of a process that was ended by an exception.
+pub const EXCEPTION_STATUS: i32 = -1;
+
+lazy_static! {
+    static ref PROCESSES: sync::Mutex<ProcessTable> =
+        sync::Mutex::with_level(ProcessTable::new(), lock_order::PROCESSES);
+    static ref PROGRAMS: sync::Mutex<BTreeMap<String, &'static [u8]>> =
+        sync::Mutex::new(BTreeMap::new());
+}
+
+/// Notified whenever a process exits.
+static EXITED: Condvar = Condvar::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug)]
+pub enum ProcessError {
+    /// The running thread doesn't belong to a process.
+    NoProcess,
+    /// There is no child process to wait for.
+    NoChild,
+    /// No program is registered under the name.
+    NotFound,
+    Elf(ElfError),
+    Map(AddressSpaceError),
+    /// The kernel stack of the thread could not be allocated.
+    Stack(StackError),
+}
+
+impl From<ElfError> for ProcessError {
+    fn from(err: ElfError) -> Self {
+        match err {
+            ElfError::Map(err) => ProcessError::Map(err),
+            ElfError::Stack(err) => ProcessError::Stack(err),
+            err => ProcessError::Elf(err),
+        }
+    }
+}
+
+impl From<AddressSpaceError> for ProcessError {
+    fn from(err: AddressSpaceError) -> Self {
+        ProcessError::Map(err)
+    }
+}
+
+impl From<MapToError> for ProcessError {
+    fn from(err: MapToError) -> Self {
+        ProcessError::Map(AddressSpaceError::Map(err))
+    }
+}
+
+impl From<StackError> for ProcessError {
+    fn from(err: StackError) -> Self {
+        ProcessError::Stack(err)
+    }
+}
+
+/// Who reaps a process once it exited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Parent {
+    /// Spawned by the kernel, which waits for it with `wait`.
+    Kernel,
+    Process(Pid),
+    /// The parent exited, nobody waits for the process.
+    Orphan,
+}
+
+struct Process {
+    parent: Parent,
+    thread: ThreadId,
+    files: FileTable,
+    /// Set when the process exited, it is a zombie until it is reaped.
+    status: Option<i32>,
+    children: BTreeSet<Pid>,
+}
+
+struct ProcessTable {
+    processes: BTreeMap<Pid, Process>,
+    /// The processes the running threads belong to.
+    threads: BTreeMap<ThreadId, Pid>,
+    /// The processes spawned by the kernel.
+    kernel_children: BTreeSet<Pid>,
+}
+
+impl ProcessTable {
+    fn new() -> Self {
+        ProcessTable {
+            processes: BTreeMap::new(),
+            threads: BTreeMap::new(),
+            kernel_children: BTreeSet::new(),
+        }
+    }
+
+    fn insert(&mut self, parent: Parent, thread: ThreadId, files: FileTable) -> Pid {
+        let pid = Pid::new();
+        let process = Process {
+            parent,
+            thread,
+            files,
+            status: None,
+            children: BTreeSet::new(),
+        };
+        self.processes.insert(pid, process);
+        self.threads.insert(thread, pid);
+        if let Some(children) = self.children_mut(parent) {
+            children.insert(pid);
+        }
+        pid
+    }
+
+    fn children_mut(&mut self, parent: Parent) -> Option<&mut BTreeSet<Pid>> {
+        match parent {
+            Parent::Kernel => Some(&mut self.kernel_children),
+            Parent::Process(pid) => Some(&mut self.processes.get_mut(&pid)?.children),
+            Parent::Orphan => None,
+        }
+    }
+
+    /// Removes the zombie `pid` and returns its exit status.
+    fn reap(&mut self, pid: Pid) -> Option<i32> {
+        let process = self.processes.remove(&pid)?;
+        if let Some(children) = self.children_mut(process.parent) {
+            children.remove(&pid);
+        }
+        process.status
+    }
+
+    /// Returns the process of the running thread.
+    fn current(&self) -> Option<Pid> {
+        self.threads.get(&thread::current()).cloned()
+    }
+}
+
+/// Makes `image` available to `EXEC` under `name`, replacing a program registered
+/// under the same name.
+pub fn register_program(name: &str, image: &'static [u8]) {
+    PROGRAMS.lock().insert(name.to_string(), image);
+}
+
+/// Starts the executable `image` in a new process with the arguments `args` and the
+/// environment `env`. The kernel reaps it with `wait`.
+pub fn spawn(image: &[u8], args: &[&str], env: &[&str]) -> Result<Pid, ProcessError> {
+    let program = elf::load(image, args, env)?;
+    // locked before the thread starts, so it finds its process
+    let mut table = PROCESSES.lock();
+    let thread = thread::spawn_user(program.address_space, program.entry, program.stack_pointer)?;
+    let pid = table.insert(Parent::Kernel, thread, FileTable::new());
+    drop(table);
+    thread::detach(thread);
+    Ok(pid)
+}
+
+/// Returns the process of the running thread, `None` for kernel threads and user
+/// threads started with `thread::spawn_user`.
+pub fn current() -> Option<Pid> {
+    PROCESSES.lock().current()
+}
+
+/// Calls `f` with the file descriptors of the running process.
+pub fn with_files<F, T>(f: F) -> Result<T, ProcessError>
+where
+    F: FnOnce(&mut FileTable) -> T,
+{
+    let mut table = PROCESSES.lock();
+    let pid = table.current().ok_or(ProcessError::NoProcess)?;
+    let process = table
+        .processes
+        .get_mut(&pid)
+        .expect("process does not exist");
+    Ok(f(&mut process.files))
+}
+
+/// Starts a child of the running process with a copy of its address space and file
+/// descriptors, which continues from the system call of `frame`.
+pub(crate) fn fork(frame: &SyscallFrame) -> Result<Pid, ProcessError> {
+    let address_space = thread::address_space().ok_or(ProcessError::NoProcess)?;
+    let copy = address_space.lock().duplicate()?;
+    drop(address_space);
+
+    let mut table = PROCESSES.lock();
+    let parent = table.current().ok_or(ProcessError::NoProcess)?;
+    let files = table.processes[&parent].files.clone();
+    let child_frame = frame.fork();
+    let thread = thread::spawn_in(
+        copy,
+        Box::new(move || unsafe { syscall::resume_user(&child_frame) }),
+    )?;
+    let pid = table.insert(Parent::Process(parent), thread, files);
+    drop(table);
+    thread::detach(thread);
+    Ok(pid)
+}
+
+/// Replaces the address space of the running thread by the registered program
+/// `name` and lets the system call of `frame` return to its entry point.
+pub(crate) fn exec(
+    frame: &mut SyscallFrame,
+    name: &str,
+    args: &[&str],
+    env: &[&str],
+) -> Result<(), ProcessError> {
+    let image = PROGRAMS
+        .lock()
+        .get(name)
+        .cloned()
+        .ok_or(ProcessError::NotFound)?;
+    let program = elf::load(image, args, env)?;
+    let old = thread::replace_address_space(program.address_space);
+    // nothing references the old program anymore
+    drop(old);
+    frame.reset(program.entry, program.stack_pointer);
+    Ok(())
+}
+
+/// Ends the running process with `status`, or only the running thread if it doesn't
+/// belong to a process.
+pub fn exit(status: i32) -> ! {
+    let mut table = PROCESSES.lock();
+    if let Some(pid) = table.current() {
+        let (parent, thread_id, files, children) = {
+            let process = table
+                .processes
+                .get_mut(&pid)
+                .expect("process does not exist");
+            process.status = Some(status);
+            let children = mem::replace(&mut process.children, BTreeSet::new());
+            (
+                process.parent,
+                process.thread,
+                process.files.close_all(),
+                children,
+            )
+        };
+        table.threads.remove(&thread_id);
+        for child in children {
+            let process = table
+                .processes
+                .get_mut(&child)
+                .expect("child does not exist");
+            process.parent = Parent::Orphan;
+            if process.status.is_some() {
+                table.processes.remove(&child);
+            }
+        }
+        if parent == Parent::Orphan {
+            table.reap(pid);
+        }
+        drop(table);
+        drop(files);
+        EXITED.notify_all();
+    } else {
+        drop(table);
+    }
+    thread::exit();
+}
+
+/// Waits until the process `pid`, which the kernel spawned, exited and reaps it.
+/// Returns its exit status.
+pub fn wait(pid: Pid) -> Result<i32, ProcessError> {
+    wait_for(Parent::Kernel, Some(pid)).map(|(_, status)| status)
+}
+
+/// Waits until the child `pid` of the running process exited, or any child if `pid`
+/// is `None`, and reaps it. Returns the id and exit status of the child.
+///
+/// Kernel threads wait for the processes the kernel spawned.
+pub fn wait_child(pid: Option<Pid>) -> Result<(Pid, i32), ProcessError> {
+    let parent = match current() {
+        Some(pid) => Parent::Process(pid),
+        None => Parent::Kernel,
+    };
+    wait_for(parent, pid)
+}
+
+fn wait_for(parent: Parent, pid: Option<Pid>) -> Result<(Pid, i32), ProcessError> {
+    let mut table = PROCESSES.lock();
+    loop {
+        let children = table.children_mut(parent).ok_or(ProcessError::NoChild)?;
+        if pid.map_or(children.is_empty(), |pid| !children.contains(&pid)) {
+            return Err(ProcessError::NoChild);
+        }
+        let candidates: Vec<Pid> = match pid {
+            Some(pid) => vec![pid],
+            None => children.iter().cloned().collect(),
+        };
+        let zombie = candidates
+            .into_iter()
+            .find(|child| table.processes[child].status.is_some());
+        if let Some(child) = zombie {
+            let status = table.reap(child).expect("zombie has no status");
+            return Ok((child, status));
+        }
+        table = EXITED.wait(table);
+    }
+}
@@ -0,0 +1,79 @@
+//! The files a process has open, indexed by their file descriptors.
+
+use crate::{print, serial_print};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Most files a process can have open at once.
+pub const MAX_FILES: usize = 64;
+
+/// An open file, shared by the file descriptors it was duplicated to.
+#[derive(Debug, Clone)]
+pub enum File {
+    /// The VGA buffer and the serial port, written to together.
+    Console,
+}
+
+impl File {
+    /// Writes `bytes` and returns how many were written.
+    pub fn write(&self, bytes: &[u8]) -> usize {
+        match self {
+            File::Console => {
+                let text = String::from_utf8_lossy(bytes);
+                print!("{}", text);
+                serial_print!("{}", text);
+                bytes.len()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileTable {
+    files: Vec<Option<File>>,
+}
+
+impl FileTable {
+    /// Creates the table of a new process, with `syscall::STDOUT` and
+    /// `syscall::STDERR` open on the console and nothing to read from.
+    pub fn new() -> Self {
+        FileTable {
+            files: vec![None, Some(File::Console), Some(File::Console)],
+        }
+    }
+
+    pub fn get(&self, fd: u64) -> Option<&File> {
+        self.files.get(fd as usize)?.as_ref()
+    }
+
+    /// Opens `file` under the lowest free file descriptor, which is returned, or
+    /// `None` if `MAX_FILES` are open.
+    pub fn open(&mut self, file: File) -> Option<u64> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return None,
+        };
+        self.files[fd] = Some(file);
+        Some(fd as u64)
+    }
+
+    /// Closes the file descriptor and returns its file, `None` if it was not open.
+    pub fn close(&mut self, fd: u64) -> Option<File> {
+        self.files.get_mut(fd as usize)?.take()
+    }
+
+    /// Closes all file descriptors.
+    pub fn close_all(&mut self) -> Vec<File> {
+        self.files.drain(..).filter_map(|file| file).collect()
+    }
+}
+
+impl Default for FileTable {
+    fn default() -> Self {
+        FileTable::new()
+    }
+}
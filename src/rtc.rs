@@ -1,15 +1,242 @@
-use crate::time;
+use crate::acpi;
+use crate::interrupts::irq;
+use crate::time::{self, DateTime};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
 
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS_ALARM: u8 = 0x05;
+const REGISTER_A: u8 = 0x0A;
+const REGISTER_B: u8 = 0x0B;
+const REGISTER_C: u8 = 0x0C;
+
+// register B
+/// Stops the clock from updating while it is set.
+const SET: u8 = 0x80;
+const PERIODIC_INTERRUPT_ENABLE: u8 = 0x40;
+const ALARM_INTERRUPT_ENABLE: u8 = 0x20;
+const BINARY_MODE: u8 = 0x04;
+const HOUR_24_MODE: u8 = 0x02;
+
+// register C
+const PERIODIC_INTERRUPT_FLAG: u8 = 0x40;
+const ALARM_INTERRUPT_FLAG: u8 = 0x20;
+
+/// The RTC shared by the kernel and its interrupt handler. Must only be locked with
+/// interrupts disabled.
+static RTC: Mutex<Rtc> = Mutex::new(Rtc::new());
+static PERIODIC_HANDLER: Mutex<Option<fn()>> = Mutex::new(None);
+static ALARM: Mutex<Option<Alarm>> = Mutex::new(None);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+/// The CMOS register holding the century according to the FADT, zero if there is none.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Debug, Clone, Copy)]
+struct Alarm {
+    /// Unix time at which the callback is called.
+    time: u64,
+    callback: fn(),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// Periodic interrupts only support powers of two from 2 to 8192 Hz.
+    InvalidFrequency,
+    /// The alarm time has already passed.
+    InPast,
+}
+
 pub fn init() {
-    let mut rtc = Rtc::new();
-    time::START.lock().0 = rtc.time();
+    if let Some(ref fadt) = acpi::ACPI_TABLE.lock().fadt {
+        CENTURY_REGISTER.store(fadt.century, Ordering::SeqCst);
+    }
+    let now = without_interrupts(|| {
+        let mut rtc = RTC.lock();
+        // a pending interrupt flag blocks all further RTC interrupts
+        unsafe { rtc.read(REGISTER_C) };
+        rtc.time()
+    });
+    time::START.lock().0 = now;
+    irq::register_irq(irq::RTC, interrupt_handler).expect("RTC IRQ is taken");
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+pub fn time() -> u64 {
+    without_interrupts(|| RTC.lock().time())
+}
+
+/// Returns the current date and time in UTC.
+pub fn date_time() -> DateTime {
+    without_interrupts(|| RTC.lock().date_time())
+}
+
+/// Sets the hardware clock to `date_time` and moves `time::START` to match it.
+pub fn set_time(date_time: DateTime) {
+    without_interrupts(|| RTC.lock().set_time(date_time));
+    time::set_realtime(date_time.to_unix());
+}
+
+/// Calls `handler` `hz` times per second from the RTC interrupt.
+pub fn set_periodic(hz: u32, handler: fn()) -> Result<(), RtcError> {
+    if !hz.is_power_of_two() || !(2..=8192).contains(&hz) {
+        return Err(RtcError::InvalidFrequency);
+    }
+    // the frequency is 32768 >> (rate - 1)
+    let rate = (16 - hz.trailing_zeros()) as u8;
+    without_interrupts(|| {
+        *PERIODIC_HANDLER.lock() = Some(handler);
+        let mut rtc = RTC.lock();
+        unsafe {
+            let register_a = rtc.read(REGISTER_A);
+            rtc.write(REGISTER_A, (register_a & 0xF0) | rate);
+            let register_b = rtc.read(REGISTER_B);
+            rtc.write(REGISTER_B, register_b | PERIODIC_INTERRUPT_ENABLE);
+        }
+    });
+    Ok(())
+}
+
+/// Stops the periodic interrupt.
+pub fn clear_periodic() {
+    without_interrupts(|| {
+        let mut rtc = RTC.lock();
+        unsafe {
+            let register_b = rtc.read(REGISTER_B);
+            rtc.write(REGISTER_B, register_b & !PERIODIC_INTERRUPT_ENABLE);
+        }
+        *PERIODIC_HANDLER.lock() = None;
+    });
+}
+
+/// Returns the number of periodic interrupts since boot.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Calls `callback` from the RTC interrupt at `time`, in seconds since the Unix
+/// epoch. Replaces an alarm that was set before.
+///
+/// The hardware alarm only compares the time of day, so alarms more than a day
+/// ahead fire once a day until their time is reached.
+pub fn set_alarm(time: u64, callback: fn()) -> Result<(), RtcError> {
+    without_interrupts(|| {
+        let mut rtc = RTC.lock();
+        if time <= rtc.time() {
+            return Err(RtcError::InPast);
+        }
+        *ALARM.lock() = Some(Alarm { time, callback });
+
+        let seconds_of_day = time % 86_400;
+        let hour = (seconds_of_day / 3600) as u8;
+        let minute = (seconds_of_day / 60 % 60) as u8;
+        let second = (seconds_of_day % 60) as u8;
+        unsafe {
+            let register_b = rtc.read(REGISTER_B);
+            rtc.write(SECONDS_ALARM, encode(second, register_b));
+            rtc.write(MINUTES_ALARM, encode(minute, register_b));
+            rtc.write(HOURS_ALARM, encode_hour(hour, register_b));
+            rtc.write(REGISTER_B, register_b | ALARM_INTERRUPT_ENABLE);
+        }
+        Ok(())
+    })
+}
+
+/// Cancels the alarm set by `set_alarm`.
+pub fn cancel_alarm() {
+    without_interrupts(|| {
+        disable_alarm(&mut RTC.lock());
+        *ALARM.lock() = None;
+    });
+}
+
+fn disable_alarm(rtc: &mut Rtc) {
+    unsafe {
+        let register_b = rtc.read(REGISTER_B);
+        rtc.write(REGISTER_B, register_b & !ALARM_INTERRUPT_ENABLE);
+    }
+}
+
+fn interrupt_handler(_irq: u8) {
+    let mut rtc = RTC.lock();
+    // reading register C acknowledges the interrupt
+    let flags = unsafe { rtc.read(REGISTER_C) };
+
+    let mut alarm = None;
+    if flags & ALARM_INTERRUPT_FLAG != 0 {
+        let mut pending = ALARM.lock();
+        if let Some(a) = *pending {
+            if unsafe { rtc.time_no_wait() } >= a.time {
+                alarm = pending.take();
+                disable_alarm(&mut rtc);
+            }
+        }
+    }
+    drop(rtc);
+
+    if flags & PERIODIC_INTERRUPT_FLAG != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+        // unlocked before the call, the handler may replace itself
+        let handler = *PERIODIC_HANDLER.lock();
+        if let Some(handler) = handler {
+            handler();
+        }
+    }
+    if let Some(alarm) = alarm {
+        (alarm.callback)();
+    }
 }
 
-fn cvt_bcd(value: usize) -> usize {
+fn cvt_bcd(value: u8) -> u8 {
     (value & 0xF) + ((value / 16) * 10)
 }
 
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | value % 10
+}
+
+/// Encodes a clock register value in the format selected by `register_b`.
+fn encode(value: u8, register_b: u8) -> u8 {
+    if register_b & BINARY_MODE != 0 {
+        value
+    } else {
+        to_bcd(value)
+    }
+}
+
+fn decode(value: u8, register_b: u8) -> u8 {
+    if register_b & BINARY_MODE != 0 {
+        value
+    } else {
+        cvt_bcd(value)
+    }
+}
+
+/// Encodes an hour from 0 to 23 in the format selected by `register_b`.
+fn encode_hour(hour: u8, register_b: u8) -> u8 {
+    if register_b & HOUR_24_MODE != 0 {
+        encode(hour, register_b)
+    } else {
+        // 12 hour mode counts 12, 1, ..., 11 and flags PM in the top bit
+        let pm = if hour >= 12 { 0x80 } else { 0 };
+        encode((hour + 11) % 12 + 1, register_b) | pm
+    }
+}
+
+/// Decodes an hour register into an hour from 0 to 23.
+fn decode_hour(hour: u8, register_b: u8) -> u8 {
+    let value = decode(hour & 0x7F, register_b);
+    if register_b & HOUR_24_MODE != 0 {
+        value
+    } else if hour & 0x80 != 0 {
+        value % 12 + 12
+    } else {
+        value % 12
+    }
+}
+
 /// RTC
 pub struct Rtc {
     addr: Port<u8>,
@@ -19,7 +246,7 @@ pub struct Rtc {
 
 impl Rtc {
     /// Create new empty RTC
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Rtc {
             addr: Port::new(0x70),
             data: Port::new(0x71),
@@ -38,7 +265,6 @@ impl Rtc {
     }
 
     /// Write
-    #[allow(dead_code)]
     unsafe fn write(&mut self, reg: u8, value: u8) {
         if self.nmi {
             self.addr.write(reg & 0x7F);
@@ -56,93 +282,98 @@ impl Rtc {
         while self.read(0xA) & 0x80 == 0x80 {}
     }
 
-    /// Get time without waiting
-    pub unsafe fn time_no_wait(&mut self) -> u64 {
-        /*let century_register = if let Some(ref fadt) = acpi::ACPI_TABLE.lock().fadt {
-            Some(fadt.century)
-        } else {
-            None
-        };*/
-
-        let mut second = self.read(0) as usize;
-        let mut minute = self.read(2) as usize;
-        let mut hour = self.read(4) as usize;
-        let mut day = self.read(7) as usize;
-        let mut month = self.read(8) as usize;
-        let mut year = self.read(9) as usize;
-        let mut century = /* TODO: Fix invalid value from VirtualBox
-        if let Some(century_reg) = century_register {
-            self.read(century_reg) as usize
-        } else */ {
-            20
-        };
-        let register_b = self.read(0xB);
-
-        if register_b & 4 != 4 {
-            second = cvt_bcd(second);
-            minute = cvt_bcd(minute);
-            hour = cvt_bcd(hour & 0x7F) | (hour & 0x80);
-            day = cvt_bcd(day);
-            month = cvt_bcd(month);
-            year = cvt_bcd(year);
-            century = /* TODO: Fix invalid value from VirtualBox
-            if century_register.is_some() {
-                cvt_bcd(century)
-            } else */ {
-                century
-            };
-        }
-
-        if register_b & 2 != 2 || hour & 0x80 == 0x80 {
-            hour = ((hour & 0x7F) + 12) % 24;
-        }
-
-        year += century * 100;
+    /// Get date and time without waiting. An invalid date reads as the Unix epoch.
+    pub unsafe fn date_time_no_wait(&mut self) -> DateTime {
+        let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+        let register_b = self.read(REGISTER_B);
 
-        // Unix time from clock
-        let mut secs: u64 = (year as u64 - 1970) * 31_536_000;
+        let second = decode(self.read(0), register_b);
+        let minute = decode(self.read(2), register_b);
+        let hour = decode_hour(self.read(4), register_b);
+        let day = decode(self.read(7), register_b);
+        let month = decode(self.read(8), register_b);
+        let year = decode(self.read(9), register_b);
+        let mut century = match century_register {
+            0 => 20,
+            register => decode(self.read(register), register_b),
+        };
 
-        let mut leap_days = (year as u64 - 1972) / 4 + 1;
-        if year % 4 == 0 && month <= 2 {
-            leap_days -= 1;
-        }
-        secs += leap_days * 86_400;
-
-        match month {
-            2 => secs += 2_678_400,
-            3 => secs += 5_097_600,
-            4 => secs += 7_776_000,
-            5 => secs += 10_368_000,
-            6 => secs += 13_046_400,
-            7 => secs += 15_638_400,
-            8 => secs += 18_316_800,
-            9 => secs += 20_995_200,
-            10 => secs += 23_587_200,
-            11 => secs += 26_265_600,
-            12 => secs += 28_857_600,
-            _ => (),
+        // some emulators, e.g. VirtualBox, report a century register with garbage in it
+        if !(19..=99).contains(&century) {
+            century = 20;
         }
 
-        secs += (day as u64 - 1) * 86_400;
-        secs += hour as u64 * 3600;
-        secs += minute as u64 * 60;
-        secs += second as u64;
+        let year = u16::from(century) * 100 + u16::from(year);
+        // e.g. a clock that was never set
+        DateTime::new(year, month, day, hour, minute, second).unwrap_or(DateTime::UNIX_EPOCH)
+    }
 
-        secs
+    /// Get time without waiting
+    pub unsafe fn time_no_wait(&mut self) -> u64 {
+        self.date_time_no_wait().to_unix()
     }
 
-    /// Get time
-    pub fn time(&mut self) -> u64 {
+    /// Get date and time
+    pub fn date_time(&mut self) -> DateTime {
         loop {
             unsafe {
                 self.wait(false);
-                let time = self.time_no_wait();
+                let date_time = self.date_time_no_wait();
                 self.wait(false);
-                let next_time = self.time_no_wait();
-                if time == next_time {
-                    return time;
+                let next_date_time = self.date_time_no_wait();
+                if date_time == next_date_time {
+                    return date_time;
                 }
             }
         }
     }
+
+    /// Get time
+    pub fn time(&mut self) -> u64 {
+        self.date_time().to_unix()
+    }
+
+    /// Set date and time, in the format selected by register B
+    pub fn set_time(&mut self, date_time: DateTime) {
+        let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+        unsafe {
+            let register_b = self.read(REGISTER_B);
+            self.write(REGISTER_B, register_b | SET);
+
+            self.write(0, encode(date_time.second(), register_b));
+            self.write(2, encode(date_time.minute(), register_b));
+            self.write(4, encode_hour(date_time.hour(), register_b));
+            self.write(7, encode(date_time.day(), register_b));
+            self.write(8, encode(date_time.month(), register_b));
+            self.write(9, encode((date_time.year() % 100) as u8, register_b));
+            if century_register != 0 {
+                self.write(
+                    century_register,
+                    encode((date_time.year() / 100) as u8, register_b),
+                );
+            }
+
+            self.write(REGISTER_B, register_b & !SET);
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_hour_encoding() {
+    serial_print!("test_hour_encoding... ");
+    let bcd_12h = 0;
+    assert_eq!(decode_hour(0x12, bcd_12h), 0);
+    assert_eq!(decode_hour(0x01, bcd_12h), 1);
+    assert_eq!(decode_hour(0x92, bcd_12h), 12);
+    assert_eq!(decode_hour(0x91, bcd_12h), 13);
+    assert_eq!(decode_hour(0x23, HOUR_24_MODE), 23);
+    for hour in 0..24 {
+        for &register_b in &[0, HOUR_24_MODE, BINARY_MODE, BINARY_MODE | HOUR_24_MODE] {
+            assert_eq!(decode_hour(encode_hour(hour, register_b), register_b), hour);
+        }
+    }
+    serial_println!("[ok]");
 }
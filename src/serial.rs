@@ -1,26 +1,33 @@
+use crate::sync::{lock_order, IrqSpinLock};
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqSpinLock<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSpinLock::with_level(serial_port, lock_order::SERIAL)
     };
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+/// Prints unless the port is locked, for code that can interrupt its holder.
+#[doc(hidden)]
+pub fn _try_print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    if let Some(mut serial) = SERIAL1.try_lock() {
+        serial.write_fmt(args).expect("Printing to serial failed");
+    }
 }
 
 /// Prints to the host through the serial interface.
@@ -0,0 +1,321 @@
+//! Startup of the application processors (APs).
+//!
+//! The processors are listed in the MADT. The bootstrap processor copies a trampoline
+//! into the frame below 1 MiB that `memory::init` reserved and starts every AP with an
+//! INIT IPI followed by startup IPIs pointing to the trampoline. The trampoline goes
+//! from real mode through protected mode to long mode with the kernel page table,
+//! which maps the trampoline at its physical address while APs start, and jumps to
+//! `ap_main` on a fresh kernel stack.
+//!
+//! The APs are started one after the other. Each one points its GS base to its
+//! `PerCpu`, loads its own GDT and TSS and the shared IDT, enables `syscall` and its
+//! local APIC, measures the offset of its TSC and becomes the idle thread of the
+//! processor, from where it runs ready threads.
+
+use crate::acpi::ACPI_TABLE;
+use crate::memory::stack::{self, KernelStack};
+use crate::memory::{self, phys_to_virt, with_mapper};
+use crate::time::tsc;
+use crate::{apic, gdt, interrupts, pit, println, syscall, thread};
+use alloc::boxed::Box;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::Cr0;
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::paging::{
+    Mapper, MapperAllSizes, Page, PageTableFlags, PhysFrame, Size4KiB, UnusedPhysFrame,
+};
+use x86_64::VirtAddr;
+
+pub mod ipi;
+pub mod percpu;
+
+pub use percpu::{cpu_count, PerCpu, MAX_CPUS};
+
+/// Size of the stack an AP starts on, which becomes the stack of its idle thread.
+const AP_STACK_PAGES: u64 = stack::DEFAULT_STACK_PAGES;
+
+/// `EFER.LMA` is set by the processor when long mode becomes active.
+const EFER_LONG_MODE_ACTIVE: u64 = 1 << 10;
+/// `CR4.PCIDE` can only be set once long mode is active.
+const CR4_PCID_ENABLE: u64 = 1 << 17;
+
+global_asm!(
+    r#"
+    .global smp_trampoline_start
+    .global smp_trampoline_params
+    .global smp_trampoline_end
+
+    // Entered in real mode with CS pointing to the copy of the trampoline, so the
+    // offsets from `smp_trampoline_start` address the copy. The linear addresses of
+    // the copy are patched in before they are needed.
+    .code16
+smp_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    movzwl %ax, %ebx
+    shl $4, %ebx
+    mov %ebx, %eax
+    add $(smp_trampoline_gdt - smp_trampoline_start), %eax
+    mov %eax, (smp_trampoline_gdt_pointer + 2 - smp_trampoline_start)
+    mov %ebx, %eax
+    add $(smp_trampoline_32 - smp_trampoline_start), %eax
+    mov %eax, (smp_trampoline_jump_32 - smp_trampoline_start)
+    mov %ebx, %eax
+    add $(smp_trampoline_64 - smp_trampoline_start), %eax
+    mov %eax, (smp_trampoline_jump_64 - smp_trampoline_start)
+    lgdtl (smp_trampoline_gdt_pointer - smp_trampoline_start)
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+    ljmpl *(smp_trampoline_jump_32 - smp_trampoline_start)
+
+    // Protected mode without paging, `ebx` holds the address of the copy.
+    .code32
+smp_trampoline_32:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov (smp_trampoline_params - smp_trampoline_start + 8)(%ebx), %eax
+    mov %eax, %cr4
+    mov (smp_trampoline_params - smp_trampoline_start)(%ebx), %eax
+    mov %eax, %cr3
+    mov $0xc0000080, %ecx
+    mov (smp_trampoline_params - smp_trampoline_start + 16)(%ebx), %eax
+    mov (smp_trampoline_params - smp_trampoline_start + 20)(%ebx), %edx
+    wrmsr
+    // enables paging, which activates long mode
+    mov (smp_trampoline_params - smp_trampoline_start + 24)(%ebx), %eax
+    mov %eax, %cr0
+    ljmpl *(smp_trampoline_jump_64 - smp_trampoline_start)(%ebx)
+
+    .code64
+smp_trampoline_64:
+    mov %ebx, %ebx
+    mov (smp_trampoline_params - smp_trampoline_start + 32)(%rbx), %rsp
+    mov (smp_trampoline_params - smp_trampoline_start + 48)(%rbx), %rdi
+    mov (smp_trampoline_params - smp_trampoline_start + 40)(%rbx), %rax
+    xor %ebp, %ebp
+    // a fake return address, so that the stack is aligned as after a call
+    push $0
+    jmp *%rax
+
+    .balign 8
+smp_trampoline_gdt:
+    .quad 0
+    // 32 bit code, data and 64 bit code
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00af9a000000ffff
+smp_trampoline_gdt_pointer:
+    .word smp_trampoline_gdt_pointer - smp_trampoline_gdt - 1
+    .long 0
+smp_trampoline_jump_32:
+    .long 0
+    .word 0x08
+smp_trampoline_jump_64:
+    .long 0
+    .word 0x18
+    .balign 8
+smp_trampoline_params:
+    .skip 56
+smp_trampoline_end:
+    "#
+);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_params: u8;
+    static smp_trampoline_end: u8;
+}
+
+/// The values the trampoline loads, at `smp_trampoline_params`.
+#[repr(C)]
+struct TrampolineParams {
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    cr0: u64,
+    stack_top: u64,
+    entry: u64,
+    /// The `PerCpu` of the AP, passed to `entry`.
+    cpu: u64,
+}
+
+/// Set by the AP that is being started once it runs.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+/// The stack of the AP that is being started, taken over by its idle thread.
+static AP_STACK: Mutex<Option<KernelStack>> = Mutex::new(None);
+
+/// Starts the APs listed in the MADT and returns the number of running processors.
+///
+/// Must be called by the bootstrap processor after `apic::init` and `thread::init`.
+pub fn init() -> usize {
+    if !apic::is_enabled() {
+        return cpu_count();
+    }
+    ipi::init();
+    let madt = match ACPI_TABLE.lock().madt.clone() {
+        Some(madt) => madt,
+        None => return cpu_count(),
+    };
+    let frame = match memory::real_mode_frame() {
+        Some(frame) => frame,
+        None => {
+            println!("WARNING: no memory below 1 MiB to start processors from");
+            return cpu_count();
+        }
+    };
+
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    let mapped =
+        with_mapper(
+            |mapper, frame_allocator| match mapper.translate_addr(page.start_address()) {
+                Some(addr) if addr == frame.start_address() => Some(false),
+                Some(_) => None,
+                None => {
+                    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                    let frame = unsafe { UnusedPhysFrame::new(frame) };
+                    let flush = mapper.map_to(page, frame, flags, frame_allocator).ok()?;
+                    flush.flush();
+                    Some(true)
+                }
+            },
+        );
+    let mapped = match mapped {
+        Some(mapped) => mapped,
+        None => {
+            println!("WARNING: can't map the processor startup trampoline");
+            return cpu_count();
+        }
+    };
+    unsafe { install_trampoline(frame) };
+
+    let bsp = apic::local_apic_id();
+    for processor in madt
+        .processors
+        .iter()
+        .filter(|p| p.enabled && p.apic_id != bsp)
+    {
+        let index = cpu_count();
+        if index >= MAX_CPUS {
+            break;
+        }
+        if !start_ap(index, processor.apic_id, frame) {
+            println!(
+                "WARNING: processor with APIC id {} did not start",
+                processor.apic_id
+            );
+        }
+    }
+
+    if mapped {
+        with_mapper(|mapper, _| {
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.ignore();
+            }
+        });
+        ipi::flush_tlb(page.start_address(), 1);
+    }
+    cpu_count()
+}
+
+/// Copies the trampoline into `frame` and fills in everything but the stack and the
+/// processor of the parameters.
+///
+/// This function is unsafe because `frame` must be reserved for the trampoline.
+unsafe fn install_trampoline(frame: PhysFrame) {
+    let start = &smp_trampoline_start as *const u8;
+    let size = &smp_trampoline_end as *const u8 as usize - start as usize;
+    assert!(size <= 4096, "processor startup trampoline too large");
+    let copy: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+    ptr::copy_nonoverlapping(start, copy, size);
+
+    let cr3 = memory::kernel_level_4_frame().start_address().as_u64();
+    assert!(
+        cr3 < 1 << 32,
+        "kernel page table not reachable from protected mode"
+    );
+    let cr4: u64;
+    asm!("mov %cr4, $0" : "=r"(cr4) ::: "volatile");
+    *params(frame) = TrampolineParams {
+        cr3,
+        cr4: cr4 & !CR4_PCID_ENABLE,
+        efer: Efer::read().bits() & !EFER_LONG_MODE_ACTIVE,
+        cr0: Cr0::read().bits(),
+        stack_top: 0,
+        entry: ap_main as usize as u64,
+        cpu: 0,
+    };
+}
+
+/// Returns the parameters of the trampoline copied into `frame`.
+unsafe fn params(frame: PhysFrame) -> &'static mut TrampolineParams {
+    let offset =
+        &smp_trampoline_params as *const u8 as usize - &smp_trampoline_start as *const u8 as usize;
+    &mut *(phys_to_virt(frame.start_address()) + offset).as_mut_ptr()
+}
+
+/// Starts the AP with `apic_id` as processor `index`. Returns whether it runs.
+fn start_ap(index: usize, apic_id: u8, trampoline: PhysFrame) -> bool {
+    let stack = match stack::allocate(AP_STACK_PAGES) {
+        Ok(stack) => stack,
+        Err(_) => return false,
+    };
+    // an AP that doesn't start keeps its data, it may still run into the trampoline
+    let cpu: &'static PerCpu = Box::leak(Box::new(PerCpu::new(index, apic_id)));
+    unsafe {
+        let params = params(trampoline);
+        params.stack_top = stack.top().as_u64();
+        params.cpu = cpu as *const PerCpu as u64;
+    }
+    *AP_STACK.lock() = Some(stack);
+    AP_STARTED.store(false, Ordering::SeqCst);
+
+    let page = (trampoline.start_address().as_u64() / 4096) as u8;
+    apic::send_init(apic_id);
+    pit::wait_micros(10_000);
+    apic::send_startup(apic_id, page);
+    let started = wait_until_started(1000) || {
+        // a processor that is already running ignores the second startup IPI
+        apic::send_startup(apic_id, page);
+        wait_until_started(100_000)
+    };
+    if started {
+        tsc::synchronize_bsp();
+    }
+    started
+}
+
+fn wait_until_started(micros: u64) -> bool {
+    for _ in 0..micros / 100 {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+        pit::wait_micros(100);
+    }
+    AP_STARTED.load(Ordering::SeqCst)
+}
+
+/// The first Rust code an AP runs, with interrupts disabled and the temporary GDT of
+/// the trampoline.
+extern "C" fn ap_main(cpu: &'static PerCpu) -> ! {
+    unsafe { percpu::init_ap(cpu) };
+    gdt::init();
+    syscall::init();
+    interrupts::init_idt();
+    apic::init_ap();
+    let stack = AP_STACK
+        .lock()
+        .take()
+        .expect("processor started without stack");
+    thread::init_ap(stack);
+    percpu::set_online(cpu);
+    AP_STARTED.store(true, Ordering::SeqCst);
+    tsc::synchronize_ap();
+    thread::idle();
+}
@@ -0,0 +1,102 @@
+//! Interrupts the processors send each other.
+//!
+//! A TLB shootdown makes the other processors drop stale translations after a
+//! mapping was removed or restricted. The initiator waits until every processor
+//! flushed, so the memory can be reused right after `flush_tlb` returns. A reschedule
+//! interrupt makes a processor look for a ready thread at the end of the interrupt.
+
+use super::percpu::{self, PerCpu};
+use crate::apic;
+use crate::interrupts::irq;
+use core::sync::atomic::{spin_loop_hint, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tlb;
+use x86_64::VirtAddr;
+
+/// Above this number of pages a shootdown flushes the whole TLB.
+const FULL_FLUSH_PAGES: u64 = 32;
+
+/// Held by the processor whose shootdown is in flight.
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PAGES: AtomicU64 = AtomicU64::new(0);
+/// The processors that did not flush for the shootdown in flight yet.
+static SHOOTDOWN_PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the handlers of the interrupts other processors send.
+pub fn init() {
+    irq::register_irq(irq::TLB_SHOOTDOWN, shootdown_handler).expect("TLB shootdown IRQ is taken");
+    irq::register_irq(irq::RESCHEDULE, reschedule_handler).expect("reschedule IRQ is taken");
+}
+
+/// Flushes the translations of `pages` pages starting at `start` on all processors.
+///
+/// Must not be called with spinlocks held or from interrupt handlers, since it waits
+/// for the other processors to handle the interrupt.
+pub fn flush_tlb(start: VirtAddr, pages: u64) {
+    without_interrupts(|| {
+        let others = percpu::online_mask() & !percpu::current().mask();
+        if others == 0 || !apic::is_enabled() {
+            flush_local(start.as_u64(), pages);
+            return;
+        }
+
+        // a processor waiting here with interrupts disabled still takes part in the
+        // shootdown in flight, otherwise the two would wait for each other
+        let guard = loop {
+            if let Some(guard) = SHOOTDOWN.try_lock() {
+                break guard;
+            }
+            handle_shootdown();
+            spin_loop_hint();
+        };
+        SHOOTDOWN_START.store(start.as_u64(), Ordering::Relaxed);
+        SHOOTDOWN_PAGES.store(pages, Ordering::Relaxed);
+        SHOOTDOWN_PENDING.store(others, Ordering::Release);
+        for cpu in percpu::cpus().filter(|cpu| others & cpu.mask() != 0) {
+            apic::send_ipi(cpu.apic_id(), irq::vector(irq::TLB_SHOOTDOWN));
+        }
+        flush_local(start.as_u64(), pages);
+        while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+            spin_loop_hint();
+        }
+        drop(guard);
+    });
+}
+
+/// Makes `cpu` check for a thread to switch to at the end of the interrupt.
+pub fn send_reschedule(cpu: &PerCpu) {
+    apic::send_ipi(cpu.apic_id(), irq::vector(irq::RESCHEDULE));
+}
+
+fn shootdown_handler(_irq: u8) {
+    handle_shootdown();
+}
+
+fn reschedule_handler(_irq: u8) {
+    percpu::current().reschedule.store(true, Ordering::Relaxed);
+}
+
+/// Flushes for the shootdown in flight if the executing processor takes part in it.
+fn handle_shootdown() {
+    let mask = percpu::current().mask();
+    if SHOOTDOWN_PENDING.load(Ordering::Acquire) & mask == 0 {
+        return;
+    }
+    flush_local(
+        SHOOTDOWN_START.load(Ordering::Relaxed),
+        SHOOTDOWN_PAGES.load(Ordering::Relaxed),
+    );
+    SHOOTDOWN_PENDING.fetch_and(!mask, Ordering::Release);
+}
+
+fn flush_local(start: u64, pages: u64) {
+    if pages > FULL_FLUSH_PAGES {
+        tlb::flush_all();
+        return;
+    }
+    for page in 0..pages {
+        tlb::flush(VirtAddr::new(start + page * 4096));
+    }
+}
@@ -0,0 +1,167 @@
+//! Data of every processor, reached through the GS segment of the processor.
+//!
+//! The GS base of a processor points to its `PerCpu`, whose first field points to
+//! itself, so `current` finds it with a single load. Until `init_bsp` set the GS base
+//! of the bootstrap processor, `current` returns the data of the bootstrap processor.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+/// Maximum number of processors, so that sets of processors fit into a `u64`.
+pub const MAX_CPUS: usize = 64;
+
+const IA32_GS_BASE: u32 = 0xC000_0101;
+
+/// The bootstrap processor, which is always processor 0.
+static BSP: PerCpu = PerCpu::new(0, 0);
+/// Whether the GS base of the bootstrap processor was set.
+static GS_READY: AtomicBool = AtomicBool::new(false);
+
+const NO_CPU: AtomicU64 = AtomicU64::new(0);
+/// The addresses of the `PerCpu` of the processors that were started, by index.
+static CPUS: [AtomicU64; MAX_CPUS] = [NO_CPU; MAX_CPUS];
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+#[repr(C)]
+pub struct PerCpu {
+    /// The address of this struct. Must stay the first field.
+    this: AtomicU64,
+    /// The top of the kernel stack of the running thread. Must stay at offset 8, it is
+    /// loaded by the system call entry.
+    pub(crate) kernel_stack: AtomicU64,
+    /// The stack pointer of ring 3 while the system call entry switches stacks. Must
+    /// stay at offset 16.
+    pub(crate) user_stack: AtomicU64,
+    /// The TSS of the processor, see `gdt::set_kernel_stack`.
+    pub(crate) tss: AtomicU64,
+    index: usize,
+    apic_id: AtomicU8,
+    /// The levels of the held locks, see `sync::lock_order`.
+    pub(crate) held_locks: AtomicU64,
+    /// The thread running on the processor.
+    pub(crate) current_thread: AtomicU64,
+    /// The thread that runs when no other thread is ready.
+    pub(crate) idle_thread: AtomicU64,
+    /// Set when the running thread should be switched out at the end of an interrupt.
+    pub(crate) reschedule: AtomicBool,
+    /// Timer ticks since the running thread was switched in.
+    pub(crate) slice_ticks: AtomicU64,
+    /// Added to the TSC of the processor to match the one of the bootstrap processor,
+    /// see `tsc::read_synchronized`.
+    pub(crate) tsc_offset: AtomicU64,
+}
+
+impl PerCpu {
+    pub(crate) const fn new(index: usize, apic_id: u8) -> Self {
+        PerCpu {
+            this: AtomicU64::new(0),
+            kernel_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+            tss: AtomicU64::new(0),
+            index,
+            apic_id: AtomicU8::new(apic_id),
+            held_locks: AtomicU64::new(0),
+            current_thread: AtomicU64::new(0),
+            idle_thread: AtomicU64::new(0),
+            reschedule: AtomicBool::new(false),
+            slice_ticks: AtomicU64::new(0),
+            tsc_offset: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the index of the processor, 0 for the bootstrap processor.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the id of the local APIC of the processor.
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    /// Returns the bit of the processor in a set of processors.
+    pub fn mask(&self) -> u64 {
+        1 << self.index
+    }
+}
+
+/// Points the GS base of the bootstrap processor to its data.
+///
+/// Must be called before the first interrupt.
+pub fn init_bsp() {
+    unsafe { load(&BSP) };
+    CPUS[0].store(&BSP as *const PerCpu as u64, Ordering::SeqCst);
+    GS_READY.store(true, Ordering::SeqCst);
+}
+
+/// Records the local APIC id of the bootstrap processor once the APIC is used.
+pub(crate) fn set_bsp_apic_id(apic_id: u8) {
+    BSP.apic_id.store(apic_id, Ordering::SeqCst);
+}
+
+/// Makes `cpu` the data of the executing processor.
+///
+/// This function is unsafe because it must be called once by a freshly started
+/// application processor before it uses anything that accesses its data.
+pub(crate) unsafe fn init_ap(cpu: &'static PerCpu) {
+    load(cpu);
+}
+
+/// Counts `cpu` as started, once it can receive interrupts from other processors.
+///
+/// The processors are started one after the other in the order of their indices.
+pub(crate) fn set_online(cpu: &'static PerCpu) {
+    CPUS[cpu.index].store(cpu as *const PerCpu as u64, Ordering::SeqCst);
+    CPU_COUNT.store(cpu.index + 1, Ordering::SeqCst);
+}
+
+unsafe fn load(cpu: &'static PerCpu) {
+    let addr = cpu as *const PerCpu as u64;
+    cpu.this.store(addr, Ordering::SeqCst);
+    Msr::new(IA32_GS_BASE).write(addr);
+}
+
+/// Returns whether the GS base of the bootstrap processor was set, see `init_bsp`.
+pub(crate) fn is_ready() -> bool {
+    GS_READY.load(Ordering::Relaxed)
+}
+
+/// Returns the data of the executing processor.
+///
+/// The running thread can be moved to another processor unless interrupts are
+/// disabled, so the result is only stable with interrupts disabled.
+pub fn current() -> &'static PerCpu {
+    if !GS_READY.load(Ordering::Relaxed) {
+        return &BSP;
+    }
+    let addr: u64;
+    unsafe { asm!("mov %gs:0, $0" : "=r"(addr) ::: "volatile") };
+    unsafe { &*(addr as *const PerCpu) }
+}
+
+/// Returns the data of the processor with `index` if it was started.
+pub fn cpu(index: usize) -> Option<&'static PerCpu> {
+    let addr = CPUS.get(index)?.load(Ordering::SeqCst);
+    if addr == 0 {
+        None
+    } else {
+        Some(unsafe { &*(addr as *const PerCpu) })
+    }
+}
+
+/// Returns the number of started processors.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/// Returns the set of started processors.
+pub fn online_mask() -> u64 {
+    (0..cpu_count())
+        .filter_map(cpu)
+        .fold(0, |mask, cpu| mask | cpu.mask())
+}
+
+/// Returns an iterator over the started processors.
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    (0..cpu_count()).filter_map(cpu)
+}
@@ -0,0 +1,25 @@
+//! Locks and primitives to wait for other threads.
+//!
+//! `IrqSpinLock` disables interrupts while it is held, so it can be shared with
+//! interrupt handlers and its holder is never preempted. `Mutex`, `Semaphore` and
+//! `Condvar` park the waiting thread in a `WaitQueue` instead of spinning. They must
+//! not be waited on in interrupt handlers, apart from the exception handlers of ring
+//! 3, but handlers may wake their waiters.
+//!
+//! Locks can be given a `LockLevel`. In debug builds a lock may only be taken while
+//! all held locks have lower levels, which catches lock order inversions and
+//! recursive locking before they deadlock.
+
+pub mod condvar;
+pub mod lock_order;
+pub mod mutex;
+pub mod semaphore;
+pub mod spin_lock;
+pub mod wait_queue;
+
+pub use condvar::Condvar;
+pub use lock_order::LockLevel;
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use spin_lock::{IrqSpinLock, IrqSpinLockGuard};
+pub use wait_queue::WaitQueue;
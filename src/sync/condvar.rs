@@ -0,0 +1,59 @@
+use super::{MutexGuard, WaitQueue};
+use crate::thread;
+
+/// Lets threads wait for a condition on the value of a `Mutex`.
+///
+/// Like all condition variables, waiting can return spuriously, so the condition has
+/// to be checked again, e.g. with `wait_while`.
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks the mutex, parks until notified and locks the mutex again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let current = thread::current();
+        // waiting starts before the mutex is unlocked, so no notification is missed
+        self.waiters.enqueue(current);
+        drop(guard);
+        thread::park();
+        self.waiters.remove(current);
+        mutex.lock()
+    }
+
+    /// Waits as long as `condition` returns true for the value of the mutex.
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.waiters.notify_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.waiters.notify_all()
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
@@ -0,0 +1,104 @@
+//! Debug checks of the order locks are taken in.
+//!
+//! The levels of the held locks are a bit set of the running thread, which is kept in
+//! the data of the processor and saved and restored when threads are switched.
+//! Interrupt handlers start with an empty set, since they can't wait for locks held
+//! by the thread they interrupted anyway.
+
+use crate::smp::percpu;
+use core::sync::atomic::Ordering;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// `process::PROCESSES`, the process table.
+pub const PROCESSES: LockLevel = LockLevel(4);
+/// The address space of a user thread, held while system calls access ring 3.
+pub const ADDRESS_SPACE: LockLevel = LockLevel(8);
+/// `MOUSE`, held while the button handlers run.
+pub const MOUSE: LockLevel = LockLevel(16);
+/// `time::START` and `time::OFFSET`.
+pub const CLOCK: LockLevel = LockLevel(48);
+/// `vga_buffer::WRITER`, anything may print.
+pub const VGA: LockLevel = LockLevel(62);
+/// `serial::SERIAL1`, anything may print.
+pub const SERIAL: LockLevel = LockLevel(63);
+
+/// The position of a lock in the lock order. A lock may only be taken while all held
+/// locks have lower levels, so locks of the same level can't be held together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LockLevel(u8);
+
+impl LockLevel {
+    /// Levels range from 0 to 63, higher values are truncated.
+    pub const fn new(level: u8) -> Self {
+        LockLevel(level & 63)
+    }
+
+    pub fn as_u8(self) -> u8 {
+        self.0
+    }
+}
+
+/// Records that a lock of `level` is taken. Panics in debug builds if a lock of the
+/// same or a higher level is held.
+pub(crate) fn acquire(level: LockLevel) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let held = held();
+    if held >> level.0 != 0 {
+        panic!(
+            "lock order violation: taking a lock of level {} while holding one of level {}",
+            level.0,
+            63 - held.leading_zeros()
+        );
+    }
+    set_held(held | 1 << level.0);
+}
+
+/// Records that a lock of `level` is taken without checking the order, for locks
+/// that are taken without waiting.
+pub(crate) fn acquire_unchecked(level: LockLevel) {
+    if cfg!(debug_assertions) {
+        set_held(held() | 1 << level.0);
+    }
+}
+
+/// Records that the lock of `level` was released.
+pub(crate) fn release(level: LockLevel) {
+    if cfg!(debug_assertions) {
+        set_held(held() & !(1 << level.0));
+    }
+}
+
+/// Returns the levels of the held locks, to be restored by `set_held`.
+///
+/// Bit `n` is set while a lock of level `n` is held. The set belongs to the running
+/// thread, which stays on the processor while interrupts are disabled.
+pub(crate) fn held() -> u64 {
+    without_interrupts(|| percpu::current().held_locks.load(Ordering::Relaxed))
+}
+
+pub(crate) fn set_held(held: u64) {
+    without_interrupts(|| percpu::current().held_locks.store(held, Ordering::Relaxed));
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_lock_levels() {
+    serial_print!("test_lock_levels... ");
+    let saved = held();
+    set_held(0);
+    acquire(LockLevel::new(3));
+    acquire(LockLevel::new(10));
+    assert_eq!(held(), 1 << 3 | 1 << 10);
+    release(LockLevel::new(10));
+    acquire(LockLevel::new(5));
+    release(LockLevel::new(5));
+    release(LockLevel::new(3));
+    assert_eq!(held(), 0);
+    assert_eq!(LockLevel::new(64 + 7), LockLevel::new(7));
+    set_held(saved);
+    serial_println!("[ok]");
+}
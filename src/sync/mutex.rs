@@ -0,0 +1,98 @@
+use super::lock_order::{self, LockLevel};
+use super::WaitQueue;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A lock that parks the threads waiting for it.
+///
+/// It must not be taken in interrupt handlers, except in those of exceptions raised
+/// by ring 3, which run in the context of the interrupted thread. Interrupts stay
+/// enabled while it is held, so its holder can be preempted.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    level: Option<LockLevel>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            level: None,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Creates a lock whose order is checked against `level`.
+    pub const fn with_level(value: T, level: LockLevel) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            level: Some(level),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        if let Some(level) = self.level {
+            lock_order::acquire(level);
+        }
+        while !self.try_acquire() {
+            self.waiters
+                .wait_until(|| !self.locked.load(Ordering::Relaxed));
+        }
+        MutexGuard { mutex: self }
+    }
+
+    /// Takes the lock if it is free.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if !self.try_acquire() {
+            return None;
+        }
+        if let Some(level) = self.level {
+            lock_order::acquire_unchecked(level);
+        }
+        Some(MutexGuard { mutex: self })
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+/// Unlocks the mutex and wakes a waiting thread when dropped.
+pub struct MutexGuard<'a, T> {
+    pub(super) mutex: &'a Mutex<T>,
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        if let Some(level) = self.mutex.level {
+            lock_order::release(level);
+        }
+        self.mutex.waiters.notify_one();
+    }
+}
@@ -0,0 +1,101 @@
+use super::lock_order::{self, LockLevel};
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use x86_64::instructions::interrupts;
+
+/// A spinlock that disables interrupts while it is held.
+///
+/// Interrupt handlers can take it without deadlocking against the code they
+/// interrupted, and the thread holding it is never preempted.
+pub struct IrqSpinLock<T> {
+    inner: spin::Mutex<T>,
+    level: Option<LockLevel>,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSpinLock {
+            inner: spin::Mutex::new(value),
+            level: None,
+        }
+    }
+
+    /// Creates a lock whose order is checked against `level`.
+    pub const fn with_level(value: T, level: LockLevel) -> Self {
+        IrqSpinLock {
+            inner: spin::Mutex::new(value),
+            level: Some(level),
+        }
+    }
+
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if let Some(level) = self.level {
+            lock_order::acquire(level);
+        }
+        IrqSpinLockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            level: self.level,
+            interrupts_enabled,
+        }
+    }
+
+    /// Takes the lock if it is free.
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                if let Some(level) = self.level {
+                    lock_order::acquire_unchecked(level);
+                }
+                Some(IrqSpinLockGuard {
+                    guard: ManuallyDrop::new(guard),
+                    level: self.level,
+                    interrupts_enabled,
+                })
+            }
+            None => {
+                if interrupts_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Unlocks the lock and restores the interrupt state when dropped.
+pub struct IrqSpinLockGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    level: Option<LockLevel>,
+    interrupts_enabled: bool,
+}
+
+impl<'a, T> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // the lock must be free before interrupts are enabled again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if let Some(level) = self.level {
+            lock_order::release(level);
+        }
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use super::IrqSpinLock;
+use crate::thread::{self, ThreadId};
+use alloc::vec::Vec;
+use core::mem;
+
+/// Threads parked until they are notified, woken in the order they started waiting.
+pub struct WaitQueue {
+    waiters: IrqSpinLock<Vec<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqSpinLock::new(Vec::new()),
+        }
+    }
+
+    /// Parks the running thread until `condition` returns true.
+    ///
+    /// `condition` is called with the queue locked and interrupts disabled, so a
+    /// notification right after it returned false is not lost.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        let current = thread::current();
+        loop {
+            {
+                let mut waiters = self.waiters.lock();
+                if condition() {
+                    return;
+                }
+                waiters.push(current);
+            }
+            thread::park();
+            self.remove(current);
+        }
+    }
+
+    /// Parks the running thread until it is notified. It can also wake up spuriously.
+    pub fn wait(&self) {
+        let current = thread::current();
+        self.enqueue(current);
+        thread::park();
+        self.remove(current);
+    }
+
+    /// Wakes the thread that waits the longest. Returns false if none waits.
+    pub fn notify_one(&self) -> bool {
+        let waiter = {
+            let mut waiters = self.waiters.lock();
+            if waiters.is_empty() {
+                None
+            } else {
+                Some(waiters.remove(0))
+            }
+        };
+        match waiter {
+            Some(waiter) => {
+                thread::unpark(waiter);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes all waiting threads and returns how many there were.
+    pub fn notify_all(&self) -> usize {
+        let waiters = mem::replace(&mut *self.waiters.lock(), Vec::new());
+        for &waiter in &waiters {
+            thread::unpark(waiter);
+        }
+        waiters.len()
+    }
+
+    /// Adds `id` to the waiters, to be parked after the queue was unlocked.
+    pub(super) fn enqueue(&self, id: ThreadId) {
+        self.waiters.lock().push(id);
+    }
+
+    /// Removes `id` if it woke up without being notified, so that no notification
+    /// is spent on a thread that doesn't wait anymore.
+    pub(super) fn remove(&self, id: ThreadId) {
+        self.waiters.lock().retain(|&waiter| waiter != id);
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        WaitQueue::new()
+    }
+}
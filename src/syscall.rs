@@ -0,0 +1,479 @@
+//! Ring 3 entry and system calls.
+//!
+//! Threads enter ring 3 through `thread::spawn_user` and come back with the
+//! `syscall` instruction, or with `int 0x80` where `syscall` is not available. Both
+//! entries switch to the kernel stack of the running thread and GS base of the
+//! processor, save the registers of ring 3 in a `SyscallFrame` and call the handler
+//! of the system call with interrupts enabled. The system call number is passed in
+//! `rax`, up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, and the
+//! result is returned in `rax`. An error is returned as the negated `SyscallError`
+//! code, all other registers but `rcx` and `r11` are preserved.
+//!
+//! Ring 3 runs with the GS base 0 and the processor data in the kernel GS base, which
+//! is swapped with `swapgs` whenever the kernel is entered from or left to ring 3.
+//! Pointers passed by ring 3 are checked against the address space of the thread
+//! before they are used, see `user`.
+
+use crate::gdt;
+use crate::process::{self, file::FileTable, Pid, ProcessError};
+use crate::thread;
+use crate::time::{self, Instant};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+use x86_64::registers::model_specific::Msr;
+use x86_64::VirtAddr;
+
+pub mod user;
+
+/// Ends the running thread, or the process it belongs to with the status `rdi`.
+pub const EXIT: u64 = 0;
+/// Writes the bytes `rsi..rsi + rdx` to the file descriptor `rdi`, returns the
+/// number of bytes written.
+pub const WRITE: u64 = 1;
+/// Lets the other ready threads run.
+pub const YIELD: u64 = 2;
+/// Returns the id of the running thread.
+pub const THREAD_ID: u64 = 3;
+/// Sleeps for `rdi` milliseconds.
+pub const SLEEP: u64 = 4;
+/// Returns the nanoseconds since boot.
+pub const CLOCK: u64 = 5;
+/// Creates a child process with a copy of the address space and file descriptors of
+/// the running one. Returns the id of the child in the parent and 0 in the child.
+pub const FORK: u64 = 6;
+/// Replaces the program of the running thread by the registered program named by
+/// the string `rdi`, with the arguments `rsi` and the environment `rdx`. Strings
+/// end with a null byte, the arguments and environment are arrays of string
+/// pointers ending with a null pointer, a null array is empty. Only returns on
+/// failure.
+pub const EXEC: u64 = 7;
+/// Waits until the child process `rdi` exited, or any child if it is `u64::MAX`,
+/// and reaps it. Stores its exit status as `i32` at `rsi` unless it is null, returns
+/// the id of the child.
+pub const WAIT: u64 = 8;
+/// Returns the id of the running process.
+pub const PROCESS_ID: u64 = 9;
+/// Closes the file descriptor `rdi`.
+pub const CLOSE: u64 = 10;
+/// Opens the file of the file descriptor `rdi` under the lowest free one, which is
+/// returned.
+pub const DUP: u64 = 11;
+
+/// The vector of the `int 0x80` entry.
+pub const INTERRUPT_VECTOR: u8 = 0x80;
+
+/// The file descriptors open in new processes, both print to the screen and serial
+/// port. Threads without a process write to them as well.
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// The `WAIT` argument to wait for any child.
+pub const ANY_CHILD: u64 = u64::max_value();
+
+/// Largest buffer a single system call copies from or to ring 3.
+pub const MAX_COPY: u64 = 1 << 20;
+/// Longest string passed to a system call, without the null byte.
+pub const MAX_STRING: u64 = 4095;
+/// Most arguments or environment variables passed to `EXEC`.
+pub const MAX_ARGS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    /// The system call number is unknown.
+    NoSys = 1,
+    /// A pointer argument points to memory ring 3 can't access.
+    Fault = 2,
+    /// An argument is out of range.
+    Invalid = 3,
+    /// The file descriptor is not open.
+    BadFd = 4,
+    /// There is no child process to wait for.
+    NoChild = 5,
+    /// No program is registered under the name.
+    NotFound = 6,
+    /// The program is no executable the loader supports.
+    NoExec = 7,
+    /// Out of memory.
+    NoMemory = 8,
+}
+
+impl SyscallError {
+    /// Returns the error `rax` holds after a system call, if any.
+    pub fn from_return(value: u64) -> Option<Self> {
+        match (value as i64).wrapping_neg() {
+            1 => Some(SyscallError::NoSys),
+            2 => Some(SyscallError::Fault),
+            3 => Some(SyscallError::Invalid),
+            4 => Some(SyscallError::BadFd),
+            5 => Some(SyscallError::NoChild),
+            6 => Some(SyscallError::NotFound),
+            7 => Some(SyscallError::NoExec),
+            8 => Some(SyscallError::NoMemory),
+            _ => None,
+        }
+    }
+
+    fn to_return(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+}
+
+impl From<ProcessError> for SyscallError {
+    fn from(err: ProcessError) -> Self {
+        match err {
+            ProcessError::NoProcess => SyscallError::Invalid,
+            ProcessError::NoChild => SyscallError::NoChild,
+            ProcessError::NotFound => SyscallError::NotFound,
+            ProcessError::Elf(_) => SyscallError::NoExec,
+            ProcessError::Map(_) | ProcessError::Stack(_) => SyscallError::NoMemory,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+type Handler = fn(&mut SyscallFrame) -> SyscallResult;
+
+/// The handlers indexed by system call number.
+static TABLE: [Handler; 12] = [
+    exit, write, yield_now, thread_id, sleep, clock, fork, exec, wait, process_id, close, dup,
+];
+
+const IA32_EFER: u32 = 0xC000_0080;
+const IA32_STAR: u32 = 0xC000_0081;
+const IA32_LSTAR: u32 = 0xC000_0082;
+const IA32_FMASK: u32 = 0xC000_0084;
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+const EFER_SYSTEM_CALL_EXTENSIONS: u64 = 1;
+/// The flags cleared on `syscall`: trap, interrupt enable, direction and alignment
+/// check.
+const SYSCALL_FLAG_MASK: u64 = 0x100 | 0x200 | 0x400 | 0x4_0000;
+/// The flags ring 3 starts with: interrupts enabled and the reserved bit 1.
+const USER_FLAGS: u64 = 0x202;
+
+global_asm!(
+    r#"
+    .global syscall_entry
+    .global syscall_interrupt_entry
+    .global syscall_return_user
+
+    // Entered by `syscall` with the return address in `rcx`, the flags in `r11` and
+    // the stack of ring 3. The stack pointer of ring 3 is parked in the processor
+    // data until it is pushed on the kernel stack, as part of a frame like the one
+    // of an interrupt from ring 3 with the selectors of `gdt`.
+syscall_entry:
+    swapgs
+    mov %rsp, %gs:16
+    mov %gs:8, %rsp
+    pushq $0x1b
+    pushq %gs:16
+    push %r11
+    pushq $0x23
+    push %rcx
+    push %r15
+    push %r14
+    push %r13
+    push %r12
+    push %rbp
+    push %rbx
+    push %r9
+    push %r8
+    push %r10
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rax
+    mov %rsp, %rdi
+    sti
+    call syscall_handler
+    cli
+    pop %rax
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %r10
+    pop %r8
+    pop %r9
+    pop %rbx
+    pop %rbp
+    pop %r12
+    pop %r13
+    pop %r14
+    pop %r15
+    pop %rcx
+    add $8, %rsp
+    pop %r11
+    pop %rsp
+    swapgs
+    sysretq
+
+    // Entered through an interrupt gate, on the kernel stack if it came from ring 3.
+syscall_interrupt_entry:
+    testb $3, 8(%rsp)
+    jz 1f
+    swapgs
+1:
+    push %r15
+    push %r14
+    push %r13
+    push %r12
+    push %rbp
+    push %rbx
+    push %r9
+    push %r8
+    push %r10
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rax
+    mov %rsp, %rdi
+    sti
+    call syscall_handler
+    cli
+    pop %rax
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %r10
+    pop %r8
+    pop %r9
+    pop %rbx
+    pop %rbp
+    pop %r12
+    pop %r13
+    pop %r14
+    pop %r15
+    testb $3, 8(%rsp)
+    jz 2f
+    swapgs
+2:
+    iretq
+
+    // Called with a `SyscallFrame` of ring 3 in `rdi`, which becomes the stack. No
+    // kernel values are left in registers.
+syscall_return_user:
+    cli
+    mov %rdi, %rsp
+    pop %rax
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %r10
+    pop %r8
+    pop %r9
+    pop %rbx
+    pop %rbp
+    pop %r12
+    pop %r13
+    pop %r14
+    pop %r15
+    xor %ecx, %ecx
+    xor %r11d, %r11d
+    swapgs
+    iretq
+    "#
+);
+
+extern "C" {
+    fn syscall_entry();
+    fn syscall_interrupt_entry();
+    fn syscall_return_user(frame: *const SyscallFrame) -> !;
+}
+
+/// The registers of ring 3 saved by the entries, in the order they are pushed.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SyscallFrame {
+    /// The system call number, replaced by the result.
+    rax: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    r10: u64,
+    r8: u64,
+    r9: u64,
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+impl SyscallFrame {
+    /// Returns the frame of a program that starts at `entry` with the stack pointer
+    /// `stack_pointer`, interrupts enabled and all other registers zeroed.
+    fn new(entry: VirtAddr, stack_pointer: VirtAddr) -> Self {
+        SyscallFrame {
+            rip: entry.as_u64(),
+            cs: u64::from(gdt::USER_CODE_SELECTOR.0),
+            rflags: USER_FLAGS,
+            rsp: stack_pointer.as_u64(),
+            ss: u64::from(gdt::USER_DATA_SELECTOR.0),
+            ..SyscallFrame::default()
+        }
+    }
+
+    /// Returns the frame of a forked thread, which sees the system call return 0.
+    pub(crate) fn fork(&self) -> Self {
+        SyscallFrame {
+            rax: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Makes the system call return to a new program instead, see `new`.
+    pub(crate) fn reset(&mut self, entry: VirtAddr, stack_pointer: VirtAddr) {
+        *self = SyscallFrame::new(entry, stack_pointer);
+    }
+}
+
+/// Enables `syscall` on the executing processor and points it to the entry.
+///
+/// Must be called on every processor after `gdt::init`.
+pub fn init() {
+    // `sysret` loads the user data segment from the STAR base plus 8 and the user
+    // code segment from the base plus 16
+    let kernel_base = u64::from(gdt::KERNEL_CODE_SELECTOR.0);
+    let user_base = u64::from(gdt::USER_DATA_SELECTOR.0) - 8;
+    debug_assert_eq!(u64::from(gdt::USER_CODE_SELECTOR.0), user_base + 16);
+    // pushed by `syscall_entry`
+    debug_assert_eq!(gdt::USER_DATA_SELECTOR.0, 0x1b);
+    debug_assert_eq!(gdt::USER_CODE_SELECTOR.0, 0x23);
+    unsafe {
+        let mut efer = Msr::new(IA32_EFER);
+        let value = efer.read();
+        efer.write(value | EFER_SYSTEM_CALL_EXTENSIONS);
+        Msr::new(IA32_STAR).write((user_base << 48) | (kernel_base << 32));
+        Msr::new(IA32_LSTAR).write(syscall_entry as usize as u64);
+        Msr::new(IA32_FMASK).write(SYSCALL_FLAG_MASK);
+        // the GS base of ring 3 while the kernel runs
+        Msr::new(IA32_KERNEL_GS_BASE).write(0);
+    }
+}
+
+/// Returns the address of the `int 0x80` entry, which is installed with privilege
+/// level 3 so that ring 3 can raise it.
+pub(crate) fn interrupt_entry() -> u64 {
+    syscall_interrupt_entry as usize as u64
+}
+
+/// Continues the running thread in ring 3 at `entry` with the stack `stack_top` and
+/// interrupts enabled.
+///
+/// This function is unsafe because the address space of the thread must map `entry`
+/// and the stack for ring 3.
+pub unsafe fn enter_user(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    resume_user(&SyscallFrame::new(entry, stack_top))
+}
+
+/// Continues the running thread in ring 3 with the registers of `frame`.
+///
+/// This function is unsafe for the same reasons as `enter_user`.
+pub(crate) unsafe fn resume_user(frame: &SyscallFrame) -> ! {
+    syscall_return_user(frame)
+}
+
+#[no_mangle]
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    frame.rax = match dispatch(frame) {
+        Ok(value) => value,
+        Err(err) => err.to_return(),
+    };
+}
+
+/// Runs the handler of the system call `frame.rax`.
+fn dispatch(frame: &mut SyscallFrame) -> SyscallResult {
+    let handler = TABLE.get(frame.rax as usize).ok_or(SyscallError::NoSys)?;
+    handler(frame)
+}
+
+fn exit(frame: &mut SyscallFrame) -> SyscallResult {
+    process::exit(frame.rdi as i32);
+}
+
+fn write(frame: &mut SyscallFrame) -> SyscallResult {
+    let (fd, addr, len) = (frame.rdi, frame.rsi, frame.rdx);
+    let file = match process::with_files(|files| files.get(fd).cloned()) {
+        Ok(file) => file,
+        Err(_) => FileTable::new().get(fd).cloned(),
+    };
+    let file = file.ok_or(SyscallError::BadFd)?;
+    let bytes = user::copy_from_user(addr, len)?;
+    Ok(file.write(&bytes) as u64)
+}
+
+fn yield_now(_frame: &mut SyscallFrame) -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+fn thread_id(_frame: &mut SyscallFrame) -> SyscallResult {
+    Ok(thread::current().as_u64())
+}
+
+fn sleep(frame: &mut SyscallFrame) -> SyscallResult {
+    time::sleep(Duration::from_millis(frame.rdi));
+    Ok(0)
+}
+
+fn clock(_frame: &mut SyscallFrame) -> SyscallResult {
+    Ok(Instant::now().since_boot().as_nanos() as u64)
+}
+
+fn fork(frame: &mut SyscallFrame) -> SyscallResult {
+    Ok(process::fork(frame)?.as_u64())
+}
+
+fn exec(frame: &mut SyscallFrame) -> SyscallResult {
+    let (name, args, env) = (frame.rdi, frame.rsi, frame.rdx);
+    let name = user::copy_string_from_user(name)?;
+    let args = user::copy_strings_from_user(args)?;
+    let env = user::copy_strings_from_user(env)?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let env: Vec<&str> = env.iter().map(String::as_str).collect();
+    process::exec(frame, &name, &args, &env)?;
+    Ok(0)
+}
+
+fn wait(frame: &mut SyscallFrame) -> SyscallResult {
+    let (pid, status_addr) = (frame.rdi, frame.rsi);
+    let pid = match pid {
+        ANY_CHILD => None,
+        pid => Some(Pid::from_u64(pid)),
+    };
+    let (pid, status) = process::wait_child(pid)?;
+    if status_addr != 0 {
+        user::copy_to_user(status_addr, &status.to_ne_bytes())?;
+    }
+    Ok(pid.as_u64())
+}
+
+fn process_id(_frame: &mut SyscallFrame) -> SyscallResult {
+    let pid = process::current().ok_or(ProcessError::NoProcess)?;
+    Ok(pid.as_u64())
+}
+
+fn close(frame: &mut SyscallFrame) -> SyscallResult {
+    let fd = frame.rdi;
+    let file = process::with_files(|files| files.close(fd))?.ok_or(SyscallError::BadFd)?;
+    // closed once the process table is unlocked
+    drop(file);
+    Ok(0)
+}
+
+fn dup(frame: &mut SyscallFrame) -> SyscallResult {
+    let fd = frame.rdi;
+    process::with_files(|files| {
+        let file = files.get(fd).cloned().ok_or(SyscallError::BadFd)?;
+        let new_fd = files.open(file).ok_or(SyscallError::Invalid)?;
+        Ok(new_fd)
+    })?
+}
@@ -0,0 +1,146 @@
+//! Access to memory passed by ring 3.
+//!
+//! A buffer is only used if every page of it is mapped for ring 3 in the address
+//! space of the running thread, and writable if the kernel writes to it. The address
+//! space stays locked while the buffer is copied, so the pages can't be unmapped by
+//! another thread in between. Copy-on-write pages get their own frame before the
+//! kernel writes to them.
+
+use super::{SyscallError, MAX_ARGS, MAX_COPY, MAX_STRING};
+use crate::memory::address_space::{AddressSpace, USER_END, USER_START};
+use crate::thread;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr::{self, NonNull};
+use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Copies `len` bytes starting at `addr` out of ring 3.
+pub fn copy_from_user(addr: u64, len: u64) -> Result<Vec<u8>, SyscallError> {
+    with_user_buffer(addr, len, false, |ptr| {
+        let mut bytes = Vec::with_capacity(len as usize);
+        unsafe {
+            ptr::copy_nonoverlapping(ptr, bytes.as_mut_ptr(), len as usize);
+            bytes.set_len(len as usize);
+        }
+        bytes
+    })
+}
+
+/// Copies `bytes` into ring 3 at `addr`.
+pub fn copy_to_user(addr: u64, bytes: &[u8]) -> Result<(), SyscallError> {
+    with_user_buffer(addr, bytes.len() as u64, true, |ptr| unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+    })
+}
+
+/// Reads the `u64` at `addr` of ring 3.
+pub fn read_u64(addr: u64) -> Result<u64, SyscallError> {
+    let bytes = copy_from_user(addr, 8)?;
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes);
+    Ok(u64::from_ne_bytes(value))
+}
+
+/// Writes `value` to `addr` of ring 3.
+pub fn write_u64(addr: u64, value: u64) -> Result<(), SyscallError> {
+    copy_to_user(addr, &value.to_ne_bytes())
+}
+
+/// Copies the string at `addr` out of ring 3, which ends with a null byte and must
+/// be valid UTF-8.
+pub fn copy_string_from_user(addr: u64) -> Result<String, SyscallError> {
+    let mut bytes = Vec::new();
+    loop {
+        // up to the end of the page, which may be followed by an unmapped one
+        let next = addr
+            .checked_add(bytes.len() as u64)
+            .ok_or(SyscallError::Fault)?;
+        let len = (4096 - next % 4096).min(MAX_STRING + 1 - bytes.len() as u64);
+        if len == 0 {
+            return Err(SyscallError::Invalid);
+        }
+        let chunk = copy_from_user(next, len)?;
+        match chunk.iter().position(|&byte| byte == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&chunk[..end]);
+                return String::from_utf8(bytes).map_err(|_| SyscallError::Invalid);
+            }
+            None => bytes.extend_from_slice(&chunk),
+        }
+    }
+}
+
+/// Copies the strings of the array of string pointers at `addr` out of ring 3. The
+/// array ends with a null pointer, a null `addr` is an empty array.
+pub fn copy_strings_from_user(addr: u64) -> Result<Vec<String>, SyscallError> {
+    let mut strings = Vec::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+    loop {
+        let ptr_addr = addr
+            .checked_add(8 * strings.len() as u64)
+            .ok_or(SyscallError::Fault)?;
+        let ptr = read_u64(ptr_addr)?;
+        if ptr == 0 {
+            return Ok(strings);
+        }
+        if strings.len() == MAX_ARGS {
+            return Err(SyscallError::Invalid);
+        }
+        strings.push(copy_string_from_user(ptr)?);
+    }
+}
+
+/// Returns whether ring 3 may access `len` bytes starting at `addr` in `space`, and
+/// write them if `write` is set.
+pub fn is_accessible(space: &AddressSpace, addr: u64, len: u64, write: bool) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    if len == 0 {
+        return true;
+    }
+    if addr < USER_START || end > USER_END {
+        return false;
+    }
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    Page::range_inclusive(first, last).all(|page| match space.protection(page.start_address()) {
+        Some(protection) => protection.user && (protection.writable || !write),
+        None => false,
+    })
+}
+
+/// Calls `f` with the checked buffer of `len` bytes at `addr`, which is in the loaded
+/// address space.
+fn with_user_buffer<F, T>(addr: u64, len: u64, write: bool, f: F) -> Result<T, SyscallError>
+where
+    F: FnOnce(*mut u8) -> T,
+{
+    if len > MAX_COPY {
+        return Err(SyscallError::Invalid);
+    }
+    let space = thread::address_space().ok_or(SyscallError::Fault)?;
+    let mut space = space.lock();
+    if !is_accessible(&space, addr, len, write) {
+        return Err(SyscallError::Fault);
+    }
+    if len == 0 {
+        // not dereferenced, but copies need a non-null pointer
+        return Ok(f(NonNull::dangling().as_ptr()));
+    }
+    if write {
+        // a fault would need the address space, which is locked
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let last = Page::<Size4KiB>::containing_address(VirtAddr::new(addr + len - 1));
+        for page in Page::range_inclusive(first, last) {
+            space
+                .handle_write_fault(page.start_address())
+                .map_err(|_| SyscallError::NoMemory)?;
+        }
+    }
+    Ok(f(addr as *mut u8))
+}
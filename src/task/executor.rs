@@ -0,0 +1,134 @@
+use super::{Task, TaskId};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::{waker_ref, ArcWake};
+use x86_64::instructions::interrupts;
+
+/// Maximum number of tasks. Every task is queued at most once, so the queue never
+/// overflows.
+const TASK_QUEUE_SIZE: usize = 100;
+
+/// Polls tasks whenever they are woken and halts the CPU while none is ready.
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    /// Ids of the woken tasks, filled by wakers that may run in interrupt handlers.
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    /// Spawns a task, which is polled by the next `run_ready_tasks`.
+    ///
+    /// Panics if the executor already has `TASK_QUEUE_SIZE` tasks.
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        assert!(self.tasks.len() < TASK_QUEUE_SIZE, "too many tasks");
+        if self.tasks.insert(task_id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        let waker = TaskWaker::new(task_id, self.task_queue.clone());
+        self.waker_cache.insert(task_id, waker.clone());
+        ArcWake::wake_by_ref(&waker);
+    }
+
+    /// Runs the tasks forever.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Polls the woken tasks until none of them is ready anymore.
+    pub fn run_ready_tasks(&mut self) {
+        let Self {
+            tasks,
+            task_queue,
+            waker_cache,
+        } = self;
+
+        while let Ok(task_id) = task_queue.pop() {
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                // the task already finished
+                None => continue,
+            };
+            let task_waker = &waker_cache[&task_id];
+            // wake-ups while the task is polled queue it again
+            task_waker.woken.store(false, Ordering::SeqCst);
+            let poll = {
+                let waker = waker_ref(task_waker);
+                task.poll(&mut Context::from_waker(&waker))
+            };
+            match poll {
+                Poll::Ready(()) => {
+                    tasks.remove(&task_id);
+                    // wakers of the finished task that are still around never queue it
+                    let task_waker = waker_cache.remove(&task_id).unwrap();
+                    task_waker.woken.store(true, Ordering::SeqCst);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    /// Returns the number of tasks that did not finish yet.
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    fn sleep_if_idle(&self) {
+        // an interrupt between the check and `hlt` would not wake the CPU
+        interrupts::disable();
+        if self.task_queue.is_empty() {
+            // `sti` only takes effect after the next instruction, so no interrupt is
+            // taken before `hlt`
+            unsafe { asm!("sti; hlt" :::: "volatile") };
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor::new()
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    /// Set while the task is in the queue.
+    woken: AtomicBool,
+}
+
+impl TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+            woken: AtomicBool::new(false),
+        })
+    }
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        if !arc_self.woken.swap(true, Ordering::SeqCst) {
+            // can't fail, the queue has room for every task
+            let _ = arc_self.task_queue.push(arc_self.task_id);
+        }
+    }
+}
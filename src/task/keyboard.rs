@@ -0,0 +1,119 @@
+//! Scancodes from the keyboard interrupt as an asynchronous stream.
+
+use crate::print;
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
+
+/// Maximum number of scancodes waiting to be processed.
+const SCANCODE_QUEUE_SIZE: usize = 100;
+
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Called by the keyboard interrupt handler, so it must not block or allocate.
+///
+/// Scancodes are dropped while no `ScancodeStream` was created.
+pub(crate) fn add_scancode(scancode: u8) {
+    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
+        // scancodes are dropped while the queue is full, printing a warning could
+        // deadlock on the writer lock
+        if queue.push(scancode).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
+
+/// The scancodes read by the keyboard interrupt handler.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    /// Creates the stream. There can only be one, since every scancode is only
+    /// yielded once.
+    pub fn new() -> Self {
+        SCANCODE_QUEUE
+            .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE))
+            .expect("ScancodeStream::new should only be called once");
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        ScancodeStream::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE.try_get().expect("not initialized");
+
+        // fast path
+        if let Ok(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        // register before checking again, so a scancode added in between wakes us
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Ok(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+/// Decodes the scancodes and prints the pressed keys.
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1);
+
+    while let Some(scancode) = scancodes.next().await {
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            if let Some(key) = keyboard.process_keyevent(key_event) {
+                match key {
+                    DecodedKey::Unicode(character) => print!("{}", character),
+                    DecodedKey::RawKey(key) => print!("{:?}", key),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_scancode_stream() {
+    serial_print!("test_scancode_stream... ");
+    use futures_util::task::noop_waker_ref;
+
+    // dropped, nobody listens yet
+    add_scancode(0x01);
+    let mut stream = ScancodeStream::new();
+    let mut cx = Context::from_waker(noop_waker_ref());
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+
+    add_scancode(0x1e);
+    add_scancode(0x9e);
+    assert_eq!(
+        Pin::new(&mut stream).poll_next(&mut cx),
+        Poll::Ready(Some(0x1e))
+    );
+    assert_eq!(
+        Pin::new(&mut stream).poll_next(&mut cx),
+        Poll::Ready(Some(0x9e))
+    );
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,86 @@
+//! Packets from the mouse interrupt as an asynchronous stream.
+
+use crate::mouse::{Packet, MOUSE};
+use crate::println;
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+
+/// Maximum number of packets waiting to be processed.
+const PACKET_QUEUE_SIZE: usize = 100;
+
+static PACKET_QUEUE: OnceCell<ArrayQueue<Packet>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Called by the mouse interrupt handler, so it must not block or allocate.
+///
+/// Packets are dropped while no `PacketStream` was created.
+pub(crate) fn add_packet(packet: Packet) {
+    if let Ok(queue) = PACKET_QUEUE.try_get() {
+        // packets are dropped while the queue is full, printing a warning could
+        // deadlock on the writer lock
+        if queue.push(packet).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
+
+/// The packets assembled by the mouse interrupt handler.
+pub struct PacketStream {
+    _private: (),
+}
+
+impl PacketStream {
+    /// Creates the stream. There can only be one, since every packet is only
+    /// yielded once.
+    pub fn new() -> Self {
+        PACKET_QUEUE
+            .try_init_once(|| ArrayQueue::new(PACKET_QUEUE_SIZE))
+            .expect("PacketStream::new should only be called once");
+        PacketStream { _private: () }
+    }
+}
+
+impl Default for PacketStream {
+    fn default() -> Self {
+        PacketStream::new()
+    }
+}
+
+impl Stream for PacketStream {
+    type Item = Packet;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Packet>> {
+        let queue = PACKET_QUEUE.try_get().expect("not initialized");
+
+        // fast path
+        if let Ok(packet) = queue.pop() {
+            return Poll::Ready(Some(packet));
+        }
+
+        // register before checking again, so a packet added in between wakes us
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Ok(packet) => {
+                WAKER.take();
+                Poll::Ready(Some(packet))
+            }
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+/// Updates the position and buttons of `MOUSE` and runs the button handlers.
+pub async fn process_packets() {
+    let mut packets = PacketStream::new();
+
+    while let Some(packet) = packets.next().await {
+        let mut mouse = MOUSE.lock();
+        mouse.process_packet(&packet);
+        let (x, y) = mouse.coordinates();
+        println!("x {} y {}", x, y);
+    }
+}
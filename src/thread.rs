@@ -0,0 +1,585 @@
+//! Preemptive kernel threads.
+//!
+//! Every thread runs on its own kernel stack from `memory::stack`, the flow of control
+//! the kernel booted with becomes the first thread and keeps the boot stack. Ready
+//! threads run round-robin: the running thread is switched out when it yields,
+//! blocks or exits, and by the timer interrupt once its time slice is used up. An
+//! exited thread is freed when it is joined, or by a later spawn if it was detached.
+//!
+//! Preemption happens after the end of interrupt was signalled, so the interrupt
+//! controller doesn't hold back interrupts until the preempted thread runs again.
+//! Locks shared with interrupt handlers are only taken with interrupts disabled, so a
+//! thread is never preempted while holding one of them.
+//!
+//! The ready threads are shared by all processors. Every processor has an idle thread,
+//! which runs when no other thread is ready and is never queued. A thread that becomes
+//! ready wakes an idle processor with a reschedule interrupt. The scheduler lock is
+//! handed over by a switch: the thread switching out takes it, the thread switched in
+//! releases it, so no other processor switches to a thread before its registers are
+//! saved.
+//!
+//! A user thread has an address space, which is loaded while it runs, and enters ring
+//! 3 from its kernel stack. Kernel threads run with the kernel page table.
+
+use crate::interrupts::irq;
+use crate::memory::address_space::AddressSpace;
+use crate::memory::kernel_level_4_frame;
+use crate::memory::stack::{self, KernelStack, StackError};
+use crate::smp::{ipi, percpu};
+use crate::sync::{self, lock_order};
+use crate::{gdt, syscall};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::{mem, ptr};
+use lazy_static::lazy_static;
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+/// Size of the stack of a spawned thread in pages.
+pub const THREAD_STACK_PAGES: u64 = stack::DEFAULT_STACK_PAGES;
+/// Timer ticks a thread runs before it is preempted.
+pub const TIME_SLICE_TICKS: u64 = 10;
+
+lazy_static! {
+    /// Must only be locked with interrupts disabled.
+    static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+}
+
+global_asm!(
+    r#"
+    .global thread_switch_context
+    // Saves the callee-saved registers on the current stack, stores the stack
+    // pointer at `rdi`, switches to the stack `rsi` and restores its registers.
+    thread_switch_context:
+        push %rbp
+        push %rbx
+        push %r12
+        push %r13
+        push %r14
+        push %r15
+        mov %rsp, (%rdi)
+        mov %rsi, %rsp
+        pop %r15
+        pop %r14
+        pop %r13
+        pop %r12
+        pop %rbx
+        pop %rbp
+        ret
+    "#
+);
+
+extern "C" {
+    fn thread_switch_context(old_stack_pointer: *mut u64, new_stack_pointer: u64);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        // the boot thread is 0
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// Waiting to be switched in.
+    Ready,
+    Running,
+    /// Parked until another thread or an interrupt handler unparks it.
+    Blocked,
+    /// Finished, waiting to be joined.
+    Exited,
+}
+
+/// The address space of a user thread, shared with the system calls it makes.
+pub type SharedAddressSpace = Arc<sync::Mutex<AddressSpace>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The thread doesn't exist or was already joined.
+    NotFound,
+    /// A thread can't wait for itself.
+    JoinSelf,
+    /// Another thread is already waiting for the thread.
+    AlreadyJoined,
+}
+
+struct Thread {
+    state: ThreadState,
+    /// The saved stack pointer while the thread is switched out.
+    stack_pointer: u64,
+    /// The stack of the thread, `None` for the boot thread and after it was freed.
+    stack: Option<KernelStack>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// The address space of a user thread, `None` for kernel threads.
+    address_space: Option<SharedAddressSpace>,
+    /// The level 4 table loaded while the thread runs.
+    page_table: PhysFrame,
+    /// Set by `unpark` while the thread was not blocked, consumed by the next `park`.
+    unparked: bool,
+    /// The thread waiting in `join` for this one.
+    joiner: Option<ThreadId>,
+    /// Set by `detach`, the thread is freed once it exited instead of joined.
+    detached: bool,
+    /// The levels of the locks held while the thread is switched out.
+    held_locks: u64,
+}
+
+struct Scheduler {
+    /// Boxed, so the saved stack pointers don't move while threads are switched.
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: VecDeque<ThreadId>,
+    /// The processors running their idle thread.
+    idle_cpus: u64,
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        let boot_thread = Thread {
+            state: ThreadState::Running,
+            stack_pointer: 0,
+            stack: None,
+            entry: None,
+            address_space: None,
+            page_table: kernel_level_4_frame(),
+            unparked: false,
+            joiner: None,
+            detached: false,
+            held_locks: 0,
+        };
+        let mut threads = BTreeMap::new();
+        threads.insert(ThreadId(0), Box::new(boot_thread));
+        Scheduler {
+            threads,
+            ready: VecDeque::new(),
+            idle_cpus: 0,
+        }
+    }
+
+    fn thread_mut(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("thread does not exist")
+    }
+
+    /// Queues the thread if it is blocked, otherwise the next `park` returns at once.
+    fn unpark(&mut self, id: ThreadId) {
+        let thread = match self.threads.get_mut(&id) {
+            Some(thread) => thread,
+            None => return,
+        };
+        match thread.state {
+            ThreadState::Blocked => {
+                thread.state = ThreadState::Ready;
+                self.make_ready(id);
+            }
+            ThreadState::Ready | ThreadState::Running => thread.unparked = true,
+            ThreadState::Exited => {}
+        }
+    }
+
+    /// Queues the thread and wakes an idle processor to run it.
+    fn make_ready(&mut self, id: ThreadId) {
+        self.ready.push_back(id);
+        self.wake_idle_cpu();
+    }
+
+    fn wake_idle_cpu(&self) {
+        if self.idle_cpus == 0 {
+            return;
+        }
+        let index = self.idle_cpus.trailing_zeros() as usize;
+        let cpu = match percpu::cpu(index) {
+            Some(cpu) => cpu,
+            None => return,
+        };
+        if cpu.index() == percpu::current().index() {
+            // an interrupt handler of the idle thread made the thread ready
+            cpu.reschedule.store(true, Ordering::Relaxed);
+        } else {
+            ipi::send_reschedule(cpu);
+        }
+    }
+}
+
+/// Makes the running flow of control the first thread, creates the idle thread of
+/// the bootstrap processor and starts preempting threads from the timer interrupts.
+pub fn init() {
+    lazy_static::initialize(&SCHEDULER);
+    let idle = new_thread(Box::new(idle_loop)).expect("failed to create idle thread");
+    let id = ThreadId::new();
+    without_interrupts(|| SCHEDULER.lock().threads.insert(id, idle));
+    percpu::current().idle_thread.store(id.0, Ordering::SeqCst);
+
+    irq::register_irq(irq::TIMER, tick).expect("timer IRQ is taken");
+    // the local APIC timer preempts the threads of the application processors
+    irq::register_irq(irq::LAPIC_TIMER, tick).expect("local APIC timer IRQ is taken");
+}
+
+/// Makes the flow of control of a freshly started application processor its idle
+/// thread, which runs on `stack`.
+pub(crate) fn init_ap(stack: KernelStack) {
+    let id = ThreadId::new();
+    let thread = Box::new(Thread {
+        state: ThreadState::Running,
+        stack_pointer: 0,
+        stack: Some(stack),
+        entry: None,
+        address_space: None,
+        page_table: kernel_level_4_frame(),
+        unparked: false,
+        joiner: None,
+        detached: false,
+        held_locks: 0,
+    });
+    let cpu = percpu::current();
+    cpu.current_thread.store(id.0, Ordering::SeqCst);
+    cpu.idle_thread.store(id.0, Ordering::SeqCst);
+    let mut scheduler = SCHEDULER.lock();
+    scheduler.threads.insert(id, thread);
+    scheduler.idle_cpus |= cpu.mask();
+}
+
+/// Halts until an interrupt makes a thread ready, forever.
+pub(crate) fn idle() -> ! {
+    loop {
+        // the reschedule interrupt switches to the ready thread when it returns
+        unsafe { asm!("sti; hlt" :::: "volatile") };
+    }
+}
+
+fn idle_loop() {
+    idle();
+}
+
+fn tick(_irq: u8) {
+    let cpu = percpu::current();
+    if cpu.slice_ticks.fetch_add(1, Ordering::Relaxed) + 1 >= TIME_SLICE_TICKS {
+        cpu.reschedule.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Switches to the next ready thread if the time slice of the running one is used
+/// up or an idle processor was woken. Called by the interrupt stubs after the end of
+/// interrupt was signalled.
+pub(crate) fn preempt_if_requested() {
+    if percpu::current().reschedule.swap(false, Ordering::Relaxed) {
+        switch(SCHEDULER.lock(), ThreadState::Ready);
+    }
+}
+
+/// Starts a thread running `entry`. The thread exits when `entry` returns.
+pub fn spawn(entry: fn()) -> Result<ThreadId, StackError> {
+    free_exited_threads();
+
+    let thread = new_thread(Box::new(entry))?;
+    Ok(start(thread))
+}
+
+/// Starts a thread that runs in ring 3 at `entry` with the stack `stack_top`, both
+/// mapped for ring 3 in `address_space`. The thread exits with the `EXIT` system call
+/// or when it raises an exception.
+pub fn spawn_user(
+    address_space: AddressSpace,
+    entry: VirtAddr,
+    stack_top: VirtAddr,
+) -> Result<ThreadId, StackError> {
+    spawn_in(
+        address_space,
+        Box::new(move || unsafe { syscall::enter_user(entry, stack_top) }),
+    )
+}
+
+/// Starts a thread running `entry` with `address_space` loaded, which enters ring 3.
+pub(crate) fn spawn_in(
+    address_space: AddressSpace,
+    entry: Box<dyn FnOnce() + Send>,
+) -> Result<ThreadId, StackError> {
+    free_exited_threads();
+
+    let page_table = address_space.level_4_frame();
+    let mut thread = new_thread(entry)?;
+    thread.page_table = page_table;
+    thread.address_space = Some(share(address_space));
+    Ok(start(thread))
+}
+
+fn share(address_space: AddressSpace) -> SharedAddressSpace {
+    Arc::new(sync::Mutex::with_level(
+        address_space,
+        lock_order::ADDRESS_SPACE,
+    ))
+}
+
+/// Makes `thread` ready under a new id.
+fn start(thread: Box<Thread>) -> ThreadId {
+    let id = ThreadId::new();
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.threads.insert(id, thread);
+        scheduler.make_ready(id);
+    });
+    id
+}
+
+/// Creates a ready kernel thread that starts running `entry` when it is switched in.
+fn new_thread(entry: Box<dyn FnOnce() + Send>) -> Result<Box<Thread>, StackError> {
+    let stack = stack::allocate(THREAD_STACK_PAGES)?;
+    // the frame `thread_switch_context` restores: six registers and the return
+    // address, followed by a fake return address of `thread_start` so that the stack
+    // is aligned as after a call
+    let frame = [0, 0, 0, 0, 0, 0, thread_start as usize as u64, 0];
+    let stack_pointer = stack.top().as_u64() - 8 * frame.len() as u64;
+    unsafe { ptr::write(stack_pointer as *mut [u64; 8], frame) };
+
+    Ok(Box::new(Thread {
+        state: ThreadState::Ready,
+        stack_pointer,
+        stack: Some(stack),
+        entry: Some(entry),
+        address_space: None,
+        page_table: kernel_level_4_frame(),
+        unparked: false,
+        joiner: None,
+        detached: false,
+        held_locks: 0,
+    }))
+}
+
+/// The first code a spawned thread runs, entered from `thread_switch_context` with
+/// interrupts disabled and the scheduler lock handed over.
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    let entry = {
+        let mut scheduler = SCHEDULER.lock();
+        let current = current_id();
+        scheduler.thread_mut(current).entry.take()
+    };
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+/// Returns the id of the running thread.
+pub fn current() -> ThreadId {
+    without_interrupts(current_id)
+}
+
+/// Returns the address space of the running thread, `None` for kernel threads.
+pub fn address_space() -> Option<SharedAddressSpace> {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = current_id();
+        scheduler.thread_mut(current).address_space.clone()
+    })
+}
+
+/// Gives the running user thread `address_space` and loads it. Returns the previous
+/// address space, which is no longer loaded.
+pub(crate) fn replace_address_space(address_space: AddressSpace) -> Option<SharedAddressSpace> {
+    let page_table = address_space.level_4_frame();
+    let address_space = share(address_space);
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = current_id();
+        let thread = scheduler.thread_mut(current);
+        thread.page_table = page_table;
+        unsafe { Cr3::write(page_table, Cr3Flags::empty()) };
+        thread.address_space.replace(address_space)
+    })
+}
+
+/// Returns the id of the running thread. Must be called with interrupts disabled.
+fn current_id() -> ThreadId {
+    ThreadId(percpu::current().current_thread.load(Ordering::Relaxed))
+}
+
+/// Returns the state of the thread, `None` if it doesn't exist or was joined.
+pub fn state(id: ThreadId) -> Option<ThreadState> {
+    without_interrupts(|| SCHEDULER.lock().threads.get(&id).map(|thread| thread.state))
+}
+
+/// Lets the other ready threads run before the running thread continues.
+pub fn yield_now() {
+    without_interrupts(|| switch(SCHEDULER.lock(), ThreadState::Ready));
+}
+
+/// Ends the running thread and wakes the thread waiting for it in `join`.
+pub fn exit() -> ! {
+    interrupts::disable();
+    let mut scheduler = SCHEDULER.lock();
+    let current = current_id();
+    if let Some(joiner) = scheduler.thread_mut(current).joiner {
+        scheduler.unpark(joiner);
+    }
+    switch(scheduler, ThreadState::Exited);
+    unreachable!("exited thread was switched in");
+}
+
+/// Blocks the running thread until it is unparked. Returns at once if it was
+/// unparked since the last call.
+pub fn park() {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = current_id();
+        let thread = scheduler.thread_mut(current);
+        if thread.unparked {
+            thread.unparked = false;
+            return;
+        }
+        // blocking under the same lock, so an unpark from another processor is
+        // either seen above or finds the thread blocked
+        switch(scheduler, ThreadState::Blocked);
+    });
+}
+
+/// Makes the thread ready again if it is parked, otherwise its next `park` returns
+/// at once. Can be called from interrupt handlers.
+pub fn unpark(id: ThreadId) {
+    without_interrupts(|| SCHEDULER.lock().unpark(id));
+}
+
+/// Waits until the thread exited and frees it.
+pub fn join(id: ThreadId) -> Result<(), JoinError> {
+    loop {
+        let exited = without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let current = current_id();
+            if id == current {
+                return Err(JoinError::JoinSelf);
+            }
+            let thread = scheduler.threads.get_mut(&id).ok_or(JoinError::NotFound)?;
+            if thread.state == ThreadState::Exited {
+                return Ok(scheduler.threads.remove(&id));
+            }
+            match thread.joiner {
+                Some(joiner) if joiner != current => Err(JoinError::AlreadyJoined),
+                _ => {
+                    thread.joiner = Some(current);
+                    Ok(None)
+                }
+            }
+        })?;
+        if let Some(thread) = exited {
+            // frees the stack with interrupts enabled
+            drop(thread);
+            return Ok(());
+        }
+        park();
+    }
+}
+
+/// Lets the thread be freed once it exited without joining it, it can't be joined
+/// anymore.
+pub fn detach(id: ThreadId) {
+    let exited = without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let thread = scheduler.threads.get_mut(&id)?;
+        if thread.state != ThreadState::Exited {
+            thread.detached = true;
+            return None;
+        }
+        scheduler.threads.remove(&id)
+    });
+    drop(exited);
+}
+
+/// Frees the detached threads that exited, and the stacks of the threads that exited
+/// but were not joined yet.
+fn free_exited_threads() {
+    let (threads, stacks) = without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let detached: Vec<ThreadId> = scheduler
+            .threads
+            .iter()
+            .filter(|(_, thread)| thread.state == ThreadState::Exited && thread.detached)
+            .map(|(&id, _)| id)
+            .collect();
+        let threads: Vec<Box<Thread>> = detached
+            .iter()
+            .filter_map(|id| scheduler.threads.remove(id))
+            .collect();
+        let stacks: Vec<KernelStack> = scheduler
+            .threads
+            .values_mut()
+            .filter(|thread| thread.state == ThreadState::Exited)
+            .filter_map(|thread| thread.stack.take())
+            .collect();
+        (threads, stacks)
+    });
+    drop(threads);
+    drop(stacks);
+}
+
+/// Puts the running thread into `state` and switches to the next ready thread, or to
+/// the idle thread of the processor if none is ready and the running thread blocks
+/// or exits. Returns when the thread is switched in again, or at once if it stays
+/// ready and no other thread is.
+///
+/// Must be called with interrupts disabled.
+fn switch(mut scheduler: MutexGuard<'static, Scheduler>, state: ThreadState) {
+    let cpu = percpu::current();
+    let current = current_id();
+    let idle = ThreadId(cpu.idle_thread.load(Ordering::Relaxed));
+    cpu.slice_ticks.store(0, Ordering::Relaxed);
+
+    let next = match scheduler.ready.pop_front() {
+        Some(next) => next,
+        None if state == ThreadState::Ready => return,
+        None => {
+            assert_ne!(current, idle, "idle thread blocked");
+            idle
+        }
+    };
+    scheduler.thread_mut(current).state = state;
+    if state == ThreadState::Ready && current != idle {
+        scheduler.ready.push_back(current);
+    }
+    scheduler.thread_mut(next).state = ThreadState::Running;
+    cpu.current_thread.store(next.0, Ordering::Relaxed);
+    if next == idle {
+        scheduler.idle_cpus |= cpu.mask();
+    } else {
+        scheduler.idle_cpus &= !cpu.mask();
+        if !scheduler.ready.is_empty() {
+            scheduler.wake_idle_cpu();
+        }
+    }
+
+    scheduler.thread_mut(current).held_locks = lock_order::held();
+    let next_thread = scheduler.thread_mut(next);
+    lock_order::set_held(next_thread.held_locks);
+    if let Some(stack) = &next_thread.stack {
+        gdt::set_kernel_stack(stack.top());
+    }
+    if Cr3::read().0 != next_thread.page_table {
+        // both stacks are in the kernel part, which all page tables share
+        unsafe { Cr3::write(next_thread.page_table, Cr3Flags::empty()) };
+    }
+    let old_stack_pointer: *mut u64 = &mut scheduler.thread_mut(current).stack_pointer;
+    let new_stack_pointer = scheduler.thread_mut(next).stack_pointer;
+    // released by the thread switched in, once the registers of this one are saved
+    mem::forget(scheduler);
+    unsafe { thread_switch_context(old_stack_pointer, new_stack_pointer) };
+    finish_switch();
+}
+
+/// Releases the scheduler lock handed over by the thread that switched to the
+/// running one.
+fn finish_switch() {
+    unsafe { SCHEDULER.force_unlock() };
+}
@@ -1,26 +1,205 @@
+use crate::interrupts::irq;
+use crate::sync::{lock_order, IrqSpinLock};
+use crate::{hpet, pit};
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use core::time::Duration;
-use spin::Mutex;
+
+pub mod date_time;
+pub mod timer;
+pub mod tsc;
+
+pub use date_time::DateTime;
 
 /// Kernel start time, measured in (seconds, nanoseconds) since Unix epoch
-pub static START: Mutex<(u64, u64)> = Mutex::new((0, 0));
-/// Kernel up time, measured in (seconds, nanoseconds) since `START_TIME`
-pub static OFFSET: Mutex<(u64, u64)> = Mutex::new((0, 0));
+pub static START: IrqSpinLock<(u64, u64)> = IrqSpinLock::with_level((0, 0), lock_order::CLOCK);
+/// Kernel up time, measured in (seconds, nanoseconds) since `START_TIME`, counted by
+/// the timer interrupt. Only used as clock if there is no better clock source.
+pub static OFFSET: IrqSpinLock<(u64, u64)> = IrqSpinLock::with_level((0, 0), lock_order::CLOCK);
+
+/// Fractions of a nanosecond counted by the timer interrupt, in units of
+/// `1 / pit::TICK_LENGTH_DENOMINATOR` ns. Only changed with `OFFSET` locked.
+static TICK_REMAINDER: AtomicU64 = AtomicU64::new(0);
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The counter `Instant::now` is based on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    /// The timer interrupt counter in `OFFSET`, with the resolution of a timer tick.
+    Ticks = 0,
+    Hpet = 1,
+    /// The time stamp counter, only used if it is invariant.
+    Tsc = 2,
+}
+
+static CLOCK_SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Ticks as u8);
+/// Value of the clock source when it was selected, in its own unit.
+static CLOCK_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Starts counting the up time and processing timers on every timer interrupt and
+/// selects the best clock source for `Instant`.
+///
+/// Must be called after `hpet::init`.
+pub fn init() {
+    irq::register_irq(irq::TIMER, tick).expect("timer IRQ is taken");
+    timer::init();
+
+    if tsc::is_invariant() && tsc::calibrate() > 0 {
+        CLOCK_BASE.store(tsc::read(), Ordering::SeqCst);
+        CLOCK_SOURCE.store(ClockSource::Tsc as u8, Ordering::SeqCst);
+    } else if hpet::is_enabled() {
+        CLOCK_BASE.store(hpet::nanos(), Ordering::SeqCst);
+        CLOCK_SOURCE.store(ClockSource::Hpet as u8, Ordering::SeqCst);
+    }
+}
+
+/// Returns the counter `Instant` is based on.
+pub fn clock_source() -> ClockSource {
+    match CLOCK_SOURCE.load(Ordering::Relaxed) {
+        2 => ClockSource::Tsc,
+        1 => ClockSource::Hpet,
+        _ => ClockSource::Ticks,
+    }
+}
+
+fn tick(_irq: u8) {
+    let length = pit::tick_length();
+    let mut offset = OFFSET.lock();
+    let remainder = TICK_REMAINDER.load(Ordering::Relaxed) + length.remainder;
+    TICK_REMAINDER.store(remainder % pit::TICK_LENGTH_DENOMINATOR, Ordering::Relaxed);
+    let sum = offset.1 + length.nanos + remainder / pit::TICK_LENGTH_DENOMINATOR;
+    offset.1 = sum % NANOS_PER_SEC;
+    offset.0 += sum / NANOS_PER_SEC;
+}
+
+/// Returns the up time counted by the timer interrupt.
+pub fn tick_time() -> Duration {
+    let (secs, nanos) = *OFFSET.lock();
+    Duration::new(secs, nanos as u32)
+}
+
+/// A point in time since the clock source was selected, with nanosecond resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        let base = CLOCK_BASE.load(Ordering::Relaxed);
+        let nanos = match clock_source() {
+            ClockSource::Tsc => tsc::to_nanos(tsc::read_synchronized() - base),
+            ClockSource::Hpet => hpet::nanos() - base,
+            ClockSource::Ticks => tick_time().as_nanos() as u64,
+        };
+        Instant(nanos)
+    }
+
+    /// Returns the time elapsed since the clock source was selected.
+    pub fn since_boot(self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
 
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Instant> {
+        let nanos = duration.as_nanos();
+        if nanos > u128::from(u64::max_value()) {
+            return None;
+        }
+        self.0.checked_add(nanos as u64).map(Instant)
+    }
+
+    pub fn checked_sub(self, duration: Duration) -> Option<Instant> {
+        let nanos = duration.as_nanos();
+        if nanos > u128::from(u64::max_value()) {
+            return None;
+        }
+        self.0.checked_sub(nanos as u64).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Halts until `duration` has passed, rounded up to whole timer ticks.
+///
+/// Interrupts must be enabled, the timer interrupt wakes the CPU.
+pub fn sleep(duration: Duration) {
+    assert!(
+        x86_64::instructions::interrupts::are_enabled(),
+        "sleeping with interrupts disabled"
+    );
+    let timeout = timer::Timeout::new(duration);
+    while !timeout.expired() {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Returns the up time as (seconds, nanoseconds).
 pub fn monotonic() -> (u64, u64) {
-    *OFFSET.lock()
+    let nanos = Instant::now().0;
+    (nanos / NANOS_PER_SEC, nanos % NANOS_PER_SEC)
 }
 
+/// Returns the time since the Unix epoch as (seconds, nanoseconds).
 pub fn realtime() -> (u64, u64) {
     let offset = monotonic();
     let start = *START.lock();
     let sum = start.1 + offset.1;
     (
-        start.0 + offset.0 + sum / 1_000_000_000,
-        sum % 1_000_000_000,
+        start.0 + offset.0 + sum / NANOS_PER_SEC,
+        sum % NANOS_PER_SEC,
     )
 }
 
+/// Moves `START` so that `realtime` returns `secs` seconds since the Unix epoch now.
+pub fn set_realtime(secs: u64) {
+    let (up_secs, up_nanos) = monotonic();
+    let start = if up_nanos == 0 {
+        (secs.saturating_sub(up_secs), 0)
+    } else {
+        (secs.saturating_sub(up_secs + 1), NANOS_PER_SEC - up_nanos)
+    };
+    *START.lock() = start;
+}
+
 pub fn duration_now() -> Duration {
     let (sec, nano) = realtime();
     Duration::new(sec, nano as u32)
 }
+
+/// Returns the current date and time in UTC.
+pub fn date_time_now() -> DateTime {
+    // the real time starts at the RTC time, which is before the year 10000
+    DateTime::from_unix(realtime().0).expect("real time after the year 65535")
+}
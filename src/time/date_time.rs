@@ -0,0 +1,238 @@
+//! Calendar dates and times in UTC.
+//!
+//! The conversions between days and dates of the proleptic Gregorian calendar follow
+//! Howard Hinnant's `days_from_civil` and `civil_from_days` algorithms.
+
+use core::fmt;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+/// Days from 0000-03-01 to 1970-01-01.
+const DAYS_BEFORE_EPOCH: i64 = 719_468;
+const DAYS_PER_ERA: i64 = 146_097;
+
+/// A date and time of day in UTC, with second precision.
+///
+/// It always exists and is never before the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    year: u16,
+    /// 1 to 12.
+    month: u8,
+    /// 1 to the number of days in the month.
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+}
+
+impl DateTime {
+    /// 1970-01-01T00:00:00Z.
+    pub const UNIX_EPOCH: DateTime = DateTime {
+        year: 1970,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+
+    /// Returns the date and time if it exists and is not before the Unix epoch.
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Option<Self> {
+        let valid = year >= 1970
+            && (1..=12).contains(&month)
+            && (1..=days_in_month(year, month)).contains(&day)
+            && hour < 24
+            && minute < 60
+            && second < 60;
+        if !valid {
+            return None;
+        }
+        Some(DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        })
+    }
+
+    /// Converts seconds since the Unix epoch. Returns `None` for times after the year
+    /// 65535.
+    pub fn from_unix(secs: u64) -> Option<Self> {
+        let days = (secs / SECONDS_PER_DAY) as i64;
+        let seconds_of_day = secs % SECONDS_PER_DAY;
+        let (year, month, day) = civil_from_days(days);
+        if year > i64::from(u16::max_value()) {
+            return None;
+        }
+        Some(DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        })
+    }
+
+    /// Returns the seconds since the Unix epoch.
+    pub fn to_unix(&self) -> u64 {
+        let days = days_from_civil(i64::from(self.year), self.month, self.day);
+        // not negative, the date is never before the epoch
+        days as u64 * SECONDS_PER_DAY
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+
+    pub fn year(&self) -> u16 {
+        self.year
+    }
+
+    /// Returns the month, 1 to 12.
+    pub fn month(&self) -> u8 {
+        self.month
+    }
+
+    /// Returns the day of the month, starting at 1.
+    pub fn day(&self) -> u8 {
+        self.day
+    }
+
+    pub fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    pub fn minute(&self) -> u8 {
+        self.minute
+    }
+
+    pub fn second(&self) -> u8 {
+        self.second
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// Formats the date and time in ISO 8601, e.g. `2020-01-31T23:59:59Z`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+pub fn is_leap_year(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Returns the days since 1970-01-01 of the given date.
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    // the year starts in March, so that the leap day is the last day of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * DAYS_PER_ERA + day_of_era - DAYS_BEFORE_EPOCH
+}
+
+/// Returns the (year, month, day) of the given days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + DAYS_BEFORE_EPOCH;
+    let era = (if days >= 0 {
+        days
+    } else {
+        days - (DAYS_PER_ERA - 1)
+    }) / DAYS_PER_ERA;
+    let day_of_era = days - era * DAYS_PER_ERA;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8;
+    let month = (if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    }) as u8;
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_unix_conversion() {
+    serial_print!("test_unix_conversion... ");
+    let cases = [
+        (0, (1970, 1, 1, 0, 0, 0)),
+        (951_782_400, (2000, 2, 29, 0, 0, 0)),
+        (2_147_483_647, (2038, 1, 19, 3, 14, 7)),
+        (4_107_542_400, (2100, 3, 1, 0, 0, 0)),
+        (13_574_563_200, (2400, 2, 29, 0, 0, 0)),
+    ];
+    for &(secs, (year, month, day, hour, minute, second)) in cases.iter() {
+        let date_time = DateTime::new(year, month, day, hour, minute, second).unwrap();
+        assert_eq!(DateTime::from_unix(secs), Some(date_time));
+        assert_eq!(date_time.to_unix(), secs);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_round_trip() {
+    serial_print!("test_round_trip... ");
+    // one day per week from 1970 to beyond 2400
+    for days in (0..160_000).step_by(7) {
+        let secs = days * SECONDS_PER_DAY + 45_296;
+        assert_eq!(DateTime::from_unix(secs).unwrap().to_unix(), secs);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_range() {
+    serial_print!("test_range... ");
+    assert!(DateTime::new(1969, 12, 31, 23, 59, 59).is_none());
+    assert_eq!(DateTime::UNIX_EPOCH.to_unix(), 0);
+    let last = DateTime::new(65535, 12, 31, 23, 59, 59).unwrap();
+    assert_eq!(DateTime::from_unix(last.to_unix()), Some(last));
+    assert_eq!(DateTime::from_unix(last.to_unix() + 1), None);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_leap_years() {
+    serial_print!("test_leap_years... ");
+    assert!(DateTime::new(2000, 2, 29, 0, 0, 0).is_some());
+    assert!(DateTime::new(2100, 2, 29, 0, 0, 0).is_none());
+    assert!(DateTime::new(2024, 2, 29, 0, 0, 0).is_some());
+    assert!(DateTime::new(2023, 2, 29, 0, 0, 0).is_none());
+    assert!(DateTime::new(2023, 4, 31, 0, 0, 0).is_none());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_display() {
+    serial_print!("test_display... ");
+    use alloc::string::ToString;
+
+    let date_time = DateTime::new(2020, 1, 31, 23, 5, 9).unwrap();
+    assert_eq!(date_time.to_string(), "2020-01-31T23:05:09Z");
+    serial_println!("[ok]");
+}
@@ -0,0 +1,407 @@
+//! Timers driven by the timer interrupt, kept in a hierarchical timing wheel.
+//!
+//! The wheel has four levels of 64 slots, a slot of level `n` spans `64^n` ticks. A
+//! timer is queued in the lowest level whose slots still reach its expiry, so adding
+//! and cancelling are O(1) and most ticks only look at a single slot of level 0.
+//! Whenever a level wraps around, the next slot of the level above is cascaded down.
+//! Timers further away than the wheel spans wait in the last level and are sorted in
+//! again when their slot is cascaded.
+//!
+//! Timers are nodes of an array linked by their indices, so the interrupt handler
+//! moves, requeues and frees them without allocating.
+
+use crate::interrupts::irq;
+use crate::pit;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+const LEVELS: usize = 4;
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+/// Ticks spanned by the whole wheel.
+const WHEEL_TICKS: u64 = 1 << (SLOT_BITS * LEVELS as u32);
+
+const NIL: u32 = u32::max_value();
+const NOT_QUEUED: u16 = u16::max_value();
+
+/// The timers of the kernel. Must only be locked with interrupts disabled.
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+/// Ticks processed by the wheel since `init`.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a timer. It stays invalid after the timer fired or was cancelled, even
+/// if the node of the timer is reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerId {
+    index: u32,
+    generation: u32,
+}
+
+struct Node {
+    /// The tick at which the timer fires.
+    expires: u64,
+    /// Ticks between two calls of a periodic timer, zero for one-shot timers.
+    period: u64,
+    callback: Option<fn()>,
+    /// Incremented whenever the node is freed.
+    generation: u32,
+    /// The slot the node is queued in, `NOT_QUEUED` if it is free.
+    slot: u16,
+    prev: u32,
+    next: u32,
+}
+
+struct Wheel {
+    nodes: Vec<Node>,
+    /// The first node of every slot, level by level.
+    heads: [u32; LEVELS * SLOTS],
+    /// The first free node, the free nodes are linked through `next`.
+    free: u32,
+    /// The last tick that was processed.
+    current: u64,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Wheel {
+            nodes: Vec::new(),
+            heads: [NIL; LEVELS * SLOTS],
+            free: NIL,
+            current: 0,
+        }
+    }
+
+    /// Adds a timer firing `delay` ticks from now, but at least on the next tick.
+    fn add(&mut self, delay: u64, period: u64, callback: Option<fn()>) -> TimerId {
+        let index = if self.free != NIL {
+            let index = self.free;
+            self.free = self.nodes[index as usize].next;
+            index
+        } else {
+            self.nodes.push(Node {
+                expires: 0,
+                period: 0,
+                callback: None,
+                generation: 0,
+                slot: NOT_QUEUED,
+                prev: NIL,
+                next: NIL,
+            });
+            (self.nodes.len() - 1) as u32
+        };
+
+        let node = &mut self.nodes[index as usize];
+        node.expires = self.current.saturating_add(delay.max(1));
+        node.period = period;
+        node.callback = callback;
+        let generation = node.generation;
+        self.enqueue(index);
+        TimerId { index, generation }
+    }
+
+    fn is_pending(&self, id: TimerId) -> bool {
+        self.nodes.get(id.index as usize).map_or(false, |node| {
+            node.generation == id.generation && node.slot != NOT_QUEUED
+        })
+    }
+
+    fn cancel(&mut self, id: TimerId) -> bool {
+        if !self.is_pending(id) {
+            return false;
+        }
+        self.dequeue(id.index);
+        self.release(id.index);
+        true
+    }
+
+    /// Moves the wheel to the next tick and cascades the slots starting at it.
+    fn advance(&mut self) {
+        self.current += 1;
+        for level in 1..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            if self.current & ((1 << shift) - 1) != 0 {
+                break;
+            }
+            let slot = level * SLOTS + ((self.current >> shift) & SLOT_MASK) as usize;
+            self.cascade(slot);
+        }
+    }
+
+    /// Removes the next timer due at the current tick and returns its callback.
+    /// Periodic timers are queued again.
+    fn pop_expired(&mut self) -> Option<fn()> {
+        let slot = (self.current & SLOT_MASK) as usize;
+        loop {
+            let index = self.heads[slot];
+            if index == NIL {
+                return None;
+            }
+            self.dequeue(index);
+            let node = &mut self.nodes[index as usize];
+            let callback = node.callback;
+            if node.period != 0 {
+                node.expires = node.expires.saturating_add(node.period);
+                self.enqueue(index);
+            } else {
+                self.release(index);
+            }
+            if callback.is_some() {
+                return callback;
+            }
+        }
+    }
+
+    /// Queues the node in the slot its expiry falls into.
+    fn enqueue(&mut self, index: u32) {
+        let expires = self.nodes[index as usize].expires;
+        // timers beyond the wheel wait in the farthest slot
+        let delta = expires.saturating_sub(self.current).min(WHEEL_TICKS - 1);
+        let target = self.current + delta;
+        let level = (0..LEVELS)
+            .find(|&level| delta < 1 << (SLOT_BITS * (level as u32 + 1)))
+            .unwrap_or(LEVELS - 1);
+        let shift = SLOT_BITS * level as u32;
+        let slot = level * SLOTS + ((target >> shift) & SLOT_MASK) as usize;
+
+        let head = self.heads[slot];
+        if head != NIL {
+            self.nodes[head as usize].prev = index;
+        }
+        let node = &mut self.nodes[index as usize];
+        node.slot = slot as u16;
+        node.prev = NIL;
+        node.next = head;
+        self.heads[slot] = index;
+    }
+
+    /// Unlinks the node from its slot.
+    fn dequeue(&mut self, index: u32) {
+        let node = &mut self.nodes[index as usize];
+        let (slot, prev, next) = (node.slot as usize, node.prev, node.next);
+        node.slot = NOT_QUEUED;
+        if prev == NIL {
+            self.heads[slot] = next;
+        } else {
+            self.nodes[prev as usize].next = next;
+        }
+        if next != NIL {
+            self.nodes[next as usize].prev = prev;
+        }
+    }
+
+    /// Adds the dequeued node to the free nodes.
+    fn release(&mut self, index: u32) {
+        let node = &mut self.nodes[index as usize];
+        node.generation = node.generation.wrapping_add(1);
+        node.callback = None;
+        node.next = self.free;
+        self.free = index;
+    }
+
+    /// Sorts the nodes of `slot` into the lower levels.
+    fn cascade(&mut self, slot: usize) {
+        let mut index = self.heads[slot];
+        self.heads[slot] = NIL;
+        while index != NIL {
+            let next = self.nodes[index as usize].next;
+            self.enqueue(index);
+            index = next;
+        }
+    }
+}
+
+/// Starts processing timers on every timer interrupt.
+pub fn init() {
+    irq::register_irq(irq::TIMER, tick).expect("timer IRQ is taken");
+}
+
+fn tick(_irq: u8) {
+    let mut wheel = WHEEL.lock();
+    wheel.advance();
+    TICKS.store(wheel.current, Ordering::Relaxed);
+    drop(wheel);
+
+    // the lock is released while a callback runs, so it can cancel timers
+    loop {
+        let callback = WHEEL.lock().pop_expired();
+        match callback {
+            Some(callback) => callback(),
+            None => break,
+        }
+    }
+}
+
+/// Returns the number of ticks the wheel processed.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Converts `duration` to timer ticks of the current length, rounding up.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let tick = u128::from(pit::tick_length().nanos.max(1));
+    let ticks = (duration.as_nanos() + tick - 1) / tick;
+    ticks.min(u128::from(u64::max_value())) as u64
+}
+
+/// Calls `callback` once from the timer interrupt after `delay`.
+///
+/// Callbacks run with interrupts disabled. They may cancel timers, but must not add
+/// any since adding can allocate.
+pub fn call_after(delay: Duration, callback: fn()) -> TimerId {
+    let delay = duration_to_ticks(delay);
+    without_interrupts(|| WHEEL.lock().add(delay, 0, Some(callback)))
+}
+
+/// Calls `callback` from the timer interrupt every `period` until the timer is
+/// cancelled. The first call is after one period.
+pub fn call_every(period: Duration, callback: fn()) -> TimerId {
+    let period = duration_to_ticks(period).max(1);
+    without_interrupts(|| WHEEL.lock().add(period, period, Some(callback)))
+}
+
+/// Cancels the timer. Returns false if it already fired or was cancelled.
+pub fn cancel(id: TimerId) -> bool {
+    without_interrupts(|| WHEEL.lock().cancel(id))
+}
+
+/// Returns whether the timer is still waiting to fire. Periodic timers are pending
+/// until they are cancelled.
+pub fn is_pending(id: TimerId) -> bool {
+    without_interrupts(|| WHEEL.lock().is_pending(id))
+}
+
+/// A deadline that can be polled, cancelled when dropped.
+#[derive(Debug)]
+pub struct Timeout(TimerId);
+
+impl Timeout {
+    pub fn new(duration: Duration) -> Self {
+        let delay = duration_to_ticks(duration);
+        Timeout(without_interrupts(|| WHEEL.lock().add(delay, 0, None)))
+    }
+
+    pub fn expired(&self) -> bool {
+        !is_pending(self.0)
+    }
+}
+
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        cancel(self.0);
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_wheel_expiry() {
+    serial_print!("test_wheel_expiry... ");
+    let mut wheel = Wheel::new();
+    let mut timers: Vec<_> = [1, 2, 63, 64, 65, 4095, 4096, 4097, 262_144, 300_000]
+        .iter()
+        .map(|&delay| (delay, wheel.add(delay, 0, None)))
+        .collect();
+    timers.sort_by_key(|&(delay, _)| delay);
+    for &(delay, id) in &timers {
+        while wheel.current < delay - 1 {
+            wheel.advance();
+            while wheel.pop_expired().is_some() {}
+        }
+        assert!(wheel.is_pending(id), "timer of {} ticks fired early", delay);
+        wheel.advance();
+        while wheel.pop_expired().is_some() {}
+        assert!(
+            !wheel.is_pending(id),
+            "timer of {} ticks did not fire",
+            delay
+        );
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_wheel_cancel_and_reuse() {
+    serial_print!("test_wheel_cancel_and_reuse... ");
+    fn callback() {}
+
+    let mut wheel = Wheel::new();
+    let first = wheel.add(10, 0, Some(callback));
+    let second = wheel.add(10, 0, Some(callback));
+    assert!(wheel.cancel(first));
+    assert!(!wheel.cancel(first));
+    // the node of the cancelled timer is reused without reviving its id
+    let third = wheel.add(5, 5, Some(callback));
+    assert_eq!(third.index, first.index);
+    assert!(!wheel.is_pending(first));
+
+    let mut calls = 0;
+    for _ in 0..20 {
+        wheel.advance();
+        while wheel.pop_expired().is_some() {
+            calls += 1;
+        }
+    }
+    // the periodic timer fired at 5, 10, 15 and 20, the one-shot timer at 10
+    assert_eq!(calls, 5);
+    assert!(!wheel.is_pending(second));
+    assert!(wheel.is_pending(third));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_wheel_many_timers() {
+    serial_print!("test_wheel_many_timers... ");
+    fn callback() {}
+
+    let mut wheel = Wheel::new();
+    // spread over all levels, plus timers sharing a slot of level 0, 1 and 2 with
+    // another timer or each other
+    let mut delays: Vec<u64> = vec![5, 5, 5, 100, 100, 120, 4100, 4100, 4200, 270_000, 270_001];
+    let mut random = 12_345u64;
+    for _ in 0..500 {
+        random = random
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1);
+        delays.push((random >> 33) % 300_000 + 1);
+    }
+    let mut timers: Vec<_> = delays
+        .iter()
+        .map(|&delay| (delay, wheel.add(delay, 0, Some(callback))))
+        .collect();
+    timers.sort_by_key(|&(delay, _)| delay);
+
+    let mut next = 0;
+    while next < timers.len() {
+        wheel.advance();
+        let mut fired = 0;
+        while wheel.pop_expired().is_some() {
+            fired += 1;
+        }
+        let due = timers[next..]
+            .iter()
+            .take_while(|&&(delay, _)| delay == wheel.current)
+            .count();
+        assert_eq!(
+            fired, due,
+            "wrong number of timers fired at {}",
+            wheel.current
+        );
+        for &(delay, id) in &timers[next..next + due] {
+            assert!(
+                !wheel.is_pending(id),
+                "timer of {} ticks is still pending",
+                delay
+            );
+        }
+        next += due;
+        if let Some(&(delay, id)) = timers.get(next) {
+            assert!(wheel.is_pending(id), "timer of {} ticks fired early", delay);
+        }
+    }
+    serial_println!("[ok]");
+}
@@ -0,0 +1,125 @@
+//! The time stamp counter (TSC) of the processor.
+//!
+//! The TSCs of the processors are not necessarily in sync, the one of an application
+//! processor usually starts later. Every application processor measures the offset
+//! of its TSC to the one of the bootstrap processor while it starts, which
+//! `read_synchronized` adds.
+
+use crate::smp::percpu;
+use crate::{hpet, pit};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{spin_loop_hint, AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
+
+/// Length of the calibration in microseconds.
+const CALIBRATION_MICROS: u64 = 10_000;
+/// Number of TSC exchanges when measuring the offset of an application processor.
+const SYNC_ROUNDS: usize = 64;
+
+/// States of the exchange between `synchronize_bsp` and `synchronize_ap`.
+const SYNC_IDLE: u8 = 0;
+const SYNC_REQUEST: u8 = 1;
+const SYNC_REPLY: u8 = 2;
+const SYNC_DONE: u8 = 3;
+
+/// TSC frequency in Hz, zero before `calibrate`.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+static SYNC_STATE: AtomicU8 = AtomicU8::new(SYNC_IDLE);
+/// The TSC of the bootstrap processor in reply to a request.
+static SYNC_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the TSC of the executing processor.
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Reads the TSC of the executing processor, adjusted to the TSC of the bootstrap
+/// processor.
+pub fn read_synchronized() -> u64 {
+    // the offset must belong to the processor the TSC is read on
+    interrupts::without_interrupts(|| {
+        read().wrapping_add(percpu::current().tsc_offset.load(Ordering::Relaxed))
+    })
+}
+
+/// Answers the TSC requests of the application processor that runs
+/// `synchronize_ap` until it is done.
+///
+/// Called by the bootstrap processor with interrupts disabled once the application
+/// processor started.
+pub(crate) fn synchronize_bsp() {
+    loop {
+        match SYNC_STATE.load(Ordering::SeqCst) {
+            SYNC_REQUEST => {
+                SYNC_TSC.store(read(), Ordering::SeqCst);
+                SYNC_STATE.store(SYNC_REPLY, Ordering::SeqCst);
+            }
+            SYNC_DONE => break,
+            _ => spin_loop_hint(),
+        }
+    }
+    SYNC_STATE.store(SYNC_IDLE, Ordering::SeqCst);
+}
+
+/// Measures the offset of the TSC of the executing application processor to the one
+/// of the bootstrap processor, which runs `synchronize_bsp` meanwhile.
+///
+/// The TSC of the bootstrap processor is requested several times, and the exchange
+/// that took the shortest time is assumed to have been answered half way through.
+pub(crate) fn synchronize_ap() {
+    let mut shortest = u64::max_value();
+    let mut offset = 0;
+    for _ in 0..SYNC_ROUNDS {
+        let start = read();
+        SYNC_STATE.store(SYNC_REQUEST, Ordering::SeqCst);
+        while SYNC_STATE.load(Ordering::SeqCst) != SYNC_REPLY {
+            spin_loop_hint();
+        }
+        let end = read();
+        let bsp = SYNC_TSC.load(Ordering::SeqCst);
+        if end - start < shortest {
+            shortest = end - start;
+            offset = bsp.wrapping_sub(start + shortest / 2);
+        }
+    }
+    percpu::current().tsc_offset.store(offset, Ordering::SeqCst);
+    SYNC_STATE.store(SYNC_DONE, Ordering::SeqCst);
+}
+
+/// Returns whether the TSC runs at a constant rate in all power states, which makes
+/// it usable as a clock source.
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// Measures the TSC frequency against the HPET, or the PIT if there is no HPET.
+pub fn calibrate() -> u64 {
+    let frequency = if hpet::is_enabled() {
+        let start_nanos = hpet::nanos();
+        let start = read();
+        let mut now = start_nanos;
+        while now - start_nanos < CALIBRATION_MICROS * 1000 {
+            now = hpet::nanos();
+        }
+        let elapsed = read() - start;
+        (u128::from(elapsed) * 1_000_000_000 / u128::from(now - start_nanos)) as u64
+    } else {
+        let start = read();
+        pit::wait_micros(CALIBRATION_MICROS);
+        (read() - start) * (1_000_000 / CALIBRATION_MICROS)
+    };
+    FREQUENCY.store(frequency, Ordering::SeqCst);
+    frequency
+}
+
+/// Returns the frequency measured by `calibrate` in Hz.
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Converts a number of TSC ticks to nanoseconds.
+pub fn to_nanos(ticks: u64) -> u64 {
+    (u128::from(ticks) * 1_000_000_000 / u128::from(frequency())) as u64
+}
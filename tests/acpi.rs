@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use metal_os::acpi::{self, Sdt, ACPI_TABLE};
+use metal_os::{memory, serial_print, serial_println};
+
+entry_point!(main);
+
+#[test_case]
+fn fadt_points_to_dsdt() {
+    serial_print!("fadt_points_to_dsdt... ");
+    let fadt = ACPI_TABLE.lock().fadt.expect("no FADT");
+    assert_ne!(fadt.pm1a_control_block, 0);
+    let dsdt = unsafe { Sdt::new(fadt.dsdt) }.expect("invalid DSDT");
+    assert_eq!(&dsdt.signature(), b"DSDT");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn tables_are_parsed() {
+    serial_print!("tables_are_parsed... ");
+    let tables = ACPI_TABLE.lock();
+    assert!(tables.madt.is_some());
+    let hpet = tables.hpet.expect("no HPET");
+    assert_eq!(hpet.base_address.address_space, acpi::SYSTEM_MEMORY);
+    assert_ne!(hpet.base_address.address, 0);
+    assert!(hpet.comparator_count >= 3);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn rtc_century_is_plausible() {
+    serial_print!("rtc_century_is_plausible... ");
+    // 2020-01-01T00:00:00Z
+    assert!(metal_os::time::START.lock().0 >= 1_577_836_800);
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
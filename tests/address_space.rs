@@ -0,0 +1,155 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use metal_os::memory::{
+    self,
+    address_space::{self, AddressSpace, AddressSpaceError, Protection, USER_START},
+};
+use metal_os::{serial_print, serial_println};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn free_frames() -> usize {
+    memory::with_frame_allocator(|frame_allocator| frame_allocator.free_frames())
+}
+
+fn reference_count(addr: PhysAddr) -> usize {
+    let frame = PhysFrame::containing_address(addr);
+    memory::with_frame_allocator(|frame_allocator| frame_allocator.reference_count(frame))
+}
+
+#[test_case]
+fn map_switch_and_destroy() {
+    serial_print!("map_switch_and_destroy... ");
+    let free = free_frames();
+    let addr = VirtAddr::new(USER_START);
+
+    let mut space = AddressSpace::new().expect("failed to create address space");
+    space
+        .map(addr, 2 * 4096, Protection::USER_READ_WRITE)
+        .expect("failed to map");
+    let phys = space.translate(addr + 4096u64).expect("page not mapped");
+    unsafe { *memory::phys_to_virt(phys).as_mut_ptr::<u64>() = 0xdead_beef };
+
+    unsafe { space.activate() };
+    let value = unsafe { *(addr + 4096u64).as_ptr::<u64>() };
+    unsafe { address_space::activate_kernel() };
+    assert_eq!(value, 0xdead_beef);
+
+    space
+        .protect(addr, 4096, Protection::USER_READ)
+        .expect("failed to protect");
+    space.unmap(addr, 4096).expect("failed to unmap");
+    assert!(space.translate(addr).is_none());
+
+    drop(space);
+    assert_eq!(free_frames(), free);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn kernel_part_is_rejected() {
+    serial_print!("kernel_part_is_rejected... ");
+    let mut space = AddressSpace::new().expect("failed to create address space");
+    let heap = VirtAddr::new(metal_os::allocator::HEAP_START as u64);
+    match space.map(heap, 4096, Protection::KERNEL_READ_WRITE) {
+        Err(AddressSpaceError::OutOfRange) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn duplicate_copies_pages() {
+    serial_print!("duplicate_copies_pages... ");
+    let free = free_frames();
+    let code = VirtAddr::new(USER_START);
+    let data = VirtAddr::new(USER_START + 0x20_0000);
+
+    let mut space = AddressSpace::new().expect("failed to create address space");
+    space
+        .map(code, 4096, Protection::USER_READ_EXECUTE)
+        .expect("failed to map");
+    space
+        .map(data, 4096, Protection::USER_READ_WRITE)
+        .expect("failed to map");
+    space.write(data, &[1, 2, 3]).expect("failed to write");
+
+    let copy = space.duplicate().expect("failed to duplicate");
+    space.write(data, &[4]).expect("failed to write");
+    assert_eq!(copy.protection(code), Some(Protection::USER_READ_EXECUTE));
+    assert_eq!(copy.protection(data), Some(Protection::USER_READ_WRITE));
+    assert_ne!(copy.translate(data), space.translate(data));
+    let bytes: *const [u8; 3] = memory::phys_to_virt(copy.translate(data).unwrap()).as_ptr();
+    assert_eq!(unsafe { *bytes }, [1, 2, 3]);
+
+    drop(space);
+    drop(copy);
+    assert_eq!(free_frames(), free);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn copy_on_write() {
+    serial_print!("copy_on_write... ");
+    let free = free_frames();
+    let code = VirtAddr::new(USER_START);
+    let data = VirtAddr::new(USER_START + 0x20_0000);
+
+    let mut space = AddressSpace::new().expect("failed to create address space");
+    space
+        .map(code, 4096, Protection::USER_READ_EXECUTE)
+        .expect("failed to map");
+    space
+        .map(data, 4096, Protection::USER_READ_WRITE)
+        .expect("failed to map");
+    space.write(data, &[1, 2, 3]).expect("failed to write");
+
+    let mut copy = space.duplicate().expect("failed to duplicate");
+    let frame = space.translate(data).unwrap();
+    assert_eq!(copy.translate(data), Some(frame));
+    assert_eq!(copy.translate(code), space.translate(code));
+    assert_eq!(reference_count(frame), 2);
+
+    // the copy gets a frame of its own, the original keeps the last reference
+    assert!(copy.handle_write_fault(data).expect("failed to copy"));
+    assert_ne!(copy.translate(data), Some(frame));
+    let bytes: *const [u8; 3] = memory::phys_to_virt(copy.translate(data).unwrap()).as_ptr();
+    assert_eq!(unsafe { *bytes }, [1, 2, 3]);
+    assert!(!copy.handle_write_fault(data).unwrap());
+    assert!(!copy.handle_write_fault(code).unwrap());
+    assert!(space.handle_write_fault(data).expect("failed to copy"));
+    assert_eq!(space.translate(data), Some(frame));
+    assert_eq!(reference_count(frame), 1);
+
+    drop(space);
+    assert_eq!(reference_count(copy.translate(code).unwrap()), 1);
+    drop(copy);
+    assert_eq!(free_frames(), free);
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
@@ -0,0 +1,110 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::arch::x86_64::_rdtsc;
+use core::panic::PanicInfo;
+use linked_list_allocator::Heap;
+use metal_os::allocator::{fixed_size_block::FixedSizeBlockAllocator, Locked};
+use metal_os::{serial_print, serial_println};
+
+entry_point!(main);
+
+const REGION_SIZE: usize = 512 * 1024;
+const ALLOCATIONS: usize = 4000;
+
+/// Allocates a region from the kernel heap that the benchmarked allocator manages.
+fn region() -> (*mut u8, Layout) {
+    let layout = Layout::from_size_align(REGION_SIZE, 4096).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert!(!ptr.is_null(), "failed to allocate benchmark region");
+    (ptr, layout)
+}
+
+/// Many small, long-lived allocations interleaved with frees, which fragments a free list.
+///
+/// Returns the number of elapsed TSC cycles.
+fn run_workload(allocator: &dyn GlobalAlloc) -> u64 {
+    let sizes = [8, 16, 24, 32, 48, 64];
+    let mut live = Vec::with_capacity(ALLOCATIONS);
+
+    let start = unsafe { _rdtsc() };
+    for i in 0..ALLOCATIONS {
+        let layout = Layout::from_size_align(sizes[i % sizes.len()], 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null(), "benchmark allocator ran out of memory");
+        unsafe { ptr.write(i as u8) };
+        live.push((ptr, layout));
+    }
+    // free every other allocation and allocate again into the holes
+    for (i, (ptr, layout)) in live.iter_mut().enumerate().step_by(2) {
+        unsafe {
+            assert_eq!(ptr.read(), i as u8);
+            allocator.dealloc(*ptr, *layout);
+            *ptr = allocator.alloc(*layout);
+        }
+        assert!(!ptr.is_null(), "benchmark allocator ran out of memory");
+    }
+    for (ptr, layout) in live.drain(..) {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+    let end = unsafe { _rdtsc() };
+
+    end - start
+}
+
+#[test_case]
+fn compare_fixed_size_block_with_linked_list() {
+    serial_print!("compare_fixed_size_block_with_linked_list... ");
+
+    let (linked_region, linked_layout) = region();
+    let linked = Locked::new(Heap::empty());
+    unsafe { linked.lock().init(linked_region as usize, REGION_SIZE) };
+    let linked_cycles = run_workload(&linked);
+
+    let (fixed_region, fixed_layout) = region();
+    let fixed = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe { fixed.lock().init(fixed_region as usize, REGION_SIZE) };
+    let fixed_cycles = run_workload(&fixed);
+
+    unsafe {
+        alloc::alloc::dealloc(linked_region, linked_layout);
+        alloc::alloc::dealloc(fixed_region, fixed_layout);
+    }
+
+    // timings vary with the host, so they are only reported, the workload itself
+    // checks that both allocators serve it correctly
+    serial_print!(
+        "linked list: {} cycles, fixed size block: {} cycles... ",
+        linked_cycles,
+        fixed_cycles
+    );
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use metal_os::memory;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
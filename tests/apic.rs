@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use metal_os::acpi::madt::Madt;
+use metal_os::apic;
+use metal_os::interrupts::irq;
+use metal_os::{memory, serial_print, serial_println};
+
+entry_point!(main);
+
+#[test_case]
+fn madt_describes_apics() {
+    serial_print!("madt_describes_apics... ");
+    let madt = Madt::get().expect("no MADT");
+    assert!(!madt.io_apics.is_empty());
+    assert!(madt.processors.iter().any(|p| p.enabled));
+    assert!(apic::is_enabled());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn legacy_irqs_are_routed() {
+    serial_print!("legacy_irqs_are_routed... ");
+    let ticks = irq::irq_count(irq::TIMER);
+    while irq::irq_count(irq::TIMER) < ticks + 3 {
+        x86_64::instructions::hlt();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn local_apic_timer_fires() {
+    serial_print!("local_apic_timer_fires... ");
+    let ticks = irq::irq_count(irq::LAPIC_TIMER);
+    apic::start_timer(100);
+    while irq::irq_count(irq::LAPIC_TIMER) < ticks + 5 {
+        x86_64::instructions::hlt();
+    }
+    apic::stop_timer();
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use metal_os::interrupts::irq;
+use metal_os::time::{self, ClockSource, Instant};
+use metal_os::{hpet, memory, serial_print, serial_println};
+
+entry_point!(main);
+
+fn wait_for_ticks(ticks: u64) {
+    let target = irq::irq_count(irq::TIMER) + ticks;
+    while irq::irq_count(irq::TIMER) < target {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn high_resolution_clock_is_used() {
+    serial_print!("high_resolution_clock_is_used... ");
+    assert!(hpet::is_enabled());
+    assert!(hpet::frequency() > 1_000_000);
+    assert_ne!(time::clock_source(), ClockSource::Ticks);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn instant_resolution_is_below_a_tick() {
+    serial_print!("instant_resolution_is_below_a_tick... ");
+    let start = Instant::now();
+    let mut now = Instant::now();
+    while now == start {
+        now = Instant::now();
+    }
+    assert!(now - start < Duration::from_millis(1));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn instant_is_monotonic() {
+    serial_print!("instant_is_monotonic... ");
+    let mut last = Instant::now();
+    for _ in 0..10_000 {
+        let now = Instant::now();
+        assert!(now >= last);
+        last = now;
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn instant_agrees_with_timer_ticks() {
+    serial_print!("instant_agrees_with_timer_ticks... ");
+    wait_for_ticks(1);
+    let start = Instant::now();
+    // the timer fires every 2.25 ms
+    wait_for_ticks(4);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(6), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(200), "{:?}", elapsed);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn instant_arithmetic() {
+    serial_print!("instant_arithmetic... ");
+    let now = Instant::now();
+    let later = now + Duration::from_micros(1500);
+    assert_eq!(later - now, Duration::from_micros(1500));
+    assert_eq!(later - Duration::from_micros(1500), now);
+    assert_eq!(now - later, Duration::from_secs(0));
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use metal_os::memory::{
+    self,
+    address_space::Protection,
+    vma::{self, Vma, VmaError, VmaKind},
+};
+use metal_os::{serial_print, serial_println};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+const LAZY_START: u64 = 0x_6666_0000_0000;
+const LAZY_SIZE: u64 = 16 * 4096;
+
+fn free_frames() -> usize {
+    memory::with_frame_allocator(|frame_allocator| frame_allocator.free_frames())
+}
+
+fn lazy_vma(start: u64, size: u64) -> Vma {
+    Vma {
+        start: VirtAddr::new(start),
+        end: VirtAddr::new(start + size),
+        protection: Protection::KERNEL_READ_WRITE,
+        kind: VmaKind::Lazy,
+        name: "test",
+    }
+}
+
+#[test_case]
+fn lazy_pages_are_backed_on_access() {
+    serial_print!("lazy_pages_are_backed_on_access... ");
+    let free = free_frames();
+    vma::register(lazy_vma(LAZY_START, LAZY_SIZE)).expect("failed to register area");
+
+    let ptr = LAZY_START as *mut u64;
+    // the first read maps a zeroed frame
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    unsafe { ptr.write_volatile(42) };
+    assert_eq!(unsafe { ptr.read_volatile() }, 42);
+    let last = (LAZY_START + LAZY_SIZE - 8) as *mut u64;
+    unsafe { last.write_volatile(7) };
+    assert!(free_frames() < free);
+
+    vma::unregister(VirtAddr::new(LAZY_START)).expect("area vanished");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn overlapping_areas_are_rejected() {
+    serial_print!("overlapping_areas_are_rejected... ");
+    vma::register(lazy_vma(LAZY_START, LAZY_SIZE)).expect("failed to register area");
+    assert_eq!(
+        vma::register(lazy_vma(LAZY_START + 4096, LAZY_SIZE)),
+        Err(VmaError::Overlap)
+    );
+    assert_eq!(
+        vma::register(lazy_vma(LAZY_START + 1, LAZY_SIZE)),
+        Err(VmaError::Unaligned)
+    );
+    vma::unregister(VirtAddr::new(LAZY_START)).expect("area vanished");
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
@@ -0,0 +1,169 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use metal_os::allocator::HEAP_START;
+use metal_os::elf::{self, ElfError, ElfFile, PF_W, PF_X, PT_LOAD};
+use metal_os::memory::{self, address_space::Protection};
+use metal_os::thread::{self, ThreadState};
+use metal_os::{serial_print, serial_println};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+/// Built from `programs/hello.s`, see there.
+static HELLO: &[u8] = include_bytes!("programs/hello.elf");
+
+const ENTRY: u64 = 0x1000_0000_1000;
+
+/// Returns the error of parsing `data`.
+fn parse_error(data: &[u8]) -> ElfError {
+    match ElfFile::parse(data) {
+        Err(err) => err,
+        Ok(file) => panic!("unexpectedly parsed {:?}", file),
+    }
+}
+
+/// Returns a copy of the program with the `u64` at `offset` replaced by `value`.
+fn patched(offset: usize, value: u64) -> Vec<u8> {
+    let mut data = HELLO.to_vec();
+    data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    data
+}
+
+#[test_case]
+fn parses_embedded_program() {
+    serial_print!("parses_embedded_program... ");
+    let file = ElfFile::parse(HELLO).expect("failed to parse program");
+    assert_eq!(file.entry(), ENTRY);
+    let segments: Vec<_> = file.load_segments().collect();
+    assert_eq!(segments.len(), 4);
+    assert!(segments.iter().all(|s| s.kind == PT_LOAD));
+    assert_eq!(segments[1].vaddr, ENTRY);
+    assert_eq!(segments[1].protection(), Protection::USER_READ_EXECUTE);
+    assert_eq!(segments[3].flags & (PF_W | PF_X), PF_W);
+    assert!(segments[3].file_size < segments[3].mem_size);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn rejects_malformed_files() {
+    serial_print!("rejects_malformed_files... ");
+    let mut bad_magic = HELLO.to_vec();
+    bad_magic[1] = b'X';
+    match parse_error(&bad_magic) {
+        ElfError::BadMagic => {}
+        other => panic!("unexpected error {:?}", other),
+    }
+    match parse_error(&HELLO[..40]) {
+        ElfError::Truncated => {}
+        other => panic!("unexpected error {:?}", other),
+    }
+    // 32 bit
+    let mut class = HELLO.to_vec();
+    class[4] = 1;
+    match parse_error(&class) {
+        ElfError::Unsupported => {}
+        other => panic!("unexpected error {:?}", other),
+    }
+    // the program header table offset
+    match parse_error(&patched(32, u64::max_value() - 8)) {
+        ElfError::Truncated => {}
+        other => panic!("unexpected error {:?}", other),
+    }
+    // the address of the first segment, the program headers start at 64
+    match parse_error(&patched(64 + 16, HEAP_START as u64)) {
+        ElfError::BadSegment => {}
+        other => panic!("unexpected error {:?}", other),
+    }
+    // the entry point, moved into the data segment
+    match parse_error(&patched(24, ENTRY + 0x2020)) {
+        ElfError::BadEntry => {}
+        other => panic!("unexpected error {:?}", other),
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn loads_segments_with_permissions() {
+    serial_print!("loads_segments_with_permissions... ");
+    let program = elf::load(HELLO, &["hello"], &[]).expect("failed to load program");
+    let space = &program.address_space;
+    assert_eq!(program.entry.as_u64(), ENTRY);
+    assert_eq!(
+        space.protection(program.entry),
+        Some(Protection::USER_READ_EXECUTE)
+    );
+    assert_eq!(
+        space.protection(VirtAddr::new(ENTRY + 0x2000)),
+        Some(Protection::USER_READ_WRITE)
+    );
+    assert_eq!(program.stack_pointer.as_u64() % 16, 0);
+    let argc = space
+        .translate(program.stack_pointer)
+        .expect("stack not mapped");
+    let argc: *const u64 = memory::phys_to_virt(argc).as_ptr();
+    assert_eq!(unsafe { argc.read_volatile() }, 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn runs_program_in_ring_3() {
+    serial_print!("runs_program_in_ring_3... ");
+    let program = elf::load(HELLO, &["hello", "argument"], &["PATH=/bin", "HOME=/"])
+        .expect("failed to load program");
+    let file = ElfFile::parse(HELLO).unwrap();
+    let data = file
+        .load_segments()
+        .find(|s| s.flags & PF_W != 0)
+        .expect("no data segment");
+    let results = program
+        .address_space
+        .translate(VirtAddr::new(data.vaddr))
+        .unwrap();
+
+    let id = thread::spawn_user(program.address_space, program.entry, program.stack_pointer)
+        .expect("failed to spawn program");
+    while thread::state(id) != Some(ThreadState::Exited) {
+        thread::yield_now();
+    }
+    let ptr: *const [u8; 8] = memory::phys_to_virt(results).as_ptr();
+    let results: Vec<[u8; 8]> = (0..7)
+        .map(|i| unsafe { ptr.add(i).read_volatile() })
+        .collect();
+    thread::join(id).unwrap();
+
+    assert_eq!(u64::from_le_bytes(results[0]), 2);
+    assert_eq!(&results[1], b"argument");
+    assert_eq!(&results[2], b"PATH=/bi");
+    assert_eq!(u64::from_le_bytes(results[3]), 4096);
+    assert_eq!(u64::from_le_bytes(results[4]), 0);
+    assert_eq!(u64::from_le_bytes(results[5]), 0);
+    assert_eq!(u64::from_le_bytes(results[6]), 26);
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
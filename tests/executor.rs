@@ -0,0 +1,176 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use futures_util::task::AtomicWaker;
+use metal_os::task::{executor::Executor, Task};
+use metal_os::time::{timer, Instant};
+use metal_os::{memory, serial_print, serial_println};
+
+entry_point!(main);
+
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+static TIMER_FIRED: AtomicBool = AtomicBool::new(false);
+static TIMER_WAKER: AtomicWaker = AtomicWaker::new();
+
+async fn number() -> u64 {
+    42
+}
+
+async fn add_number() {
+    let number = number().await;
+    COMPLETED.fetch_add(number, Ordering::SeqCst);
+}
+
+/// Returns pending `count` times, waking itself every time.
+struct Yield {
+    count: u64,
+}
+
+impl Future for Yield {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.count == 0 {
+            return Poll::Ready(());
+        }
+        self.count -= 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Wakes itself many times per poll and counts how often it is polled.
+struct WakeRepeatedly {
+    polls: u64,
+}
+
+impl Future for WakeRepeatedly {
+    type Output = u64;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<u64> {
+        self.polls += 1;
+        if self.polls == 3 {
+            return Poll::Ready(self.polls);
+        }
+        // more wake-ups than the task queue has room for
+        for _ in 0..1000 {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+/// Completes once `timer_callback` ran in the timer interrupt.
+struct TimerFired;
+
+impl Future for TimerFired {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        TIMER_WAKER.register(cx.waker());
+        if TIMER_FIRED.load(Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+fn timer_callback() {
+    TIMER_FIRED.store(true, Ordering::SeqCst);
+    TIMER_WAKER.wake();
+}
+
+#[test_case]
+fn tasks_run_to_completion() {
+    serial_print!("tasks_run_to_completion... ");
+    COMPLETED.store(0, Ordering::SeqCst);
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        executor.spawn(Task::new(add_number()));
+    }
+    assert_eq!(executor.task_count(), 3);
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 0);
+    assert_eq!(COMPLETED.load(Ordering::SeqCst), 3 * 42);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn woken_task_is_polled_again() {
+    serial_print!("woken_task_is_polled_again... ");
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        Yield { count: 5 }.await;
+        COMPLETED.store(1, Ordering::SeqCst);
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 0);
+    assert_eq!(COMPLETED.load(Ordering::SeqCst), 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn repeated_wake_ups_queue_task_once() {
+    serial_print!("repeated_wake_ups_queue_task_once... ");
+    COMPLETED.store(0, Ordering::SeqCst);
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let polls = WakeRepeatedly { polls: 0 }.await;
+        COMPLETED.store(polls, Ordering::SeqCst);
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 0);
+    assert_eq!(COMPLETED.load(Ordering::SeqCst), 3);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn task_woken_from_interrupt() {
+    serial_print!("task_woken_from_interrupt... ");
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(TimerFired));
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 1);
+
+    timer::call_after(Duration::from_millis(20), timer_callback);
+    let start = Instant::now();
+    while executor.task_count() > 0 {
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "task was not woken"
+        );
+        x86_64::instructions::hlt();
+        executor.run_ready_tasks();
+    }
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
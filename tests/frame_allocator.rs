@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use metal_os::memory::BitmapFrameAllocator;
+use metal_os::{serial_print, serial_println};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, UnusedPhysFrame};
+
+entry_point!(main);
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+#[test_case]
+fn reuse_freed_frame() {
+    serial_print!("reuse_freed_frame... ");
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+
+    let frame = allocator.allocate_frame().expect("out of frames");
+    let addr = frame.start_address();
+    assert_eq!(allocator.free_frames(), free - 1);
+
+    allocator.deallocate_frame(frame);
+    assert_eq!(allocator.free_frames(), free);
+    let again = allocator.allocate_frame().expect("out of frames");
+    assert_eq!(again.start_address(), addr);
+    allocator.deallocate_frame(again);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn contiguous_allocation() {
+    serial_print!("contiguous_allocation... ");
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let used = allocator.used_frames();
+
+    let range = allocator
+        .allocate_contiguous(16, 16)
+        .expect("no contiguous range");
+    assert_eq!(range.start.start_address().as_u64() % (16 * 4096), 0);
+    assert_eq!(range.end - range.start, 16);
+    assert_eq!(allocator.used_frames(), used + 16);
+
+    allocator.deallocate_contiguous(range);
+    assert_eq!(allocator.used_frames(), used);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn shared_frame_is_freed_last() {
+    serial_print!("shared_frame_is_freed_last... ");
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+
+    let frame = allocator.allocate_frame().expect("out of frames");
+    let phys = *frame;
+    assert_eq!(allocator.reference_count(phys), 1);
+    allocator.share_frame(phys);
+    assert_eq!(allocator.reference_count(phys), 2);
+
+    allocator.deallocate_frame(frame);
+    assert_eq!(allocator.reference_count(phys), 1);
+    assert_eq!(allocator.free_frames(), free - 1);
+    allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(phys) });
+    assert_eq!(allocator.reference_count(phys), 0);
+    assert_eq!(allocator.free_frames(), free);
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    assert_eq!(
+        allocator.free_frames() + allocator.used_frames(),
+        allocator.total_frames()
+    );
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
@@ -43,15 +43,29 @@ fn many_boxes() {
     serial_println!("[ok]");
 }
 
+#[test_case]
+fn heap_grows_beyond_initial_size() {
+    use metal_os::allocator::HEAP_SIZE;
+
+    serial_print!("heap_grows_beyond_initial_size... ");
+    let n = HEAP_SIZE * 4;
+    let mut vec = Vec::with_capacity(n);
+    for i in 0..n {
+        vec.push(i as u8);
+    }
+    assert_eq!(vec.len(), n);
+    assert_eq!(vec[n - 1], (n - 1) as u8);
+    serial_println!("[ok]");
+}
+
 fn main(boot_info: &'static BootInfo) -> ! {
     use metal_os::allocator;
-    use metal_os::memory::{self, BootInfoFrameAllocator};
+    use metal_os::memory;
     use x86_64::VirtAddr;
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
 
     metal_os::init();
 
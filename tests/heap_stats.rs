@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicU64, Ordering};
+use metal_os::allocator::{self, tracking};
+use metal_os::{serial_print, serial_println, thread};
+
+entry_point!(main);
+
+static LEAKED: AtomicU64 = AtomicU64::new(0);
+static STOP: AtomicBool = AtomicBool::new(false);
+
+fn leak_until_stopped() {
+    while !STOP.load(Ordering::SeqCst) {
+        core::mem::forget(Box::new(0u32));
+        LEAKED.fetch_add(1, Ordering::SeqCst);
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn stats_follow_allocations() {
+    serial_print!("stats_follow_allocations... ");
+    let before = allocator::stats();
+
+    let value = Box::new([0u64; 16]);
+    let during = allocator::stats();
+    assert_eq!(during.bytes_allocated, before.bytes_allocated + 128);
+    assert!(during.peak_bytes_allocated >= during.bytes_allocated);
+    // 128 bytes are served by the 128 byte size class
+    assert_eq!(during.allocations[4], before.allocations[4] + 1);
+    assert_eq!(during.live_allocations[4], before.live_allocations[4] + 1);
+    assert!(during.largest_free_block > 0);
+    assert!(during.largest_free_block <= during.heap_size);
+
+    drop(value);
+    let after = allocator::stats();
+    assert_eq!(after.bytes_allocated, before.bytes_allocated);
+    assert_eq!(after.live_allocations[4], before.live_allocations[4]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn no_leak_detected() {
+    serial_print!("no_leak_detected... ");
+    let report = tracking::check_leaks(|| {
+        let mut vec = Vec::new();
+        for i in 0..100 {
+            vec.push(Box::new(i));
+        }
+    });
+    report.assert_no_leaks();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn leak_detected() {
+    serial_print!("leak_detected... ");
+    let report = tracking::check_leaks(|| {
+        core::mem::forget(Box::new(42u32));
+    });
+    assert_eq!(report.leaks.len(), 1);
+    assert_eq!(report.leaks[0].size, 4);
+    assert_ne!(report.leaks[0].backtrace[0], 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn leaks_of_other_threads_are_ignored() {
+    serial_print!("leaks_of_other_threads_are_ignored... ");
+    STOP.store(false, Ordering::SeqCst);
+    let id = thread::spawn(leak_until_stopped).unwrap();
+    let report = tracking::check_leaks(|| {
+        let leaked = LEAKED.load(Ordering::SeqCst);
+        // the leaking thread runs on another processor or preempts this one
+        while LEAKED.load(Ordering::SeqCst) < leaked + 10 {
+            spin_loop_hint();
+        }
+    });
+    STOP.store(true, Ordering::SeqCst);
+    thread::join(id).unwrap();
+    report.assert_no_leaks();
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::memory;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use metal_os::interrupts::irq::{self, IrqError};
+use metal_os::{memory, serial_print, serial_println};
+
+entry_point!(main);
+
+static CALLS: AtomicU64 = AtomicU64::new(0);
+static UNREGISTERING_CALLS: AtomicU64 = AtomicU64::new(0);
+
+fn count_calls(irq: u8) {
+    assert_eq!(irq, irq::TIMER);
+    CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn unregister_once(irq: u8) {
+    UNREGISTERING_CALLS.fetch_add(1, Ordering::SeqCst);
+    irq::unregister_irq(irq, unregister_once);
+}
+
+/// Waits until the timer IRQ was raised at least `ticks` more times.
+fn wait_for_ticks(ticks: u64) {
+    let target = irq::irq_count(irq::TIMER) + ticks;
+    while irq::irq_count(irq::TIMER) < target {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn shared_handler_is_called() {
+    serial_print!("shared_handler_is_called... ");
+    irq::register_irq(irq::TIMER, count_calls).expect("failed to register handler");
+    wait_for_ticks(3);
+    assert!(CALLS.load(Ordering::SeqCst) >= 2);
+
+    // the time keeping handler on the same line keeps working
+    let (seconds, nanos) = metal_os::time::monotonic();
+    assert!(seconds > 0 || nanos > 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn handler_is_registered_once() {
+    serial_print!("handler_is_registered_once... ");
+    assert_eq!(
+        irq::register_irq(irq::TIMER, count_calls),
+        Err(IrqError::AlreadyRegistered)
+    );
+    assert_eq!(
+        irq::register_irq(irq::MAX_IRQS, count_calls),
+        Err(IrqError::InvalidIrq)
+    );
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn unregistered_handler_is_not_called() {
+    serial_print!("unregistered_handler_is_not_called... ");
+    assert!(irq::unregister_irq(irq::TIMER, count_calls));
+    assert!(!irq::unregister_irq(irq::TIMER, count_calls));
+    wait_for_ticks(1);
+    let calls = CALLS.load(Ordering::SeqCst);
+    wait_for_ticks(3);
+    assert_eq!(CALLS.load(Ordering::SeqCst), calls);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn handler_unregisters_itself() {
+    serial_print!("handler_unregisters_itself... ");
+    irq::register_irq(irq::TIMER, unregister_once).expect("failed to register handler");
+    wait_for_ticks(3);
+    assert_eq!(UNREGISTERING_CALLS.load(Ordering::SeqCst), 1);
+    assert!(!irq::unregister_irq(irq::TIMER, unregister_once));
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
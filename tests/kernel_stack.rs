@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use metal_os::memory::{
+    self, stack,
+    vma::{VmaKind, KERNEL_VMAS},
+};
+use metal_os::{serial_print, serial_println};
+
+entry_point!(main);
+
+#[test_case]
+fn stack_is_mapped_above_guard() {
+    serial_print!("stack_is_mapped_above_guard... ");
+    let stack = stack::allocate(2).expect("failed to allocate stack");
+    assert_eq!(stack.size(), 2 * 4096);
+
+    let bottom = stack.bottom().as_mut_ptr::<u64>();
+    let top = (stack.top() - 8u64).as_mut_ptr::<u64>();
+    unsafe {
+        bottom.write_volatile(1);
+        top.write_volatile(2);
+        assert_eq!(bottom.read_volatile() + top.read_volatile(), 3);
+    }
+
+    let guard = stack.bottom() - 1u64;
+    let kind = KERNEL_VMAS.lock().find(guard).map(|vma| vma.kind);
+    assert_eq!(kind, Some(VmaKind::Guard));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn dropped_stack_is_reused() {
+    serial_print!("dropped_stack_is_reused... ");
+    let free = memory::with_frame_allocator(|frame_allocator| frame_allocator.free_frames());
+    let stack = stack::allocate(3).expect("failed to allocate stack");
+    let top = stack.top();
+    drop(stack);
+
+    let free_after = memory::with_frame_allocator(|frame_allocator| frame_allocator.free_frames());
+    assert_eq!(free_after, free);
+    let stack = stack::allocate(3).expect("failed to allocate stack");
+    assert_eq!(stack.top(), top);
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
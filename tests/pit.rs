@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use metal_os::interrupts::irq;
+use metal_os::rtc;
+use metal_os::time::{self, Instant};
+use metal_os::{memory, pit, serial_print, serial_println};
+
+entry_point!(main);
+
+/// Busy waits without relying on the timer IRQ.
+fn spin_for(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {}
+}
+
+fn assert_close(actual: Duration, expected: Duration, tolerance: Duration) {
+    assert!(
+        actual + tolerance >= expected && actual <= expected + tolerance,
+        "expected {:?} +- {:?}, got {:?}",
+        expected,
+        tolerance,
+        actual
+    );
+}
+
+#[test_case]
+fn tick_length_matches_frequency() {
+    serial_print!("tick_length_matches_frequency... ");
+    let length = pit::set_frequency(1000);
+    // 1193 input clock cycles
+    assert_eq!(length.nanos, 999_847);
+    assert_eq!(pit::tick_length(), length);
+
+    let ticks = irq::irq_count(irq::TIMER);
+    spin_for(Duration::from_millis(100));
+    let ticks = irq::irq_count(irq::TIMER) - ticks;
+    assert!((85..=115).contains(&ticks), "{} ticks in 100 ms", ticks);
+
+    pit::set_frequency(pit::DEFAULT_FREQUENCY);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn one_shot_fires_once() {
+    serial_print!("one_shot_fires_once... ");
+    let length = pit::start_one_shot(Duration::from_millis(5));
+    assert!(length.duration() >= Duration::from_millis(5));
+    assert!(length.duration() < Duration::from_micros(5001));
+
+    let ticks = irq::irq_count(irq::TIMER);
+    spin_for(Duration::from_millis(30));
+    assert_eq!(irq::irq_count(irq::TIMER) - ticks, 1);
+
+    pit::set_frequency(pit::DEFAULT_FREQUENCY);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn monotonic_advances_with_rtc() {
+    serial_print!("monotonic_advances_with_rtc... ");
+    let wait_for_next_second = || {
+        let second = rtc::time();
+        while rtc::time() == second {}
+    };
+
+    wait_for_next_second();
+    let ticks = time::tick_time();
+    let (secs, nanos) = time::monotonic();
+    let monotonic = Duration::new(secs, nanos as u32);
+    wait_for_next_second();
+    wait_for_next_second();
+    let (secs, nanos) = time::monotonic();
+
+    let tolerance = Duration::from_millis(100);
+    assert_close(time::tick_time() - ticks, Duration::from_secs(2), tolerance);
+    assert_close(
+        Duration::new(secs, nanos as u32) - monotonic,
+        Duration::from_secs(2),
+        tolerance,
+    );
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
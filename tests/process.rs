@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use metal_os::elf::ElfFile;
+use metal_os::process::{self, Pid, ProcessError};
+use metal_os::{memory, serial_print, serial_println};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+/// Built from `programs/hello.s` and `programs/fork.s`, see there.
+static HELLO: &[u8] = include_bytes!("programs/hello.elf");
+static FORK: &[u8] = include_bytes!("programs/fork.elf");
+
+fn run(image: &[u8], args: &[&str]) -> i32 {
+    let pid = process::spawn(image, args, &[]).expect("failed to spawn process");
+    process::wait(pid).expect("failed to wait for process")
+}
+
+#[test_case]
+fn processes_are_reaped() {
+    serial_print!("processes_are_reaped... ");
+    let pid = process::spawn(HELLO, &["hello", "argument"], &["PATH=/bin"])
+        .expect("failed to spawn process");
+    assert_eq!(process::wait(pid).unwrap(), 0);
+    match process::wait(pid) {
+        Err(ProcessError::NoChild) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    match process::wait(Pid::from_u64(u64::max_value())) {
+        Err(ProcessError::NoChild) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(process::current(), None);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn fork_exec_and_wait() {
+    serial_print!("fork_exec_and_wait... ");
+    process::register_program("hello", HELLO);
+    // the number of the failed check otherwise
+    assert_eq!(run(FORK, &["fork"]), 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn exceptions_end_processes() {
+    serial_print!("exceptions_end_processes... ");
+    let file = ElfFile::parse(HELLO).unwrap();
+    let text = file
+        .load_segments()
+        .find(|s| s.vaddr == file.entry())
+        .expect("no segment starts at the entry point");
+    let mut image = HELLO.to_vec();
+    let entry = text.offset as usize;
+    // ud2
+    image[entry..entry + 2].copy_from_slice(&[0x0f, 0x0b]);
+    assert_eq!(run(&image, &["ud2"]), process::EXCEPTION_STATUS);
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
@@ -0,0 +1,182 @@
+# A program for the process tests, which checks fork, exec, wait and the file
+# descriptor table. It exits with status 0 if all checks passed, otherwise with the
+# number of the first one that failed. `hello` must be registered.
+#
+# Rebuild `fork.elf` after changing it with:
+#
+#     as -o fork.o fork.s
+#     ld -static -nostdlib --build-id=none -z noexecstack -z max-page-size=4096 \
+#         -Ttext-segment=0x100000000000 -o fork.elf fork.o
+
+    .set EXIT, 0
+    .set WRITE, 1
+    .set FORK, 6
+    .set EXEC, 7
+    .set WAIT, 8
+    .set PROCESS_ID, 9
+    .set CLOSE, 10
+    .set DUP, 11
+    .set STDOUT, 1
+    .set STDERR, 2
+    .set BAD_FD, -4
+    .set NO_CHILD, -5
+    .set NOT_FOUND, -6
+    .set CHILD_STATUS, 42
+
+    .text
+    .global _start
+_start:
+    movq $5, counter(%rip)
+    mov $PROCESS_ID, %eax
+    syscall
+    mov %rax, pid(%rip)
+
+    # 1: fork
+    mov $FORK, %eax
+    syscall
+    test %rax, %rax
+    mov $1, %edi
+    js exit
+    jz child
+    mov %rax, %rbx
+
+    # 2, 3: the child is reaped with its exit status
+    mov %rbx, %rdi
+    lea status(%rip), %rsi
+    mov $WAIT, %eax
+    syscall
+    cmp %rbx, %rax
+    mov $2, %edi
+    jne exit
+    cmpl $CHILD_STATUS, status(%rip)
+    mov $3, %edi
+    jne exit
+
+    # 4: the child changed its own copy of the memory
+    cmpq $5, counter(%rip)
+    mov $4, %edi
+    jne exit
+
+    # 5: the child can't be waited for twice
+    mov $-1, %rdi
+    xor %esi, %esi
+    mov $WAIT, %eax
+    syscall
+    cmp $NO_CHILD, %rax
+    mov $5, %edi
+    jne exit
+
+    # 6: unknown programs are not executed
+    lea missing(%rip), %rdi
+    lea argv(%rip), %rsi
+    xor %edx, %edx
+    mov $EXEC, %eax
+    syscall
+    cmp $NOT_FOUND, %rax
+    mov $6, %edi
+    jne exit
+
+    # 7, 8: a second child runs `hello`, which exits with status 0
+    mov $FORK, %eax
+    syscall
+    test %rax, %rax
+    mov $7, %edi
+    js exit
+    jz run_hello
+    movl $-1, status(%rip)
+    mov %rax, %rdi
+    lea status(%rip), %rsi
+    mov $WAIT, %eax
+    syscall
+    cmpl $0, status(%rip)
+    mov $8, %edi
+    jne exit
+
+    # 9, 10: closed file descriptors can't be written to
+    mov $STDOUT, %edi
+    mov $CLOSE, %eax
+    syscall
+    test %rax, %rax
+    mov $9, %edi
+    jnz exit
+    mov $STDOUT, %edi
+    lea message(%rip), %rsi
+    mov $(message_end - message), %edx
+    mov $WRITE, %eax
+    syscall
+    cmp $BAD_FD, %rax
+    mov $10, %edi
+    jne exit
+
+    # 11, 12: duplicated file descriptors get the lowest free one
+    mov $STDERR, %edi
+    mov $DUP, %eax
+    syscall
+    cmp $STDOUT, %rax
+    mov $11, %edi
+    jne exit
+    mov $STDOUT, %edi
+    lea message(%rip), %rsi
+    mov $(message_end - message), %edx
+    mov $WRITE, %eax
+    syscall
+    cmp $(message_end - message), %rax
+    mov $12, %edi
+    jne exit
+
+    xor %edi, %edi
+exit:
+    mov $EXIT, %eax
+    syscall
+
+    # exits with CHILD_STATUS if it runs in a new process with a copy of the memory
+child:
+    cmpq $5, counter(%rip)
+    mov $1, %edi
+    jne exit
+    movq $9, counter(%rip)
+    mov $PROCESS_ID, %eax
+    syscall
+    cmp pid(%rip), %rax
+    mov $1, %edi
+    je exit
+    mov $CHILD_STATUS, %edi
+    jmp exit
+
+run_hello:
+    lea hello(%rip), %rdi
+    lea argv(%rip), %rsi
+    lea envp(%rip), %rdx
+    mov $EXEC, %eax
+    syscall
+    mov $1, %edi
+    jmp exit
+
+    .section .rodata
+hello:
+    .asciz "hello"
+missing:
+    .asciz "missing"
+argument:
+    .asciz "argument"
+path:
+    .asciz "PATH=/bin"
+message:
+    .ascii "hello from a duplicated file descriptor\n"
+message_end:
+
+    .data
+    .balign 8
+argv:
+    .quad hello, argument, 0
+envp:
+    .quad path, 0
+
+    .bss
+    .balign 8
+pid:
+    .zero 8
+counter:
+    .zero 8
+status:
+    .zero 4
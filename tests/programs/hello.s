@@ -0,0 +1,79 @@
+# A program for the ELF loader tests, which records what it finds on its stack in
+# `results` and prints a message.
+#
+# Rebuild `hello.elf` after changing it with:
+#
+#     as -o hello.o hello.s
+#     ld -static -nostdlib --build-id=none -z noexecstack -z max-page-size=4096 \
+#         -Ttext-segment=0x100000000000 -o hello.elf hello.o
+#
+# The text segment starts at the beginning of the user part of an address space.
+
+    .set WRITE, 1
+    .set STDOUT, 1
+    .set AT_PAGESZ, 6
+
+    .text
+    .global _start
+_start:
+    mov %rsp, %rax
+    and $15, %rax
+    mov %rax, results+32(%rip)
+    # argc, the first eight bytes of argv[1] and of envp[0]
+    mov (%rsp), %rcx
+    mov %rcx, results(%rip)
+    mov 16(%rsp), %rdx
+    mov (%rdx), %rax
+    mov %rax, results+8(%rip)
+    lea 16(%rsp,%rcx,8), %rsi
+    mov (%rsi), %rdx
+    mov (%rdx), %rax
+    mov %rax, results+16(%rip)
+    # the auxiliary vector follows the null pointer ending envp
+1:
+    mov (%rsi), %rax
+    add $8, %rsi
+    test %rax, %rax
+    jnz 1b
+2:
+    mov (%rsi), %rax
+    mov 8(%rsi), %rdx
+    add $16, %rsi
+    test %rax, %rax
+    jz 3f
+    cmp $AT_PAGESZ, %rax
+    jne 2b
+    mov %rdx, results+24(%rip)
+3:
+    # .bss starts zeroed and is writable
+    mov counter(%rip), %rax
+    mov %rax, results+40(%rip)
+    movq $7, counter(%rip)
+
+    mov $WRITE, %eax
+    mov $STDOUT, %edi
+    lea message(%rip), %rsi
+    mov $(message_end - message), %edx
+    syscall
+    mov %rax, results+48(%rip)
+
+    # EXIT with status 0
+    xor %edi, %edi
+    xor %eax, %eax
+    syscall
+
+    .section .rodata
+message:
+    .ascii "hello from an ELF program\n"
+message_end:
+
+    .data
+    .balign 8
+    .global results
+# argc, argv[1], envp[0], AT_PAGESZ, stack alignment, .bss, WRITE result
+results:
+    .zero 56
+
+    .bss
+counter:
+    .zero 8
@@ -0,0 +1,120 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use metal_os::rtc::{self, RtcError};
+use metal_os::time::{self, DateTime, Instant};
+use metal_os::{memory, serial_print, serial_println};
+
+entry_point!(main);
+
+static PERIODIC_CALLS: AtomicU64 = AtomicU64::new(0);
+static ALARM_FIRED: AtomicBool = AtomicBool::new(false);
+static CLEARING_CALLS: AtomicU64 = AtomicU64::new(0);
+
+fn count_periodic() {
+    PERIODIC_CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn clear_periodic_once() {
+    CLEARING_CALLS.fetch_add(1, Ordering::SeqCst);
+    rtc::clear_periodic();
+}
+
+fn alarm() {
+    ALARM_FIRED.store(true, Ordering::SeqCst);
+}
+
+#[test_case]
+fn periodic_interrupt_rate() {
+    serial_print!("periodic_interrupt_rate... ");
+    assert_eq!(
+        rtc::set_periodic(100, count_periodic),
+        Err(RtcError::InvalidFrequency)
+    );
+    rtc::set_periodic(64, count_periodic).expect("failed to enable periodic interrupt");
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(500) {
+        x86_64::instructions::hlt();
+    }
+    rtc::clear_periodic();
+
+    let calls = PERIODIC_CALLS.load(Ordering::SeqCst);
+    assert!((24..=40).contains(&calls), "{} calls in 500 ms", calls);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn periodic_handler_clears_itself() {
+    serial_print!("periodic_handler_clears_itself... ");
+    rtc::set_periodic(64, clear_periodic_once).expect("failed to enable periodic interrupt");
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(200) {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(CLEARING_CALLS.load(Ordering::SeqCst), 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn alarm_fires_at_time() {
+    serial_print!("alarm_fires_at_time... ");
+    let now = rtc::time();
+    assert_eq!(rtc::set_alarm(now, alarm), Err(RtcError::InPast));
+    rtc::set_alarm(now + 2, alarm).expect("failed to set alarm");
+
+    let start = Instant::now();
+    while !ALARM_FIRED.load(Ordering::SeqCst) {
+        assert!(
+            start.elapsed() < Duration::from_secs(4),
+            "alarm did not fire"
+        );
+        x86_64::instructions::hlt();
+    }
+    assert!(rtc::time() >= now + 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn set_time_round_trip() {
+    serial_print!("set_time_round_trip... ");
+    let original = rtc::time();
+    let date_time = DateTime::new(2040, 2, 29, 13, 4, 5).unwrap();
+    rtc::set_time(date_time);
+    let read = rtc::date_time();
+    assert!(
+        read.to_unix() - date_time.to_unix() <= 1,
+        "read back {}",
+        read
+    );
+    assert_eq!(time::date_time_now().year(), 2040);
+
+    rtc::set_time(DateTime::from_unix(original + 1).unwrap());
+    assert!(time::date_time_now().year() >= 2020);
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
@@ -0,0 +1,193 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use metal_os::memory::{self, stack};
+use metal_os::smp::{self, percpu};
+use metal_os::thread;
+use metal_os::time::Instant;
+use metal_os::{pit, serial_print, serial_println};
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+static RAN_ON: AtomicU64 = AtomicU64::new(0);
+static STOP: AtomicBool = AtomicBool::new(false);
+static WOKEN: AtomicBool = AtomicBool::new(false);
+static MIGRATIONS: AtomicU64 = AtomicU64::new(0);
+
+fn record_processor() {
+    while !STOP.load(Ordering::SeqCst) {
+        let mask = interrupts::without_interrupts(|| percpu::current().mask());
+        RAN_ON.fetch_or(mask, Ordering::SeqCst);
+    }
+}
+
+fn spin_until_stopped() {
+    while !STOP.load(Ordering::SeqCst) {}
+}
+
+/// Returns the processor the thread runs on and the current time on it.
+fn processor_and_now() -> (usize, Instant) {
+    interrupts::without_interrupts(|| (percpu::current().index(), Instant::now()))
+}
+
+fn compare_instants_across_migrations() {
+    let (mut last_cpu, mut last) = processor_and_now();
+    while !STOP.load(Ordering::SeqCst) {
+        thread::yield_now();
+        let (cpu, now) = processor_and_now();
+        assert!(
+            now >= last,
+            "time went backwards from processor {} to {}",
+            last_cpu,
+            cpu
+        );
+        if cpu != last_cpu {
+            MIGRATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        last_cpu = cpu;
+        last = now;
+    }
+}
+
+fn wake() {
+    WOKEN.store(true, Ordering::SeqCst);
+}
+
+#[test_case]
+fn processors_started() {
+    serial_print!("processors_started... ");
+    assert_eq!(smp::cpu_count(), 4);
+    let mut apic_ids = 0u64;
+    for (index, cpu) in percpu::cpus().enumerate() {
+        assert_eq!(cpu.index(), index);
+        assert_eq!(apic_ids & 1 << cpu.apic_id(), 0, "APIC id used twice");
+        apic_ids |= 1 << cpu.apic_id();
+    }
+    assert_eq!(apic_ids.count_ones(), 4);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn threads_run_on_other_processors() {
+    serial_print!("threads_run_on_other_processors... ");
+    RAN_ON.store(0, Ordering::SeqCst);
+    STOP.store(false, Ordering::SeqCst);
+    let ids = [
+        thread::spawn(record_processor).unwrap(),
+        thread::spawn(record_processor).unwrap(),
+        thread::spawn(record_processor).unwrap(),
+    ];
+    let start = Instant::now();
+    while RAN_ON.load(Ordering::SeqCst).count_ones() < 2 {
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "threads ran on one processor"
+        );
+        thread::yield_now();
+    }
+    STOP.store(true, Ordering::SeqCst);
+    for &id in ids.iter() {
+        thread::join(id).unwrap();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn tlb_shootdown_while_processors_busy() {
+    serial_print!("tlb_shootdown_while_processors_busy... ");
+    STOP.store(false, Ordering::SeqCst);
+    let ids = [
+        thread::spawn(spin_until_stopped).unwrap(),
+        thread::spawn(spin_until_stopped).unwrap(),
+        thread::spawn(spin_until_stopped).unwrap(),
+    ];
+    for round in 0..16u64 {
+        let stack = stack::allocate(2).expect("failed to allocate stack");
+        let bottom = stack.bottom().as_mut_ptr::<u64>();
+        unsafe {
+            bottom.write_volatile(round);
+            assert_eq!(bottom.read_volatile(), round);
+        }
+        // unmapping waits for every processor to flush
+        drop(stack);
+    }
+    STOP.store(true, Ordering::SeqCst);
+    for &id in ids.iter() {
+        thread::join(id).unwrap();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn instant_is_monotonic_across_migrations() {
+    serial_print!("instant_is_monotonic_across_migrations... ");
+    MIGRATIONS.store(0, Ordering::SeqCst);
+    STOP.store(false, Ordering::SeqCst);
+    // more threads than processors, so that they are moved between them
+    let ids = [
+        thread::spawn(compare_instants_across_migrations).unwrap(),
+        thread::spawn(compare_instants_across_migrations).unwrap(),
+        thread::spawn(compare_instants_across_migrations).unwrap(),
+        thread::spawn(compare_instants_across_migrations).unwrap(),
+        thread::spawn(compare_instants_across_migrations).unwrap(),
+    ];
+    let start = Instant::now();
+    while MIGRATIONS.load(Ordering::SeqCst) < 100 {
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "threads were not moved between processors"
+        );
+        thread::yield_now();
+    }
+    STOP.store(true, Ordering::SeqCst);
+    for &id in ids.iter() {
+        thread::join(id).unwrap();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn reschedule_wakes_idle_processor() {
+    serial_print!("reschedule_wakes_idle_processor... ");
+    WOKEN.store(false, Ordering::SeqCst);
+    // this processor can't run the thread, so an idle one has to be woken
+    let id = interrupts::without_interrupts(|| {
+        let id = thread::spawn(wake).unwrap();
+        let mut waited = 0;
+        while !WOKEN.load(Ordering::SeqCst) {
+            assert!(waited < 10_000, "no idle processor was woken");
+            pit::wait_micros(100);
+            waited += 1;
+        }
+        id
+    });
+    thread::join(id).unwrap();
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
@@ -2,6 +2,7 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use lazy_static::lazy_static;
 use metal_os::serial_print;
@@ -9,10 +10,19 @@ use metal_os::{exit_qemu, serial_println, QemuExitCode};
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::structures::idt::InterruptStackFrame;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::{allocator, memory};
+    use x86_64::VirtAddr;
+
     serial_print!("stack_overflow... ");
 
+    // the double fault stack is allocated with a guard page
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
     metal_os::gdt::init();
     init_test_idt();
 
@@ -0,0 +1,177 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use metal_os::sync::{Condvar, IrqSpinLock, Mutex, Semaphore, WaitQueue};
+use metal_os::thread::{self, ThreadState};
+use metal_os::time::timer;
+use metal_os::{memory, serial_print, serial_println};
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+const INCREMENTS: u64 = 10_000;
+
+static COUNTER: Mutex<u64> = Mutex::new(0);
+static SEMAPHORE: Semaphore = Semaphore::new(0);
+static READY: Mutex<usize> = Mutex::new(0);
+static CONDVAR: Condvar = Condvar::new();
+static QUEUE: WaitQueue = WaitQueue::new();
+static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+fn increment_counter() {
+    for _ in 0..INCREMENTS {
+        let mut counter = COUNTER.lock();
+        let value = *counter;
+        // invites preemption while the lock is held
+        thread::yield_now();
+        *counter = value + 1;
+    }
+}
+
+fn acquire_permit() {
+    SEMAPHORE.acquire();
+    WOKEN.fetch_add(1, Ordering::SeqCst);
+}
+
+fn release_permit() {
+    SEMAPHORE.release();
+}
+
+fn wait_for_ready() {
+    let ready = CONDVAR.wait_while(READY.lock(), |ready| *ready == 0);
+    assert!(*ready > 0);
+    drop(ready);
+    WOKEN.fetch_add(1, Ordering::SeqCst);
+}
+
+fn wait_on_queue() {
+    QUEUE.wait_until(|| WOKEN.load(Ordering::SeqCst) > 0);
+}
+
+/// Yields until thread `id` reached `state`, which may take a while when it runs on
+/// another processor.
+fn wait_for_state(id: thread::ThreadId, state: ThreadState) {
+    while thread::state(id) != Some(state) {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn mutex_excludes_threads() {
+    serial_print!("mutex_excludes_threads... ");
+    *COUNTER.lock() = 0;
+    let ids = [
+        thread::spawn(increment_counter).unwrap(),
+        thread::spawn(increment_counter).unwrap(),
+        thread::spawn(increment_counter).unwrap(),
+    ];
+    for &id in ids.iter() {
+        thread::join(id).unwrap();
+    }
+    assert_eq!(*COUNTER.lock(), 3 * INCREMENTS);
+    assert!(COUNTER.try_lock().is_some());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn semaphore_released_from_interrupt() {
+    serial_print!("semaphore_released_from_interrupt... ");
+    WOKEN.store(0, Ordering::SeqCst);
+    let id = thread::spawn(acquire_permit).unwrap();
+    wait_for_state(id, ThreadState::Blocked);
+    assert!(!SEMAPHORE.try_acquire());
+
+    timer::call_after(Duration::from_millis(10), release_permit);
+    thread::join(id).unwrap();
+    assert_eq!(WOKEN.load(Ordering::SeqCst), 1);
+    assert_eq!(SEMAPHORE.available_permits(), 0);
+
+    SEMAPHORE.release();
+    assert!(SEMAPHORE.try_acquire());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn condvar_notifies_all() {
+    serial_print!("condvar_notifies_all... ");
+    WOKEN.store(0, Ordering::SeqCst);
+    *READY.lock() = 0;
+    let ids = [
+        thread::spawn(wait_for_ready).unwrap(),
+        thread::spawn(wait_for_ready).unwrap(),
+        thread::spawn(wait_for_ready).unwrap(),
+    ];
+    thread::yield_now();
+    assert_eq!(WOKEN.load(Ordering::SeqCst), 0);
+
+    *READY.lock() = 1;
+    CONDVAR.notify_all();
+    for &id in ids.iter() {
+        thread::join(id).unwrap();
+    }
+    assert_eq!(WOKEN.load(Ordering::SeqCst), 3);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn wait_queue_notify() {
+    serial_print!("wait_queue_notify... ");
+    WOKEN.store(0, Ordering::SeqCst);
+    let id = thread::spawn(wait_on_queue).unwrap();
+    wait_for_state(id, ThreadState::Blocked);
+
+    WOKEN.store(1, Ordering::SeqCst);
+    assert!(QUEUE.notify_one());
+    thread::join(id).unwrap();
+    assert!(!QUEUE.notify_one());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn irq_spin_lock_restores_interrupts() {
+    serial_print!("irq_spin_lock_restores_interrupts... ");
+    let lock = IrqSpinLock::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut value = lock.lock();
+        *value += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.try_lock().is_none());
+        // a failed try_lock leaves interrupts disabled for the held lock
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+
+    interrupts::disable();
+    drop(lock.lock());
+    assert!(!interrupts::are_enabled());
+    interrupts::enable();
+    assert_eq!(*lock.lock(), 1);
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
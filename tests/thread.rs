@@ -0,0 +1,160 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use metal_os::thread::{self, JoinError, ThreadState};
+use metal_os::time::Instant;
+use metal_os::{memory, serial_print, serial_println, smp};
+
+entry_point!(main);
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+static STOP: AtomicBool = AtomicBool::new(false);
+static PARKED: AtomicBool = AtomicBool::new(false);
+static UNPARKED: AtomicBool = AtomicBool::new(false);
+
+fn increment() {
+    COUNTER.fetch_add(1, Ordering::SeqCst);
+}
+
+fn spin_until_stopped() {
+    STARTED.fetch_add(1, Ordering::SeqCst);
+    while !STOP.load(Ordering::SeqCst) {}
+}
+
+fn park_once() {
+    PARKED.store(true, Ordering::SeqCst);
+    thread::park();
+    UNPARKED.store(true, Ordering::SeqCst);
+}
+
+/// Yields until thread `id` reached `state`, which may take a while when it runs on
+/// another processor.
+fn wait_for_state(id: thread::ThreadId, state: ThreadState) {
+    while thread::state(id) != Some(state) {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn spawn_and_join() {
+    serial_print!("spawn_and_join... ");
+    COUNTER.store(0, Ordering::SeqCst);
+    let ids = [
+        thread::spawn(increment).unwrap(),
+        thread::spawn(increment).unwrap(),
+        thread::spawn(increment).unwrap(),
+    ];
+    for &id in ids.iter() {
+        thread::join(id).unwrap();
+        assert_eq!(thread::state(id), None);
+    }
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 3);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn yield_runs_other_threads() {
+    serial_print!("yield_runs_other_threads... ");
+    COUNTER.store(0, Ordering::SeqCst);
+    let id = thread::spawn(increment).unwrap();
+    wait_for_state(id, ThreadState::Exited);
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+    thread::join(id).unwrap();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn timer_preempts_busy_thread() {
+    serial_print!("timer_preempts_busy_thread... ");
+    // one more busy thread than processors, counting this one, so the last one only
+    // runs if another is preempted
+    let count = smp::cpu_count();
+    let mut ids = Vec::new();
+    for _ in 0..count {
+        ids.push(thread::spawn(spin_until_stopped).unwrap());
+    }
+    let start = Instant::now();
+    while STARTED.load(Ordering::SeqCst) < count {
+        assert!(start.elapsed() < Duration::from_secs(1), "thread never ran");
+    }
+    STOP.store(true, Ordering::SeqCst);
+    for id in ids {
+        thread::join(id).unwrap();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn park_and_unpark() {
+    serial_print!("park_and_unpark... ");
+    let id = thread::spawn(park_once).unwrap();
+    wait_for_state(id, ThreadState::Blocked);
+    assert!(PARKED.load(Ordering::SeqCst));
+    assert!(!UNPARKED.load(Ordering::SeqCst));
+    thread::unpark(id);
+    thread::join(id).unwrap();
+    assert!(UNPARKED.load(Ordering::SeqCst));
+
+    // an unpark before the park is not lost
+    thread::unpark(thread::current());
+    thread::park();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn join_errors() {
+    serial_print!("join_errors... ");
+    assert_eq!(thread::join(thread::current()), Err(JoinError::JoinSelf));
+    let id = thread::spawn(increment).unwrap();
+    thread::join(id).unwrap();
+    assert_eq!(thread::join(id), Err(JoinError::NotFound));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn detached_threads_are_freed() {
+    serial_print!("detached_threads_are_freed... ");
+    let id = thread::spawn(increment).unwrap();
+    wait_for_state(id, ThreadState::Exited);
+    thread::detach(id);
+    assert_eq!(thread::state(id), None);
+
+    // freed by the next spawn once it exited
+    let id = thread::spawn(spin_until_stopped).unwrap();
+    thread::detach(id);
+    STOP.store(true, Ordering::SeqCst);
+    wait_for_state(id, ThreadState::Exited);
+    thread::join(thread::spawn(increment).unwrap()).unwrap();
+    assert_eq!(thread::state(id), None);
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
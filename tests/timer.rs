@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use metal_os::time::{self, timer, Instant};
+use metal_os::{memory, serial_print, serial_println};
+
+entry_point!(main);
+
+static ONE_SHOT_CALLS: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_CALLS: AtomicU64 = AtomicU64::new(0);
+static CANCELLED_CALLS: AtomicU64 = AtomicU64::new(0);
+
+fn one_shot() {
+    ONE_SHOT_CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn periodic() {
+    PERIODIC_CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn cancelled() {
+    CANCELLED_CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test_case]
+fn sleep_duration() {
+    serial_print!("sleep_duration... ");
+    let tick = Duration::from_nanos(metal_os::pit::tick_length().nanos);
+    let start = Instant::now();
+    time::sleep(Duration::from_millis(100));
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(100) - tick,
+        "{:?}",
+        elapsed
+    );
+    assert!(elapsed < Duration::from_millis(110), "{:?}", elapsed);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn one_shot_fires_once() {
+    serial_print!("one_shot_fires_once... ");
+    let id = timer::call_after(Duration::from_millis(20), one_shot);
+    assert!(timer::is_pending(id));
+    time::sleep(Duration::from_millis(50));
+    assert_eq!(ONE_SHOT_CALLS.load(Ordering::SeqCst), 1);
+    assert!(!timer::is_pending(id));
+    assert!(!timer::cancel(id));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn periodic_fires_until_cancelled() {
+    serial_print!("periodic_fires_until_cancelled... ");
+    let id = timer::call_every(Duration::from_millis(10), periodic);
+    time::sleep(Duration::from_millis(105));
+    assert!(timer::cancel(id));
+    let calls = PERIODIC_CALLS.load(Ordering::SeqCst);
+    assert!((9..=11).contains(&calls), "{} calls in 105 ms", calls);
+    time::sleep(Duration::from_millis(30));
+    assert_eq!(PERIODIC_CALLS.load(Ordering::SeqCst), calls);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn cancelled_timers_do_not_fire() {
+    serial_print!("cancelled_timers_do_not_fire... ");
+    // thousands of timers spread over all levels of the wheel
+    let ids: Vec<_> = (0..5000)
+        .map(|i| timer::call_after(Duration::from_millis(5 + i * 37), cancelled))
+        .collect();
+    for &id in &ids {
+        assert!(timer::cancel(id));
+    }
+    time::sleep(Duration::from_millis(50));
+    assert_eq!(CANCELLED_CALLS.load(Ordering::SeqCst), 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn timeout_expires() {
+    serial_print!("timeout_expires... ");
+    let timeout = timer::Timeout::new(Duration::from_millis(20));
+    assert!(!timeout.expired());
+    let start = Instant::now();
+    while !timeout.expired() {
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "timeout did not expire"
+        );
+        x86_64::instructions::hlt();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(15));
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}
@@ -0,0 +1,252 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(metal_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use metal_os::allocator::HEAP_START;
+use metal_os::memory::{
+    self,
+    address_space::{AddressSpace, Protection, USER_START},
+};
+use metal_os::syscall::{self, user, SyscallError};
+use metal_os::thread::{self, ThreadId, ThreadState};
+use metal_os::{serial_print, serial_println};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+const CODE: u64 = USER_START;
+const DATA: u64 = USER_START + 0x1000;
+const MESSAGE: u64 = DATA + 0x100;
+const STACK_TOP: u64 = USER_START + 0x4000;
+const UNMAPPED: u64 = USER_START + 0x10_0000;
+
+/// Assembles the few instructions the tests run in ring 3.
+struct Code(Vec<u8>);
+
+impl Code {
+    fn new() -> Self {
+        Code(Vec::new())
+    }
+
+    fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    /// `mov eax, value`
+    fn mov_eax(self, value: u32) -> Self {
+        self.bytes(&[0xb8]).bytes(&value.to_le_bytes())
+    }
+
+    /// `mov edi, value`
+    fn mov_edi(self, value: u32) -> Self {
+        self.bytes(&[0xbf]).bytes(&value.to_le_bytes())
+    }
+
+    /// `mov edx, value`
+    fn mov_edx(self, value: u32) -> Self {
+        self.bytes(&[0xba]).bytes(&value.to_le_bytes())
+    }
+
+    /// `movabs rsi, value`
+    fn mov_rsi(self, value: u64) -> Self {
+        self.bytes(&[0x48, 0xbe]).bytes(&value.to_le_bytes())
+    }
+
+    /// `movabs rax, value`
+    fn mov_rax(self, value: u64) -> Self {
+        self.bytes(&[0x48, 0xb8]).bytes(&value.to_le_bytes())
+    }
+
+    /// `movabs [addr], rax`
+    fn store_rax(self, addr: u64) -> Self {
+        self.bytes(&[0x48, 0xa3]).bytes(&addr.to_le_bytes())
+    }
+
+    fn syscall(self) -> Self {
+        self.bytes(&[0x0f, 0x05])
+    }
+
+    /// `int 0x80`
+    fn int_80(self) -> Self {
+        self.bytes(&[0xcd, 0x80])
+    }
+
+    fn exit(self) -> Self {
+        // xor eax, eax
+        self.bytes(&[0x31, 0xc0]).syscall()
+    }
+}
+
+/// Creates an address space with `code`, a data page and a stack.
+fn new_space(code: &Code) -> AddressSpace {
+    let mut space = AddressSpace::new().expect("failed to create address space");
+    space
+        .map(VirtAddr::new(CODE), 4096, Protection::USER_READ_EXECUTE)
+        .expect("failed to map code");
+    space
+        .map(VirtAddr::new(DATA), 4096, Protection::USER_READ_WRITE)
+        .expect("failed to map data");
+    space
+        .map(
+            VirtAddr::new(STACK_TOP - 4096),
+            4096,
+            Protection::USER_READ_WRITE,
+        )
+        .expect("failed to map stack");
+    write(&space, CODE, &code.0);
+    space
+}
+
+/// Writes `bytes` into a single page of `space` through the physical memory mapping.
+fn write(space: &AddressSpace, addr: u64, bytes: &[u8]) {
+    let phys = space.translate(VirtAddr::new(addr)).expect("not mapped");
+    let ptr: *mut u8 = memory::phys_to_virt(phys).as_mut_ptr();
+    unsafe { ptr.copy_from_nonoverlapping(bytes.as_ptr(), bytes.len()) };
+}
+
+fn spawn(space: AddressSpace) -> ThreadId {
+    thread::spawn_user(space, VirtAddr::new(CODE), VirtAddr::new(STACK_TOP))
+        .expect("failed to spawn user thread")
+}
+
+/// Returns the `u64` values at `DATA` once the thread exited, while its address space
+/// still exists.
+fn results(id: ThreadId, data_phys: x86_64::PhysAddr, count: usize) -> Vec<u64> {
+    while thread::state(id) != Some(ThreadState::Exited) {
+        thread::yield_now();
+    }
+    let ptr: *const u64 = memory::phys_to_virt(data_phys).as_ptr();
+    (0..count)
+        .map(|i| unsafe { ptr.add(i).read_volatile() })
+        .collect()
+}
+
+#[test_case]
+fn system_calls_from_ring_3() {
+    serial_print!("system_calls_from_ring_3... ");
+    let message = b"hello from ring 3 ";
+    let code = Code::new()
+        .mov_eax(syscall::THREAD_ID as u32)
+        .syscall()
+        .store_rax(DATA)
+        .mov_eax(syscall::WRITE as u32)
+        .mov_edi(syscall::STDOUT as u32)
+        .mov_rsi(MESSAGE)
+        .mov_edx(message.len() as u32)
+        .syscall()
+        .store_rax(DATA + 8)
+        // mov rax, rdi, which the system call preserves
+        .bytes(&[0x48, 0x89, 0xf8])
+        .store_rax(DATA + 16)
+        .mov_eax(syscall::WRITE as u32)
+        .mov_rsi(UNMAPPED)
+        .mov_edx(8)
+        .syscall()
+        .store_rax(DATA + 24)
+        .mov_eax(99)
+        .int_80()
+        .store_rax(DATA + 32)
+        .mov_eax(syscall::THREAD_ID as u32)
+        .int_80()
+        .store_rax(DATA + 40)
+        .exit();
+    let space = new_space(&code);
+    write(&space, MESSAGE, message);
+    let data_phys = space.translate(VirtAddr::new(DATA)).unwrap();
+
+    let id = spawn(space);
+    let results = results(id, data_phys, 6);
+    thread::join(id).unwrap();
+    assert_eq!(results[0], id.as_u64());
+    assert_eq!(results[1], message.len() as u64);
+    assert_eq!(results[2], syscall::STDOUT);
+    assert_eq!(
+        SyscallError::from_return(results[3]),
+        Some(SyscallError::Fault)
+    );
+    assert_eq!(
+        SyscallError::from_return(results[4]),
+        Some(SyscallError::NoSys)
+    );
+    assert_eq!(results[5], id.as_u64());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn exceptions_end_user_threads() {
+    serial_print!("exceptions_end_user_threads... ");
+    // ud2
+    let invalid_opcode = Code::new().bytes(&[0x0f, 0x0b]);
+    // mov [rax], rax with a kernel address
+    let kernel_write = Code::new()
+        .mov_rax(HEAP_START as u64)
+        .bytes(&[0x48, 0x89, 0x00]);
+    for code in [invalid_opcode, kernel_write].iter() {
+        let id = spawn(new_space(code));
+        thread::join(id).unwrap();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn interrupts_arrive_in_ring_3() {
+    serial_print!("interrupts_arrive_in_ring_3... ");
+    let code = Code::new()
+        // mov ecx, 20000000; 1: dec rcx; jnz 1b
+        .bytes(&[0xb9])
+        .bytes(&20_000_000u32.to_le_bytes())
+        .bytes(&[0x48, 0xff, 0xc9, 0x75, 0xfb])
+        .exit();
+    let ids = [
+        spawn(new_space(&code)),
+        spawn(new_space(&code)),
+        spawn(new_space(&code)),
+        spawn(new_space(&code)),
+        spawn(new_space(&code)),
+    ];
+    for &id in ids.iter() {
+        thread::join(id).unwrap();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn user_buffers_are_checked() {
+    serial_print!("user_buffers_are_checked... ");
+    let space = new_space(&Code::new().exit());
+    assert!(user::is_accessible(&space, DATA, 4096, true));
+    assert!(user::is_accessible(&space, CODE, 16, false));
+    assert!(!user::is_accessible(&space, CODE, 16, true));
+    // the data page is followed by an unmapped one
+    assert!(!user::is_accessible(&space, DATA + 4000, 200, false));
+    assert!(!user::is_accessible(&space, UNMAPPED, 1, false));
+    assert!(!user::is_accessible(&space, HEAP_START as u64, 8, false));
+    assert!(!user::is_accessible(&space, u64::max_value() - 4, 8, false));
+    serial_println!("[ok]");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use metal_os::allocator;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    metal_os::init();
+
+    test_main();
+    metal_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    metal_os::test_panic_handler(info)
+}